@@ -0,0 +1,229 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! The access log: a line on stderr per request once its body is
+//! through, or cut off, with method, path, status, bytes sent, time
+//! taken and the client, from X-Forwarded-For where the peer is a
+//! trusted proxy.
+//!
+//! Like everything else handoc logs, these are plain lines rather
+//! than tracing events, so `$HANDOC_LOG` switching the format is all
+//! there is to filter them.
+
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime};
+
+use axum::body::{Body, Bytes};
+use axum::extract::Request;
+use axum::http::{header, HeaderMap};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::Router;
+use hyper::body::{Frame, SizeHint};
+
+use crate::config::{config, LogFormat};
+use crate::feed;
+
+/// The address of the other end of the connection.
+#[derive(Clone, Copy)]
+struct Peer(SocketAddr);
+
+/// Who the request is from, added to requests.
+#[derive(Clone, Copy)]
+pub struct Client(pub IpAddr);
+
+/// The configured format, unless `$HANDOC_LOG` names another.
+fn format() -> LogFormat {
+    static FORMAT: OnceLock<LogFormat> = OnceLock::new();
+    *FORMAT.get_or_init(|| match std::env::var("HANDOC_LOG").as_deref() {
+        Ok("off") => LogFormat::Off,
+        Ok("fields") => LogFormat::Fields,
+        Ok("clf") => LogFormat::Clf,
+        Ok(other) => {
+            eprintln!("unknown HANDOC_LOG={other}, using the configured format");
+            config().log.format
+        }
+        Err(_) => config().log.format,
+    })
+}
+
+/// `app` for the connection from `peer`, telling its requests who
+/// they are from and logging them.
+pub fn wrap(app: Router, peer: SocketAddr) -> Router {
+    app.layer(axum::middleware::from_fn(layer))
+        .layer(axum::Extension(Peer(peer)))
+}
+
+async fn layer(mut req: Request, next: Next) -> Response {
+    let from = req
+        .extensions()
+        .get::<Peer>()
+        .map(|p| client(p.0.ip().to_canonical(), req.headers()));
+    if let Some(from) = from {
+        req.extensions_mut().insert(Client(from));
+    }
+    if format() == LogFormat::Off {
+        return next.run(req).await;
+    }
+    let mut entry = Entry {
+        start: Instant::now(),
+        when: SystemTime::now(),
+        client: from.map(|c| c.to_string()),
+        method: req.method().to_string(),
+        target: req
+            .uri()
+            .path_and_query()
+            .map_or("/".to_owned(), ToString::to_string),
+        version: req.version(),
+        agent: req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_owned),
+        status: 0,
+        bytes: 0,
+    };
+    let res = next.run(req).await;
+    entry.status = res.status().as_u16();
+    on_end(res, move |bytes| {
+        entry.bytes = bytes;
+        drop(entry);
+    })
+}
+
+/// `res` calling `done` with the bytes sent of its body, once
+/// through or cut off.
+pub fn on_end(res: Response, done: impl FnOnce(usize) + Send + 'static) -> Response {
+    let (parts, inner) = res.into_parts();
+    let body = Counted {
+        inner,
+        bytes: 0,
+        done: Some(Box::new(done)),
+    };
+    Response::from_parts(parts, Body::new(body))
+}
+
+/// Who the request is from: the peer, or as long as that is a
+/// trusted proxy, the address before it in X-Forwarded-For.
+fn client(peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    let trusted = &config().trusted_proxies;
+    let hops: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .collect();
+    let mut client = peer;
+    for hop in hops.iter().rev() {
+        if !trusted.contains(&client) {
+            break;
+        }
+        match hop.trim().parse::<IpAddr>() {
+            Ok(addr) => client = addr.to_canonical(),
+            Err(_) => break,
+        }
+    }
+    client
+}
+
+struct Entry {
+    start: Instant,
+    when: SystemTime,
+    client: Option<String>,
+    method: String,
+    target: String,
+    version: axum::http::Version,
+    agent: Option<String>,
+    status: u16,
+    bytes: usize,
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        let client = self.client.as_deref().unwrap_or("-");
+        match format() {
+            LogFormat::Off => {}
+            LogFormat::Fields => eprintln!(
+                "access client={client} method={} path={:?} status={} bytes={} ms={:.1} agent={:?}",
+                self.method,
+                self.target,
+                self.status,
+                self.bytes,
+                self.start.elapsed().as_secs_f64() * 1000.0,
+                self.agent.as_deref().unwrap_or_default(),
+            ),
+            LogFormat::Clf => eprintln!(
+                "{client} - - [{}] \"{} {} {:?}\" {} {}",
+                clf_time(self.when),
+                self.method,
+                self.target,
+                self.version,
+                self.status,
+                self.bytes,
+            ),
+        }
+    }
+}
+
+/// A response body counting what is sent of it.
+struct Counted {
+    inner: Body,
+    bytes: usize,
+    done: Option<Box<dyn FnOnce(usize) + Send>>,
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        if let Some(done) = self.done.take() {
+            done(self.bytes);
+        }
+    }
+}
+
+impl hyper::body::Body for Counted {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            let n = frame.data_ref().map_or(0, Bytes::len);
+            self.bytes += n;
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// `10/Oct/2000:13:55:36 +0000`.
+fn clf_time(t: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (year, month, day, rem) = feed::civil(t);
+    format!(
+        "{day:02}/{}/{year}:{:02}:{:02}:{:02} +0000",
+        MONTHS[month as usize - 1],
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
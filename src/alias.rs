@@ -0,0 +1,39 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! `aliases` in the config: request paths redirected permanently
+//! elsewhere, ahead of all routes, e.g. short names or the paths of a
+//! previous man server.
+
+use axum::extract::Request;
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use crate::config::config;
+use crate::{base, with_base};
+
+pub async fn layer(req: Request, next: Next) -> Response {
+    let path = req.uri().path();
+    let Some(dst) = path
+        .strip_prefix(base())
+        .and_then(|path| config().aliases.get(path))
+    else {
+        return next.run(req).await;
+    };
+    // paths are under the base path too; anything else is a full URL
+    let mut dst = if dst.starts_with('/') {
+        with_base(dst)
+    } else {
+        dst.clone()
+    };
+    if let Some(q) = req.uri().query().filter(|_| !dst.contains('?')) {
+        dst = format!("{dst}?{q}");
+    }
+    (StatusCode::PERMANENT_REDIRECT, [(header::LOCATION, dst)]).into_response()
+}
@@ -0,0 +1,283 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! `/api/v1`: pages and lookups as JSON, open to any origin so other
+//! sites and browser extensions can embed man content.
+
+use axum::body::Body;
+use axum::extract::Path;
+use axum::http::{header, HeaderValue, Uri};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::Router;
+use serde_json::json;
+
+use crate::config::config;
+use crate::error::{Context, Error, Message};
+use crate::prefs::Prefs;
+use crate::source::source;
+use crate::{
+    bg, cache, check_so, guard, lint, lookup, meta, package, popular, query_param, render, stats,
+    url_path, Format, ManPath,
+};
+
+/// Most results `/search` returns.
+const SEARCH_LIMIT: usize = 50;
+
+pub fn routes() -> Router {
+    use axum::routing::*;
+    Router::new()
+        .route("/page/:section/:name", get(page))
+        .route("/referenced-by/:section/:name", get(referenced_by))
+        .route("/lint/:section/:name", get(lint))
+        .route("/find/:name", get(find))
+        .route("/search", get(search))
+        .route("/popular", get(popular))
+        .layer(axum::middleware::map_response(json_errors))
+        .layer(axum::middleware::map_response(cors))
+}
+
+async fn cors(mut res: Response) -> Response {
+    res.headers_mut().insert(
+        header::ACCESS_CONTROL_ALLOW_ORIGIN,
+        HeaderValue::from_static("*"),
+    );
+    res
+}
+
+fn reply(body: serde_json::Value) -> Response {
+    (
+        [(header::CONTENT_TYPE, "application/json")],
+        body.to_string(),
+    )
+        .into_response()
+}
+
+/// Error responses, with their body turned into JSON.
+async fn json_errors(res: Response) -> Response {
+    if !res.status().is_client_error() && !res.status().is_server_error() {
+        return res;
+    }
+    let (mut parts, _) = res.into_parts();
+    let message = match parts.extensions.get::<Message>() {
+        Some(Message(m)) => m.clone(),
+        None => parts
+            .status
+            .canonical_reason()
+            .unwrap_or("error")
+            .to_owned(),
+    };
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    // that of the page it replaces
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let body = json!({ "error": message }).to_string();
+    Response::from_parts(parts, Body::from(body))
+}
+
+/// `/api/v1/page/<section>/<name>`: metadata and the HTML fragment of
+/// a page.  Pages that are only a `.so` redirect to their target.
+async fn page(
+    Path(ManPath { section, name }): Path<ManPath>,
+    prefs: Prefs,
+) -> Result<Response, Error> {
+    let prefs = Prefs {
+        lang: prefs.lang,
+        ..Default::default()
+    };
+    let fp = bg({
+        let (section, file) = (section.clone(), format!("{name}.{section}.gz"));
+        let lang = prefs.lang.clone();
+        move || lookup::resolve(&section, &file, lang.as_deref())
+    })
+    .await
+    .ok_or(Error::NotFound)?;
+    let (so, info, mtime, package) = bg({
+        let fp = fp.clone();
+        move || -> Result<_, Error> {
+            Ok((
+                check_so(&fp).at("read", &fp)?,
+                meta::read(&fp).at("read", &fp)?,
+                source().metadata(&fp).at("stat", &fp)?.modified,
+                package::owner(&fp),
+            ))
+        }
+    })
+    .await?;
+    if let Some(so) = so {
+        let (name, section) = so
+            .rsplit('/')
+            .next()
+            .and_then(|file| file.rsplit_once('.'))
+            .ok_or(Error::NotFound)?;
+        return Ok(
+            Redirect::temporary(&url_path(&["api", "v1", "page", section, name])).into_response(),
+        );
+    }
+    let key = cache::Key {
+        path: fp,
+        mtime,
+        format: Format::Fragment,
+        prefs,
+    };
+    let (cached, key) = bg(move || (cache::get(&key), key)).await;
+    let html = match cached {
+        Some(html) => html,
+        None => {
+            bg(guard::check).await?;
+            let slot = guard::slot().await?;
+            render::collect(key, Some(slot)).await?
+        }
+    };
+    let hits = bg({
+        let page = format!("{section}/{name}.{section}");
+        move || {
+            stats::hit(&page);
+            popular::hits(&page)
+        }
+    })
+    .await;
+    let see_also: Vec<_> = info
+        .see_also
+        .iter()
+        .map(|(name, section)| json!({ "name": name, "section": section }))
+        .collect();
+    let mtime = mtime
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    Ok(reply(json!({
+        "name": name,
+        "section": section,
+        "description": info.description,
+        "mtime": mtime,
+        "see_also": see_also,
+        "package": package.map(|p| json!({ "name": p.name, "version": p.version })),
+        "hits": hits,
+        "html": String::from_utf8_lossy(&html),
+    })))
+}
+
+/// `/api/v1/referenced-by/<section>/<name>`: the pages naming it
+/// under SEE ALSO.
+async fn referenced_by(
+    Path(ManPath { section, name }): Path<ManPath>,
+    prefs: Prefs,
+) -> Result<Response, Error> {
+    let found = bg({
+        let (section, name) = (section.clone(), name.clone());
+        move || {
+            let lang = prefs.lang.as_deref();
+            lookup::resolve(&section, &format!("{name}.{section}.gz"), lang)
+                .map(|_| lookup::referenced_by(&name, &section, lang, true))
+        }
+    })
+    .await
+    .ok_or(Error::NotFound)?;
+    let pages: Vec<_> = found
+        .iter()
+        .map(|(name, section)| {
+            json!({
+                "url": url_path(&[section, &format!("{name}.{section}.html")]),
+                "api": url_path(&["api", "v1", "page", section, name]),
+                "name": name,
+                "section": section,
+            })
+        })
+        .collect();
+    Ok(reply(json!({
+        "name": name,
+        "section": section,
+        "referenced_by": pages,
+    })))
+}
+
+/// `/api/v1/lint/<section>/<name>`: what `mandoc -T lint` says about
+/// a page.
+async fn lint(
+    Path(ManPath { section, name }): Path<ManPath>,
+    prefs: Prefs,
+) -> Result<Response, Error> {
+    let fp = bg({
+        let (section, file) = (section.clone(), format!("{name}.{section}.gz"));
+        move || lookup::resolve(&section, &file, prefs.lang.as_deref())
+    })
+    .await
+    .ok_or(Error::NotFound)?;
+    bg(guard::check).await?;
+    let slot = guard::slot().await?;
+    let issues = lint::check(&fp, slot).await?;
+    Ok(reply(json!({
+        "name": name,
+        "section": section,
+        "issues": issues,
+    })))
+}
+
+/// `/api/v1/find/<name>`: where `<name>` or `<name>.<section>` is.
+async fn find(Path(name): Path<String>, prefs: Prefs) -> Result<Response, Error> {
+    let (name, section) = bg(move || lookup::parse(&name, prefs.lang.as_deref()))
+        .await
+        .ok_or(Error::NotFound)?;
+    Ok(reply(json!({
+        "url": url_path(&[&section, &format!("{name}.{section}.html")]),
+        "api": url_path(&["api", "v1", "page", &section, &name]),
+        "name": name,
+        "section": section,
+    })))
+}
+
+/// `/api/v1/search?q=<text>`: pages whose names contain it.
+async fn search(uri: Uri, prefs: Prefs) -> Result<Response, Error> {
+    let q = query_param(&uri, "q")
+        .filter(|q| !q.trim().is_empty())
+        .ok_or(Error::BadRequest("nothing to search for"))?;
+    let found = bg(move || lookup::search(q.trim(), prefs.lang.as_deref(), SEARCH_LIMIT)).await;
+    let results: Vec<_> = found
+        .into_iter()
+        .map(|(name, sections)| {
+            let best = &sections[0];
+            json!({
+                "url": url_path(&[best, &format!("{name}.{best}.html")]),
+                "name": name,
+                "sections": sections,
+            })
+        })
+        .collect();
+    Ok(reply(json!({ "results": results })))
+}
+
+/// `/api/v1/popular?limit=10`: the most viewed pages and their hits,
+/// up to as many as `/popular` lists.
+async fn popular(uri: Uri, prefs: Prefs) -> Result<Response, Error> {
+    let entries = config().popular.entries;
+    let limit = match query_param(&uri, "limit") {
+        Some(n) => n
+            .parse::<usize>()
+            .map_err(|_| Error::BadRequest("bad limit"))?
+            .min(entries),
+        None => entries,
+    };
+    let found = bg(move || popular::top(limit, prefs.lang.as_deref()))
+        .await
+        .ok_or(Error::NotFound)?;
+    let pages: Vec<_> = found
+        .into_iter()
+        .map(|(section, file, hits)| {
+            let name = file.strip_suffix(&format!(".{section}")).unwrap_or(&file);
+            json!({
+                "url": url_path(&[&section, &format!("{file}.html")]),
+                "api": url_path(&["api", "v1", "page", &section, name]),
+                "name": name,
+                "section": section,
+                "hits": hits,
+            })
+        })
+        .collect();
+    Ok(reply(json!({ "pages": pages })))
+}
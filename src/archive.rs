@@ -0,0 +1,313 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Pages not installed, taken from a pool of .deb and .rpm archives:
+//! their contents are indexed on the first miss, and a page is
+//! extracted into a man tree of its own once asked for.  Listing and
+//! extracting need dpkg-deb and tar, or rpm2cpio and cpio.
+//!
+//! Like the page index, the index is saved with the extracted pages
+//! for as long as the mtimes of the pool directories hold, checked
+//! every `index_refresh` seconds, as archives are added and removed
+//! rather than rewritten.  One process at a time rebuilds it; until
+//! then, the others go on with the stale one.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::config;
+use crate::{guard, lookup};
+
+/// `man<dir>/<file>` to the archive holding it and its member name.
+type Contents = HashMap<String, (PathBuf, String)>;
+
+/// Pool directories with their mtimes.
+type Dirs = Vec<(PathBuf, Option<SystemTime>)>;
+
+#[derive(Serialize, Deserialize)]
+struct Index {
+    /// The configured pool it was built from.
+    pool: Vec<PathBuf>,
+    /// Every directory searched, with its mtime then.
+    dirs: Dirs,
+    contents: Contents,
+}
+
+impl Index {
+    /// Whether no archive was added to or removed from the pool
+    /// since.
+    fn fresh(&self) -> bool {
+        self.pool == config().archives.pool && self.dirs.iter().all(|(d, t)| mtime(d) == *t)
+    }
+}
+
+/// The index in memory, with when it was last checked.
+static INDEX: Mutex<Option<(Instant, Arc<Index>)>> = Mutex::new(None);
+
+/// A rebuild begun in the background.
+static REBUILD: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+
+/// Where pages are extracted to, as `man<dir>/<file>`, along with the
+/// saved index.
+fn dir() -> Option<PathBuf> {
+    let archives = &config().archives;
+    if archives.pool.is_empty() {
+        return None;
+    }
+    archives
+        .dir
+        .clone()
+        .or_else(|| Some(guard::runtime_dir()?.join("archives")))
+}
+
+/// The source `file` of `section` from an archive, extracted if need
+/// be.  Blocks, for long when the pool changed.
+pub fn fetch(section: &str, file: &str) -> Option<PathBuf> {
+    let dir = dir()?;
+    let base = section
+        .find(|c: char| !c.is_ascii_digit())
+        .filter(|&i| i > 0)
+        .map(|i| &section[..i]);
+    let keys: Vec<_> = std::iter::once(section)
+        .chain(base)
+        .map(|d| format!("man{d}/{file}"))
+        .collect();
+    if let Some(dst) = keys.iter().map(|key| dir.join(key)).find(|p| p.exists()) {
+        return Some(dst);
+    }
+    let (key, archive, member) = with_index(&dir, |contents| {
+        keys.iter().find_map(|key| {
+            let (archive, member) = contents.get(key)?.clone();
+            Some((key.clone(), archive, member))
+        })
+    })??;
+    let dst = dir.join(key);
+    match extract(&archive, &member, &dst) {
+        Ok(()) => Some(dst),
+        Err(e) => {
+            eprintln!("cannot extract {member} from {}: {e}", archive.display());
+            None
+        }
+    }
+}
+
+/// Sections of page `name` in the pool.  Blocks.
+pub fn sections(name: &str) -> Vec<String> {
+    let found = dir().and_then(|dir| {
+        with_index(&dir, |contents| {
+            let secs: BTreeSet<String> = contents
+                .keys()
+                .filter_map(|key| {
+                    let file = key.split_once('/')?.1.strip_suffix(".gz")?;
+                    let (page, sec) = file.rsplit_once('.')?;
+                    (page == name).then(|| sec.to_owned())
+                })
+                .collect();
+            secs
+        })
+    });
+    found.into_iter().flatten().collect()
+}
+
+/// Run `f` on the index of the pool.
+fn with_index<T>(dir: &Path, f: impl FnOnce(&Contents) -> T) -> Option<T> {
+    Some(f(&index(dir)?.contents))
+}
+
+/// The index of the pool: as saved in `dir` while fresh, else built
+/// if there is none yet.  A stale one is used as it is while rebuilt
+/// in the background.  May block.
+fn index(dir: &Path) -> Option<Arc<Index>> {
+    let mut held = INDEX.lock().unwrap();
+    let refresh = Duration::from_secs(config().index_refresh);
+    if let Some((checked, index)) = held.as_mut() {
+        if checked.elapsed() < refresh {
+            return Some(index.clone());
+        }
+        if index.fresh() {
+            *checked = Instant::now();
+            return Some(index.clone());
+        }
+    }
+    let index = Arc::new(match load(dir) {
+        Some(index) if index.fresh() => index,
+        Some(stale) => {
+            rebuild_later(dir);
+            stale
+        }
+        None => rebuild(dir, true)?,
+    });
+    *held = Some((Instant::now(), index.clone()));
+    Some(index)
+}
+
+fn load(dir: &Path) -> Option<Index> {
+    serde_json::from_slice(&std::fs::read(dir.join("index.json")).ok()?).ok()
+}
+
+fn save(dir: &Path, index: &Index) -> std::io::Result<()> {
+    let path = dir.join("index.json");
+    // write aside and rename, as other processes may be reading
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    std::fs::write(&tmp, serde_json::to_vec(index)?)?;
+    std::fs::rename(tmp, path)
+}
+
+/// Build the index and save it in `dir`, one process at a time: the
+/// others `wait` for it and take what it saved, or leave it be.
+fn rebuild(dir: &Path, wait: bool) -> Option<Index> {
+    let lock = std::fs::create_dir_all(dir).and_then(|()| File::create(dir.join("index.lock")));
+    match &lock {
+        Ok(f) if wait => f.lock().ok()?,
+        Ok(f) => f.try_lock().ok()?,
+        Err(e) => eprintln!("cannot lock the index in {}: {e}", dir.display()),
+    }
+    if let Some(index) = load(dir).filter(Index::fresh) {
+        return Some(index);
+    }
+    let index = build();
+    if let Err(e) = save(dir, &index) {
+        eprintln!("cannot save the index in {}: {e}", dir.display());
+    }
+    Some(index)
+}
+
+/// Rebuild the stale index for later lookups, unless already at it.
+fn rebuild_later(dir: &Path) {
+    let mut rebuild = REBUILD.lock().unwrap();
+    if rebuild.as_ref().is_some_and(|t| !t.is_finished()) {
+        return;
+    }
+    let dir = dir.to_owned();
+    *rebuild = Some(std::thread::spawn(move || {
+        if let Some(index) = self::rebuild(&dir, false) {
+            *INDEX.lock().unwrap() = Some((Instant::now(), Arc::new(index)));
+        }
+    }));
+}
+
+/// Wait for a rebuild begun in the background, which would be lost
+/// along with the process.  Blocks.
+pub fn finish() {
+    let rebuild = REBUILD.lock().unwrap().take();
+    if let Some(t) = rebuild {
+        t.join().ok();
+    }
+}
+
+fn mtime(p: &Path) -> Option<SystemTime> {
+    std::fs::metadata(p).and_then(|m| m.modified()).ok()
+}
+
+/// Archives of the pool, sorted, and the directories searched.
+fn pool() -> (Vec<PathBuf>, Dirs) {
+    let (mut archives, mut searched) = (Vec::new(), Vec::new());
+    let mut dirs: Vec<PathBuf> = config().archives.pool.clone();
+    while let Some(dir) = dirs.pop() {
+        // taken before listing, so changes meanwhile show later
+        searched.push((dir.clone(), mtime(&dir)));
+        let Ok(ents) = std::fs::read_dir(&dir) else {
+            continue;
+        };
+        for ent in ents.flatten() {
+            let (path, Ok(meta)) = (ent.path(), ent.metadata()) else {
+                continue;
+            };
+            if meta.is_dir() {
+                dirs.push(path);
+            } else if path.extension().is_some_and(|e| e == "deb" || e == "rpm") {
+                archives.push(path);
+            }
+        }
+    }
+    archives.sort_unstable();
+    (archives, searched)
+}
+
+fn build() -> Index {
+    let (archives, dirs) = pool();
+    let mut contents = Contents::new();
+    for archive in &archives {
+        let Some(list) = unpack(archive, None) else {
+            eprintln!("cannot list {}", archive.display());
+            continue;
+        };
+        for member in String::from_utf8_lossy(&list).lines() {
+            let Some(rest) = member
+                .trim_start_matches("./")
+                .strip_prefix("usr/share/man/man")
+            else {
+                continue;
+            };
+            let Some((dir, file)) = rest.split_once('/') else {
+                continue;
+            };
+            if lookup::valid_section(dir) && lookup::valid_component(file) {
+                contents
+                    .entry(format!("man{dir}/{file}"))
+                    .or_insert_with(|| (archive.clone(), member.to_owned()));
+            }
+        }
+    }
+    Index {
+        pool: config().archives.pool.clone(),
+        dirs,
+        contents,
+    }
+}
+
+fn extract(archive: &Path, member: &str, dst: &Path) -> std::io::Result<()> {
+    let src = unpack(archive, Some(member))
+        .filter(|s| !s.is_empty())
+        .ok_or_else(|| std::io::Error::other("no such regular file"))?;
+    std::fs::create_dir_all(dst.parent().unwrap())?;
+    let tmp = dst.with_extension(format!("tmp{}", std::process::id()));
+    std::fs::File::create(&tmp)?.write_all(&src)?;
+    std::fs::rename(tmp, dst)
+}
+
+/// The member list of `archive`, or the content of `member`.
+fn unpack(archive: &Path, member: Option<&str>) -> Option<Vec<u8>> {
+    let is_deb = archive.extension().is_some_and(|e| e == "deb");
+    let mut first = if is_deb {
+        let mut cmd = Command::new("dpkg-deb");
+        cmd.arg("--fsys-tarfile");
+        cmd
+    } else {
+        Command::new("rpm2cpio")
+    };
+    let mut second = Command::new(if is_deb { "tar" } else { "cpio" });
+    match (is_deb, member) {
+        (true, None) => second.arg("-t"),
+        (true, Some(m)) => second.args(["-xO", "--", m]),
+        (false, None) => second.args(["--quiet", "-t"]),
+        (false, Some(m)) => second.args(["--quiet", "-i", "--to-stdout", "--", m]),
+    };
+    let mut first = first
+        .arg(archive)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    let out = second
+        .stdin(first.stdout.take()?)
+        .stderr(Stdio::null())
+        .output();
+    let ok = first.wait().is_ok_and(|s| s.success());
+    out.ok()
+        .filter(|o| ok && o.status.success())
+        .map(|o| o.stdout)
+}
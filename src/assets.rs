@@ -0,0 +1,113 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Files built into the binary, under `/static/`, and the default
+//! stylesheet and favicon also at their usual `/style.css` and
+//! `/favicon.ico`.  Pages link them with `?v=` and a hash of their
+//! content, so they can be cached for good and still be fetched anew
+//! once they change.
+
+use std::hash::{Hash, Hasher};
+
+use axum::extract::Path;
+use axum::http::{header, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+
+use crate::error::Error;
+use crate::{query_param, with_base, IfNoneMatch};
+
+struct Asset {
+    name: &'static str,
+    mime: &'static str,
+    body: &'static [u8],
+}
+
+static ASSETS: &[Asset] = &[
+    Asset {
+        name: "style.css",
+        mime: "text/css; charset=utf-8",
+        body: include_bytes!("../assets/style.css"),
+    },
+    Asset {
+        name: "favicon.svg",
+        mime: "image/svg+xml",
+        body: include_bytes!("../assets/favicon.svg"),
+    },
+];
+
+fn asset(name: &str) -> Option<&'static Asset> {
+    ASSETS.iter().find(|a| a.name == name)
+}
+
+fn version(asset: &Asset) -> String {
+    let mut h = std::hash::DefaultHasher::new();
+    asset.body.hash(&mut h);
+    format!("{:016x}", h.finish())
+}
+
+/// Where pages link asset `name`.
+pub fn href(name: &str) -> String {
+    let path = match name {
+        "style.css" => with_base("/style.css"),
+        _ => with_base(&format!("/static/{name}")),
+    };
+    match asset(name) {
+        Some(asset) => format!("{path}?v={}", version(asset)),
+        None => path,
+    }
+}
+
+/// The type and content of the built-in asset at `url`, a link as
+/// made by [`href`].
+pub fn linked(url: &str) -> Option<(&'static str, &'static [u8])> {
+    let path = url.split('?').next()?;
+    ASSETS
+        .iter()
+        .find(|a| href(a.name).split('?').next() == Some(path))
+        .map(|a| (a.mime, a.body))
+}
+
+pub fn routes() -> Router {
+    use axum::routing::*;
+    Router::new().route("/:name", get(file))
+}
+
+async fn file(Path(name): Path<String>, uri: Uri, tags: IfNoneMatch) -> Response {
+    match asset(&name) {
+        Some(asset) => serve(asset, uri, tags),
+        None => Error::NotFound.into_response(),
+    }
+}
+
+pub async fn style(uri: Uri, tags: IfNoneMatch) -> Response {
+    serve(asset("style.css").unwrap(), uri, tags)
+}
+
+pub async fn favicon(uri: Uri, tags: IfNoneMatch) -> Response {
+    serve(asset("favicon.svg").unwrap(), uri, tags)
+}
+
+fn serve(asset: &Asset, uri: Uri, IfNoneMatch(tags): IfNoneMatch) -> Response {
+    let version = version(asset);
+    let etag = format!("\"{version}\"");
+    // without the current version, e.g. from a stale page, revalidate
+    let cache_control = if query_param(&uri, "v").as_deref() == Some(&*version) {
+        "public, max-age=31536000, immutable"
+    } else {
+        "no-cache"
+    };
+    let headers = [
+        (header::ETAG, etag.clone()),
+        (header::CACHE_CONTROL, cache_control.to_owned()),
+    ];
+    if tags.is_some_and(|tags| tags.is_empty() || tags.contains(&etag)) {
+        return (StatusCode::NOT_MODIFIED, headers).into_response();
+    }
+    ([(header::CONTENT_TYPE, asset.mime)], headers, asset.body).into_response()
+}
@@ -0,0 +1,196 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Access control for internal deployments: clients outside the
+//! `auth.allow` networks get 403, and with an `auth.htpasswd` file,
+//! requests without a login from it get 401.  Checked ahead of
+//! everything touching the filesystem.  Passwords are checked by
+//! crypt(3), so take bcrypt (`htpasswd -B`) or SHA-crypt hashes, but
+//! not Apache's own MD5 ones.
+
+use std::collections::HashMap;
+use std::ffi::{c_char, CStr, CString};
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+
+use axum::extract::Request;
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+
+use crate::access::Client;
+use crate::bg;
+use crate::config::config;
+
+/// An address or network, as `192.0.2.0/24` or `2001:db8::/32`.
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    net: IpAddr,
+    bits: u32,
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        let (net, bits) = s.split_once('/').unwrap_or((&s, ""));
+        let net: IpAddr = net.parse().map_err(|_| format!("bad address in {s:?}"))?;
+        let width = if net.is_ipv4() { 32 } else { 128 };
+        let bits = match bits {
+            "" => width,
+            bits => bits
+                .parse()
+                .ok()
+                .filter(|&b| b <= width)
+                .ok_or_else(|| format!("bad prefix length in {s:?}"))?,
+        };
+        Ok(Self { net, bits })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let (net, ip, width) = match (self.net, ip.to_canonical()) {
+            (IpAddr::V4(n), IpAddr::V4(i)) => (u32::from(n).into(), u32::from(i).into(), 32),
+            (IpAddr::V6(n), IpAddr::V6(i)) => (u128::from(n), u128::from(i), 128),
+            _ => return false,
+        };
+        (net ^ ip).checked_shr(width - self.bits).unwrap_or(0) == 0
+    }
+}
+
+pub async fn layer(req: Request, next: Next) -> Response {
+    let auth = &config().auth;
+    if !auth.allow.is_empty() {
+        let client = req.extensions().get::<Client>().map(|c| c.0);
+        if !client.is_some_and(|c| auth.allow.iter().any(|n| n.contains(c))) {
+            if let Some(client) = client {
+                eprintln!("{client}: not in auth.allow");
+            }
+            return StatusCode::FORBIDDEN.into_response();
+        }
+    }
+    if auth.htpasswd.is_none() {
+        return next.run(req).await;
+    }
+    let given = req.headers().get(header::AUTHORIZATION).cloned();
+    match bg(move || login(given.as_ref()?)).await {
+        Some(()) => next.run(req).await,
+        None => challenge(),
+    }
+}
+
+fn challenge() -> Response {
+    let realm = config().auth.realm.replace(['"', '\\'], "");
+    (
+        StatusCode::UNAUTHORIZED,
+        [(
+            header::WWW_AUTHENTICATE,
+            format!("Basic realm=\"{realm}\", charset=\"UTF-8\""),
+        )],
+    )
+        .into_response()
+}
+
+/// Check `Authorization: Basic ...` against the htpasswd file.
+/// Blocks.
+fn login(given: &HeaderValue) -> Option<()> {
+    // the last login that passed, not to run crypt on every request
+    static PASSED: Mutex<Option<HeaderValue>> = Mutex::new(None);
+    if PASSED.lock().unwrap().as_ref() == Some(given) {
+        return Some(());
+    }
+    let users = users()?;
+    let login = given
+        .to_str()
+        .ok()
+        .and_then(|v| v.strip_prefix("Basic "))
+        .and_then(|v| base64(v.trim()))
+        .and_then(|v| String::from_utf8(v).ok());
+    let (user, password) = login.as_deref()?.split_once(':')?;
+    let ok = users
+        .get(user)
+        .and_then(|hash| Some(same(hashed(password, hash)?.as_bytes(), hash.as_bytes())))
+        .unwrap_or(false);
+    if !ok {
+        eprintln!("login failed for {user:?}");
+        return None;
+    }
+    *PASSED.lock().unwrap() = Some(given.clone());
+    Some(())
+}
+
+/// The users and password hashes of the htpasswd file, `user:hash`
+/// per line.
+fn users() -> Option<&'static HashMap<String, String>> {
+    static USERS: OnceLock<Option<HashMap<String, String>>> = OnceLock::new();
+    USERS
+        .get_or_init(|| {
+            let path = config().auth.htpasswd.as_ref()?;
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| eprintln!("cannot read {}: {e}", path.display()))
+                .ok()?;
+            Some(
+                text.lines()
+                    .filter(|l| !l.trim_start().starts_with('#'))
+                    .filter_map(|l| l.trim().split_once(':'))
+                    .map(|(user, hash)| (user.to_owned(), hash.to_owned()))
+                    .collect(),
+            )
+        })
+        .as_ref()
+}
+
+// in libc itself elsewhere
+#[cfg_attr(target_os = "linux", link(name = "crypt"))]
+extern "C" {
+    fn crypt(key: *const c_char, salt: *const c_char) -> *mut c_char;
+}
+
+/// `password` hashed like `hash`.
+fn hashed(password: &str, hash: &str) -> Option<String> {
+    // crypt returns a static buffer
+    static LOCK: Mutex<()> = Mutex::new(());
+    let (key, salt) = (CString::new(password).ok()?, CString::new(hash).ok()?);
+    let _lock = LOCK.lock().unwrap();
+    let out = unsafe { crypt(key.as_ptr(), salt.as_ptr()) };
+    if out.is_null() {
+        return None;
+    }
+    Some(unsafe { CStr::from_ptr(out) }.to_str().ok()?.to_owned())
+}
+
+/// Compare without giving away where they differ.
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Standard base64, padded or not.
+fn base64(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let (mut acc, mut n) = (0u32, 0);
+    for c in s.trim_end_matches('=').bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = acc << 6 | u32::from(v);
+        n += 6;
+        if n >= 8 {
+            n -= 8;
+            out.push((acc >> n) as u8);
+        }
+    }
+    Some(out)
+}
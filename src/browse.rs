@@ -0,0 +1,190 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Pages for finding pages: the sections at `/`, the listing of each
+//! at `/<section>/`, name search at `/search?q=`, and the pages of a
+//! package at `/package/<name>`.
+
+use axum::extract::Path;
+use axum::http::Uri;
+use axum::response::{Html, IntoResponse, Redirect, Response};
+
+use crate::error::Error;
+use crate::prefs::Prefs;
+use crate::{
+    bg, doc, escape_html, html, lookup, package, query_param, template, url_path, vhost, with_base,
+};
+
+/// Most results a search lists.
+const SEARCH_LIMIT: usize = 50;
+
+pub fn document(prefs: &Prefs, title: &str, place: html::Place, body: &str) -> Html<String> {
+    let page = html::Page {
+        title: title.to_owned(),
+        description: None,
+        place,
+    };
+    let (pre, post) = template::wrap(prefs);
+    Html(html::rewrite(&(pre + body + &post), page))
+}
+
+/// What the traditional sections hold, by their leading digit.
+fn section_title(section: &str) -> &'static str {
+    match section.chars().next() {
+        Some('1') => "User commands",
+        Some('2') => "System calls",
+        Some('3') => "Library functions",
+        Some('4') => "Devices",
+        Some('5') => "File formats",
+        Some('6') => "Games",
+        Some('7') => "Overviews and conventions",
+        Some('8') => "System administration",
+        Some('9') => "Kernel interfaces",
+        _ => "",
+    }
+}
+
+pub async fn home(prefs: Prefs) -> Response {
+    let lang = prefs.lang.clone();
+    let sections = bg(move || lookup::all_sections(lang.as_deref())).await;
+    let mut body = String::from("<h1>Manual pages</h1>\n<ul class=\"sections\">\n");
+    for sec in &sections {
+        body += &format!(
+            "<li><a href=\"{}/\">{}</a> {}</li>\n",
+            url_path(&[sec]),
+            escape_html(sec),
+            section_title(sec)
+        );
+    }
+    body += "</ul>";
+    document(&prefs, "Manual pages", html::Place::Other, &body).into_response()
+}
+
+pub async fn section(Path(section): Path<String>, prefs: Prefs) -> Response {
+    if !lookup::valid_section(&section) {
+        return Error::NotFound.into_response();
+    }
+    let pages = bg({
+        let (section, lang) = (section.clone(), prefs.lang.clone());
+        move || lookup::section_pages(&section, lang.as_deref())
+    })
+    .await;
+    if pages.is_empty() {
+        return Error::NotFound.into_response();
+    }
+    let title = format!("Section {section}");
+    let mut body = format!(
+        "<h1>{}</h1>\n<p>{}</p>\n<ul class=\"pages\">\n",
+        escape_html(&title),
+        section_title(&section)
+    );
+    for name in &pages {
+        body += &format!(
+            "<li><a href=\"{}\">{}</a></li>\n",
+            url_path(&[&section, &format!("{name}.{section}.html")]),
+            escape_html(name)
+        );
+    }
+    body += "</ul>";
+    document(&prefs, &title, html::Place::Section(section), &body).into_response()
+}
+
+/// A page of exactly the name searched for is shown straight away;
+/// otherwise the names containing it are listed.
+pub async fn search(uri: Uri, prefs: Prefs) -> Response {
+    let q = query_param(&uri, "q").unwrap_or_default();
+    let q = q.trim().to_owned();
+    let found = if q.is_empty() {
+        Vec::new()
+    } else {
+        let (q, lang) = (q.clone(), prefs.lang.clone());
+        bg(move || lookup::search(&q, lang.as_deref(), SEARCH_LIMIT)).await
+    };
+    if found.first().is_some_and(|(name, _)| *name == q) {
+        return Redirect::to(&url_path(&[&q])).into_response();
+    }
+    let mut body = format!(
+        "<h1>Search</h1>\n<form action=\"{}\" method=\"get\">\
+         <input type=\"search\" name=\"q\" value=\"{}\"/> <button type=\"submit\">Search</button></form>\n",
+        with_base("/search"),
+        escape_html(&q)
+    );
+    if !q.is_empty() && found.is_empty() {
+        body += "<p>No pages found.</p>";
+    }
+    if !found.is_empty() {
+        body += "<ul class=\"results\">\n";
+        for (name, sections) in &found {
+            let links: Vec<_> = sections
+                .iter()
+                .map(|sec| {
+                    format!(
+                        "<a href=\"{}\">{}({})</a>",
+                        url_path(&[sec, &format!("{name}.{sec}.html")]),
+                        escape_html(name),
+                        escape_html(sec)
+                    )
+                })
+                .collect();
+            body += &format!("<li>{}</li>\n", links.join(", "));
+        }
+        body += "</ul>";
+    }
+    document(&prefs, "Search", html::Place::Other, &body).into_response()
+}
+
+/// The pages package `name` installed in the roots served.
+pub async fn package(Path(name): Path<String>, prefs: Prefs) -> Response {
+    let found = bg(move || {
+        let (package, paths) = package::pages(&name)?;
+        let roots = vhost::roots();
+        let mut pages: Vec<_> = paths
+            .iter()
+            .filter(|p| {
+                p.parent()
+                    .and_then(|d| d.parent())
+                    .is_some_and(|r| roots.iter().any(|root| root == r))
+            })
+            .filter_map(|p| {
+                let file = p.file_name()?.to_str()?.strip_suffix(".gz")?;
+                let (name, sec) = file.rsplit_once('.')?;
+                Some((sec.to_owned(), name.to_owned()))
+            })
+            .collect();
+        pages.sort_unstable();
+        pages.dedup();
+        let doc = doc::dir(&package.name).is_some();
+        Some((package, pages, doc))
+    })
+    .await;
+    let Some((package, pages, doc)) = found.filter(|(_, pages, _)| !pages.is_empty()) else {
+        return Error::NotFound.into_response();
+    };
+    let title = format!("Package {}", package.name);
+    let mut body = format!(
+        "<h1>{}</h1>\n<p>Version {}</p>\n<ul class=\"pages\">\n",
+        escape_html(&title),
+        escape_html(&package.version)
+    );
+    for (sec, name) in &pages {
+        body += &format!(
+            "<li><a href=\"{}\">{}({})</a></li>\n",
+            url_path(&[sec, &format!("{name}.{sec}.html")]),
+            escape_html(name),
+            escape_html(sec)
+        );
+    }
+    body += "</ul>";
+    if doc {
+        body += &format!(
+            "\n<p><a href=\"{}/\">Other documentation</a></p>",
+            url_path(&["doc", &package.name])
+        );
+    }
+    document(&prefs, &title, html::Place::Other, &body).into_response()
+}
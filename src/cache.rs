@@ -0,0 +1,202 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Caches of rendered pages: an in-memory LRU bounded by total size,
+//! backed by an optional directory on disk.  Entries are keyed by
+//! source mtime, so an upgraded page is simply a miss; in memory the
+//! stale rendering ages out, on disk it is overwritten.  So too when
+//! other pages, packages or tldr-pages are installed, as they show in
+//! the page around the rendering.
+//!
+//! The memory tier lasts as long as the process: with one process
+//! per connection, just the requests of a keep-alive connection, so
+//! the disk is what is shared.  It does more for `handoc preview`
+//! and embedders of [`crate::Server::router`].
+
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::body::Bytes;
+use lru::LruCache;
+
+use crate::config::config;
+use crate::prefs::Prefs;
+use crate::{lookup, package, tldr, trace, vhost, Format, RENDER_VERSION};
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Key {
+    pub path: PathBuf,
+    pub mtime: SystemTime,
+    pub format: Format,
+    pub prefs: Prefs,
+}
+
+struct Cache {
+    /// With what else was [`installed`] then.
+    entries: LruCache<(Key, u64), Bytes>,
+    bytes: usize,
+}
+
+static CACHE: LazyLock<Mutex<Cache>> = LazyLock::new(|| {
+    Mutex::new(Cache {
+        entries: LruCache::unbounded(),
+        bytes: 0,
+    })
+});
+
+static DISK_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// Keep nothing in memory, as the process serves just one request.
+pub fn disk_only() {
+    DISK_ONLY.store(true, Ordering::Relaxed);
+}
+
+/// Look `key` up in memory, then on disk.  May block.
+pub fn get(key: &Key) -> Option<Bytes> {
+    let mut span = trace::span("cache.get");
+    let key = (
+        key.clone(),
+        installed(&key.path, key.format, key.prefs.lang.as_deref()),
+    );
+    if let Some(page) = CACHE.lock().unwrap().entries.get(&key).cloned() {
+        span.attr("hit", "memory");
+        return Some(page);
+    }
+    let page = disk_get(&key);
+    span.attr("hit", if page.is_some() { "disk" } else { "none" });
+    let page = page?;
+    remember(key, page.clone());
+    Some(page)
+}
+
+/// Store in memory and on disk.  May block.
+pub fn put(key: Key, page: Bytes) {
+    let _span = trace::span("cache.put");
+    let stamp = installed(&key.path, key.format, key.prefs.lang.as_deref());
+    let key = (key, stamp);
+    if let Err(e) = disk_put(&key, &page) {
+        eprintln!("cannot write disk cache: {e}");
+    }
+    remember(key, page);
+}
+
+/// Changes with what else installed goes into the page of source
+/// `path`: the roots of the host, their page indexes, for its links,
+/// versions and references, the package database and its tldr page.
+/// May block.
+pub fn installed(path: &Path, format: Format, lang: Option<&str>) -> u64 {
+    if !matches!(format, Format::Html | Format::Fragment) {
+        return 0;
+    }
+    let file = path
+        .file_name()
+        .and_then(|f| f.to_str())
+        .unwrap_or_default();
+    let name = file.split('.').next().unwrap_or_default();
+    let mut h = std::hash::DefaultHasher::new();
+    (
+        vhost::roots(),
+        lookup::stamp(lang),
+        package::stamp(),
+        tldr::stamp(name, lang),
+    )
+        .hash(&mut h);
+    h.finish()
+}
+
+fn remember(key: (Key, u64), page: Bytes) {
+    let budget = config().cache.memory;
+    if page.len() > budget || DISK_ONLY.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut cache = CACHE.lock().unwrap();
+    cache.bytes += page.len();
+    if let Some(old) = cache.entries.put(key, page) {
+        cache.bytes -= old.len();
+    }
+    while cache.bytes > budget {
+        let Some((_, evicted)) = cache.entries.pop_lru() else {
+            break;
+        };
+        cache.bytes -= evicted.len();
+    }
+}
+
+/// Disk entries are named by everything in the key but the mtime,
+/// which is recorded on the first line instead.
+fn disk_path((key, installed): &(Key, u64)) -> Option<PathBuf> {
+    let dir = config().cache.dir.as_ref()?;
+    let mut h = std::hash::DefaultHasher::new();
+    let renderer = crate::render::renderer().name();
+    let template = crate::template::stamp();
+    let stamps = (RENDER_VERSION, renderer, template, installed);
+    (&key.path, &key.prefs, stamps).hash(&mut h);
+    Some(dir.join(format!("{:016x}.{}", h.finish(), key.format.ext())))
+}
+
+fn stamp(mtime: SystemTime) -> String {
+    let d = mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+    format!("{}.{:09}", d.as_secs(), d.subsec_nanos())
+}
+
+fn disk_get(key: &(Key, u64)) -> Option<Bytes> {
+    let f = std::fs::File::open(disk_path(key)?).ok()?;
+    let mut f = std::io::BufReader::new(f);
+    let mut line = String::new();
+    f.read_line(&mut line).ok()?;
+    if line.trim_end() != stamp(key.0.mtime) {
+        return None;
+    }
+    let mut page = Vec::new();
+    f.read_to_end(&mut page).ok()?;
+    Some(page.into())
+}
+
+fn disk_put(key: &(Key, u64), page: &[u8]) -> std::io::Result<()> {
+    let Some(path) = disk_path(key) else {
+        return Ok(());
+    };
+    // write aside and rename, so readers never see a partial entry
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    let mut f = std::fs::File::create(&tmp)?;
+    writeln!(f, "{}", stamp(key.0.mtime))?;
+    f.write_all(page)?;
+    drop(f);
+    std::fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scope::{self, Scope};
+
+    #[test]
+    fn installed_by_host() {
+        crate::testing::setup();
+        let stamp = |roots| {
+            let scope = Scope {
+                roots: Some(roots),
+                ..Default::default()
+            };
+            let path = Path::new("/man/man1/ls.1.gz");
+            scope::within(scope, || installed(path, Format::Html, None))
+        };
+        let config = config();
+        assert_eq!(stamp(&config.roots), stamp(&config.roots));
+        assert_ne!(
+            stamp(&config.roots),
+            stamp(&config.vhosts["other.test"].roots)
+        );
+        let txt = installed(Path::new("/man/man1/ls.1.gz"), Format::Txt, None);
+        assert_eq!(txt, 0);
+    }
+}
@@ -0,0 +1,109 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! URLs of other man page sites, redirected to our own, so existing
+//! links keep working: mandoc's man.cgi at `/man.cgi` and
+//! `/cgi-bin/man.cgi`, and manpages.debian.org's
+//! `/<suite>/<package>/<page>.<section>.<lang>.html`.
+
+use axum::extract::Path;
+use axum::http::Uri;
+use axum::response::{IntoResponse, Redirect, Response};
+
+use percent_encoding::utf8_percent_encode;
+
+use crate::config::config;
+use crate::error::Error;
+use crate::{lookup, query_param, url_path, with_base, SEGMENT};
+
+/// `?query=NAME&sektion=N&apropos=1`: `sektion` (or `sec`) empty, `0`
+/// or `all` means any; `manpath` and `arch` are ignored.
+pub async fn man_cgi(uri: Uri) -> Response {
+    let query = query_param(&uri, "query").unwrap_or_default();
+    let query = query.trim();
+    let section = query_param(&uri, "sektion")
+        .or_else(|| query_param(&uri, "sec"))
+        .filter(|s| !matches!(s.as_str(), "" | "0" | "all"));
+    let apropos = query_param(&uri, "apropos").is_some_and(|a| a != "0");
+    let dst = if query.is_empty() {
+        with_base("/")
+    } else if apropos {
+        let q = utf8_percent_encode(query, SEGMENT);
+        with_base(&format!("/search?q={q}"))
+    } else {
+        match section {
+            Some(sec) => url_path(&[&format!("{query}.{sec}")]),
+            None => url_path(&[query]),
+        }
+    };
+    Redirect::to(&dst).into_response()
+}
+
+/// The package is ignored, and the suite too unless `debian.suites`
+/// lists those accepted.  The language, without which English is
+/// meant, becomes `?lang=` unless English.
+pub async fn debian(Path((suite, _package, page)): Path<(String, String, String)>) -> Response {
+    let suites = &config().debian.suites;
+    if !suites.is_empty() && !suites.contains(&suite) {
+        return Error::NotFound.into_response();
+    }
+    let Some(page) = page.strip_suffix(".html") else {
+        return Error::NotFound.into_response();
+    };
+    let (page, lang) = match page.rsplit_once('.') {
+        Some((rest, lang)) if language(lang) && rest.contains('.') => (rest, Some(lang)),
+        _ => (page, None),
+    };
+    let Some((name, section)) = page
+        .rsplit_once('.')
+        .filter(|(name, sec)| !name.is_empty() && lookup::valid_section(sec))
+    else {
+        return Error::NotFound.into_response();
+    };
+    let mut dst = url_path(&[section, &format!("{name}.{section}.html")]);
+    if let Some(lang) = lang.filter(|l| *l != "en") {
+        dst += &format!("?lang={}", utf8_percent_encode(lang, SEGMENT));
+    }
+    Redirect::temporary(&dst).into_response()
+}
+
+/// Whether the last part of a page is a language like en, pt_BR or
+/// sr@latin, rather than a section like 1, 3ssl or Tcl's n.
+fn language(s: &str) -> bool {
+    s.len() >= 2
+        && s.chars()
+            .all(|c| c.is_ascii_alphabetic() || "_@-".contains(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn redirect(page: &str) -> Option<String> {
+        crate::testing::setup();
+        let path = Path(("stable".into(), "pkg".into(), page.into()));
+        let res = debian(path).await;
+        let dst = res.headers().get(axum::http::header::LOCATION)?;
+        Some(dst.to_str().unwrap().to_owned())
+    }
+
+    #[tokio::test]
+    async fn debian_languages() {
+        for (page, dst) in [
+            ("ls.1.en.html", "/1/ls.1.html"),
+            ("ls.1.html", "/1/ls.1.html"),
+            ("ls.1.pt_BR.html", "/1/ls.1.html?lang=pt_BR"),
+            ("ls.1.sr@latin.html", "/1/ls.1.html?lang=sr@latin"),
+            ("foo.bar.n.html", "/n/foo.bar.n.html"),
+            ("SSL_read.3ssl.html", "/3ssl/SSL_read.3ssl.html"),
+        ] {
+            assert_eq!(redirect(page).await.as_deref(), Some(dst), "{page}");
+        }
+        assert_eq!(redirect("ls.html").await, None);
+    }
+}
@@ -0,0 +1,721 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use serde::Deserialize;
+
+/// Runtime configuration, read once from `$HANDOC_CONFIG` or
+/// `/etc/handoc.toml` unless given to a [`crate::Server`].  Every key
+/// is optional; a missing file means all defaults.
+#[derive(PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Man trees, each containing man<section> directories; earlier
+    /// roots take precedence.
+    pub roots: Vec<PathBuf>,
+    /// Names of roots, shown when several have the same page;
+    /// defaults to the path.
+    pub labels: HashMap<PathBuf, String>,
+    /// Section probing order for find(), like `$MANSECT`, which
+    /// overrides it.
+    pub mansect: Option<Vec<String>>,
+    /// Cache-Control sent with pages and their revalidations; empty
+    /// to send none.
+    pub cache_control: String,
+    /// Seconds between checks whether the page index is stale.
+    pub index_refresh: u64,
+    /// Seconds before a request still without a response gets 503;
+    /// 0 for no limit.
+    pub request_timeout: u64,
+    /// Proxies whose X-Forwarded-For names the client, for logging
+    /// and rate limits.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Path prefix handoc is served under, e.g. `/man`.
+    pub base_path: String,
+    /// Directory of templates replacing the built-in ones.
+    pub templates: Option<PathBuf>,
+    pub guard: Guard,
+    pub render: Render,
+    pub stats: Stats,
+    pub popular: Popular,
+    pub cache: Cache,
+    pub robots: Robots,
+    pub feed: Feed,
+    pub debian: Debian,
+    pub packages: Packages,
+    pub archives: Archives,
+    pub info: Info,
+    pub pod: Pod,
+    pub tldr: Tldr,
+    pub doc: Doc,
+    pub preview: Preview,
+    pub log: Log,
+    pub otlp: Otlp,
+    pub rate_limit: RateLimit,
+    pub connections: Connections,
+    pub runtime: Runtime,
+    pub security: Security,
+    pub auth: Auth,
+    pub sandbox: Sandbox,
+    pub privileges: Privileges,
+    /// Request paths, under `base_path`, redirected to other paths
+    /// or URLs.
+    pub aliases: HashMap<String, String>,
+    /// Host names, lowercase and without port, served from other
+    /// roots.
+    pub vhosts: HashMap<String, Vhost>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            roots: vec!["/usr/share/man".into()],
+            labels: Default::default(),
+            mansect: None,
+            cache_control: "no-cache".into(),
+            index_refresh: 30,
+            request_timeout: 30,
+            trusted_proxies: vec![[127, 0, 0, 1].into(), std::net::Ipv6Addr::LOCALHOST.into()],
+            base_path: String::new(),
+            templates: None,
+            guard: Default::default(),
+            render: Default::default(),
+            stats: Default::default(),
+            popular: Default::default(),
+            cache: Default::default(),
+            robots: Default::default(),
+            feed: Default::default(),
+            debian: Default::default(),
+            packages: Default::default(),
+            archives: Default::default(),
+            info: Default::default(),
+            pod: Default::default(),
+            tldr: Default::default(),
+            doc: Default::default(),
+            preview: Default::default(),
+            log: Default::default(),
+            otlp: Default::default(),
+            rate_limit: Default::default(),
+            connections: Default::default(),
+            runtime: Default::default(),
+            security: Default::default(),
+            auth: Default::default(),
+            sandbox: Default::default(),
+            privileges: Default::default(),
+            aliases: Default::default(),
+            vhosts: Default::default(),
+        }
+    }
+}
+
+/// Resource thresholds above which new renders are refused.
+#[derive(PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Guard {
+    /// Maximum number of open file descriptors.
+    pub max_fds: Option<usize>,
+    /// Maximum resident set size, in bytes.
+    pub max_rss: Option<u64>,
+    /// How many of the largest consumers to log when a limit is hit.
+    pub report_top: usize,
+}
+
+impl Default for Guard {
+    fn default() -> Self {
+        Self {
+            max_fds: None,
+            max_rss: None,
+            report_top: 5,
+        }
+    }
+}
+
+/// Running the renderer.
+#[derive(PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Render {
+    pub renderer: RendererKind,
+    /// The mandoc binary, looked up in `$PATH` unless a path.
+    pub mandoc: PathBuf,
+    /// Extra `-O` options for HTML output, e.g. `toc`; they replace
+    /// built-in ones of the same name.
+    pub mandoc_options: Vec<String>,
+    /// Renders allowed at once, across all handoc processes; 0 for no
+    /// limit.
+    pub concurrency: usize,
+    /// Seconds a request may wait for a render slot before 503.
+    pub queue_timeout: u64,
+    /// Requests allowed to wait for a slot, across all handoc
+    /// processes, beyond which they get 503 at once; 0 for no limit.
+    pub max_queue: usize,
+    /// Retry-After sent with 503, in seconds.
+    pub retry_after: u64,
+    /// Where the slot lock files and probed renderer capabilities
+    /// live; defaults to `$RUNTIME_DIRECTORY`, else `handoc` in the
+    /// temp directory.
+    pub lock_dir: Option<PathBuf>,
+    /// Seconds before a render is killed.
+    pub timeout: u64,
+    /// Bytes of renderer output above which the render is aborted.
+    pub max_output: usize,
+}
+
+impl Default for Render {
+    fn default() -> Self {
+        Self {
+            renderer: RendererKind::Auto,
+            mandoc: "mandoc".into(),
+            mandoc_options: Vec::new(),
+            concurrency: 8,
+            queue_timeout: 5,
+            max_queue: 64,
+            retry_after: 5,
+            lock_dir: None,
+            timeout: 10,
+            max_output: 16 << 20,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RendererKind {
+    Auto,
+    Mandoc,
+    Groff,
+    #[cfg(feature = "builtin-renderer")]
+    Builtin,
+}
+
+/// Caching of rendered pages.
+#[derive(PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cache {
+    /// Total size of the in-memory cache, in bytes; 0 disables it.
+    /// It is per process, so with one per connection it only serves
+    /// the requests of a keep-alive connection.
+    pub memory: usize,
+    /// Directory for the disk cache, which `handoc warm` fills.
+    pub dir: Option<PathBuf>,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self {
+            memory: 8 << 20,
+            dir: None,
+        }
+    }
+}
+
+/// The policy served as /robots.txt, for all user agents.
+#[derive(PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Robots {
+    /// Path prefixes crawlers are asked to stay off.
+    pub disallow: Vec<String>,
+    /// Exceptions within those.
+    pub allow: Vec<String>,
+}
+
+impl Default for Robots {
+    fn default() -> Self {
+        Self {
+            disallow: ["/api/", "/suggest", "/prefs", "/diff"]
+                .map(String::from)
+                .into(),
+            allow: Vec::new(),
+        }
+    }
+}
+
+/// The feed of recently updated pages.
+#[derive(PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Feed {
+    /// How far back changes are listed.
+    pub days: u64,
+    /// Most entries listed.
+    pub entries: usize,
+}
+
+impl Default for Feed {
+    fn default() -> Self {
+        Self {
+            days: 30,
+            entries: 100,
+        }
+    }
+}
+
+/// manpages.debian.org-style URLs.
+#[derive(PartialEq, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Debian {
+    /// Suites accepted in them; empty for any.
+    pub suites: Vec<String>,
+}
+
+/// Telling which package installed a page.
+#[derive(PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Packages {
+    pub db: PackageDb,
+}
+
+impl Default for Packages {
+    fn default() -> Self {
+        Self {
+            db: PackageDb::Auto,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PackageDb {
+    Auto,
+    Dpkg,
+    Pacman,
+    Rpm,
+    None,
+}
+
+/// Package archives to take pages not installed from.
+#[derive(PartialEq, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Archives {
+    /// Directories searched for .deb and .rpm files.
+    pub pool: Vec<PathBuf>,
+    /// Where pages are extracted; defaults to `archives` in the
+    /// runtime directory.
+    pub dir: Option<PathBuf>,
+}
+
+/// GNU info manuals served under `/info/`.
+#[derive(PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Info {
+    /// Directories of info files, searched in order; empty to serve
+    /// none.
+    pub dirs: Vec<PathBuf>,
+}
+
+impl Default for Info {
+    fn default() -> Self {
+        Self {
+            dirs: vec!["/usr/share/info".into()],
+        }
+    }
+}
+
+/// Perl documentation served under `/pod/`.
+#[derive(PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Pod {
+    pub enabled: bool,
+    /// The perl whose `@INC` is searched, and which renders.
+    pub perl: PathBuf,
+}
+
+impl Default for Pod {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            perl: "perl".into(),
+        }
+    }
+}
+
+/// tldr-pages examples for commands.
+#[derive(PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tldr {
+    /// A checkout or client cache, holding `pages/`; none to show no
+    /// examples.
+    pub dir: Option<PathBuf>,
+    /// Platform directories searched, in order.
+    pub platforms: Vec<String>,
+}
+
+impl Default for Tldr {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            platforms: vec!["common".into(), "linux".into()],
+        }
+    }
+}
+
+/// Package documentation served under `/doc/`.
+#[derive(PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Doc {
+    pub enabled: bool,
+    pub dir: PathBuf,
+}
+
+impl Default for Doc {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            dir: "/usr/share/doc".into(),
+        }
+    }
+}
+
+/// Rendering sources POSTed to `/preview`.
+#[derive(PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Preview {
+    /// Off unless asked for, as anyone may then have roff run.
+    pub enabled: bool,
+    /// Bytes of source accepted, above which 413.
+    pub max_source: usize,
+    /// Seconds before the render is killed.
+    pub timeout: u64,
+}
+
+impl Default for Preview {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_source: 1 << 20,
+            timeout: 5,
+        }
+    }
+}
+
+/// The access log, a line per request on stderr.
+#[derive(PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Log {
+    /// Overridden by `$HANDOC_LOG`.
+    pub format: LogFormat,
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Fields,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Off,
+    /// `key=value` pairs.
+    Fields,
+    /// Common Log Format, as web servers write it.
+    Clf,
+}
+
+/// Requests allowed per client address.
+#[derive(PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimit {
+    /// Requests a second in the long run; 0 for no limit.
+    pub rate: f64,
+    /// Requests allowed at once after a pause.
+    pub burst: f64,
+    /// Clients without a limit.
+    pub exempt: Vec<IpAddr>,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            rate: 0.0,
+            burst: 20.0,
+            exempt: Vec::new(),
+        }
+    }
+}
+
+/// Limits on client connections.
+#[derive(PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Connections {
+    /// Connections served at once, across all handoc processes,
+    /// beyond which they get 503 at once; 0 for no limit.
+    pub max: usize,
+    /// Seconds a client may take to send a request head, from its
+    /// first byte or from connecting; 0 for no limit.
+    pub header_timeout: u64,
+    /// Seconds a connection may sit idle between requests; 0 for no
+    /// limit.
+    pub idle_timeout: u64,
+    /// Whether a connection may carry more than one request.
+    pub keep_alive: bool,
+    /// Requests served on a connection before it is closed; 0 for no
+    /// limit.
+    pub max_requests: usize,
+    /// Whether to go on answering a client that has shut down its
+    /// side of the connection, rather than dropping it.
+    pub half_close: bool,
+    pub mode: ConnectionMode,
+}
+
+impl Default for Connections {
+    fn default() -> Self {
+        Self {
+            max: 256,
+            header_timeout: 10,
+            idle_timeout: 60,
+            keep_alive: true,
+            max_requests: 0,
+            half_close: false,
+            mode: ConnectionMode::Inetd,
+        }
+    }
+}
+
+/// What handoc is given on fd 0.
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionMode {
+    /// A connection, served to its end by the process, as from
+    /// inetd or systemd sockets with `Accept=yes`.
+    Inetd,
+    /// A listening socket, as with `Accept=no`: each connection is
+    /// accepted and served by a process of its own, as inetd would.
+    Listen,
+}
+
+/// How a connection process runs its tasks.
+#[derive(PartialEq, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Runtime {
+    /// Worker threads of a multi-threaded runtime, so that IO goes on
+    /// while other threads are busy; 0 for all on the one thread.
+    pub worker_threads: usize,
+}
+
+/// Headers telling browsers how to treat responses.
+#[derive(PartialEq, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Security {
+    /// Strict-Transport-Security sent with every response, e.g.
+    /// `max-age=31536000`; empty to send none.
+    pub hsts: String,
+}
+
+/// Who may use handoc at all.
+#[derive(PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Auth {
+    /// Client addresses or networks allowed, e.g. `10.0.0.0/8`;
+    /// empty for all.
+    pub allow: Vec<crate::auth::Cidr>,
+    /// htpasswd file of the users allowed; none for no login.
+    pub htpasswd: Option<PathBuf>,
+    /// Realm named when asking for a login.
+    pub realm: String,
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Self {
+            allow: Vec::new(),
+            htpasswd: None,
+            realm: "handoc".into(),
+        }
+    }
+}
+
+/// Confining connection processes and the renderers they run.
+#[derive(PartialEq, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sandbox {
+    pub enabled: bool,
+    /// Paths to allow reading beyond those the config names.
+    pub read: Vec<PathBuf>,
+    /// Paths to allow writing beyond the runtime and cache
+    /// directories.
+    pub write: Vec<PathBuf>,
+}
+
+/// What to give up when started as root.
+#[derive(PartialEq, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Privileges {
+    /// User to become; none to stay root.
+    pub user: Option<String>,
+    /// Group to become instead of the user's own.
+    pub group: Option<String>,
+    /// Directory to make the root directory.
+    pub chroot: Option<PathBuf>,
+    /// Working directory, within any new root.
+    pub directory: Option<PathBuf>,
+}
+
+/// Sending traces to an OpenTelemetry collector.
+#[derive(PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Otlp {
+    /// OTLP/HTTP traces URL, e.g. `http://localhost:4318/v1/traces`;
+    /// none sends nothing.
+    pub endpoint: Option<String>,
+    pub service_name: String,
+}
+
+impl Default for Otlp {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            service_name: "handoc".into(),
+        }
+    }
+}
+
+/// A host served from its own man trees.
+#[derive(PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Vhost {
+    pub roots: Vec<PathBuf>,
+}
+
+/// Page hit counting and periodic export of the aggregates.
+#[derive(PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Stats {
+    pub enabled: bool,
+    /// Seconds between exports; counts are also exported when the
+    /// connection ends.
+    pub interval: u64,
+    pub sinks: Vec<Sink>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: 60,
+            sinks: Vec::new(),
+        }
+    }
+}
+
+#[derive(PartialEq, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Sink {
+    /// Cumulative totals merged into a JSON object on disk.
+    Json { path: PathBuf },
+    /// Counter increments sent over UDP.
+    Statsd {
+        addr: String,
+        #[serde(default = "default_statsd_prefix")]
+        prefix: String,
+    },
+    /// Increments POSTed as a JSON object; plain http only.
+    Webhook { url: String },
+}
+
+fn default_statsd_prefix() -> String {
+    "handoc".into()
+}
+
+/// Hit counts kept on disk, for `/popular` and the API.
+#[derive(PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Popular {
+    pub enabled: bool,
+    /// The file counts are kept in; `popular.json` in the cache
+    /// directory, or else the runtime directory, by default.
+    pub path: Option<PathBuf>,
+    /// Seconds between writes; counts are also written when the
+    /// connection ends.
+    pub interval: u64,
+    /// Most pages `/popular` lists.
+    pub entries: usize,
+}
+
+impl Default for Popular {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: None,
+            interval: 60,
+            entries: 50,
+        }
+    }
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// The config in use: as installed by a [`crate::Server`], else read
+/// from the file, panicking if that fails.
+pub fn config() -> &'static Config {
+    CONFIG.get_or_init(|| load().unwrap_or_else(|e| panic!("{e}")))
+}
+
+/// Why a config cannot be used.
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Invalid(PathBuf, toml::de::Error),
+    /// The process uses another already.
+    Installed,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "cannot read {}: {e}", path.display()),
+            ConfigError::Invalid(path, e) => write!(f, "invalid config {}: {e}", path.display()),
+            ConfigError::Installed => f.write_str("another config is in use already"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Use `config` rather than reading the file; once one is in use,
+/// only the same again.
+pub(crate) fn install(config: Config) -> Result<(), ConfigError> {
+    match CONFIG.set(config) {
+        Err(config) if CONFIG.get() != Some(&config) => Err(ConfigError::Installed),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn load() -> Result<Config, ConfigError> {
+    let path = std::env::var_os("HANDOC_CONFIG")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| "/etc/handoc.toml".into());
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Default::default()),
+        Err(e) => return Err(ConfigError::Read(path, e)),
+    };
+    toml::from_str(&text).map_err(|e| ConfigError::Invalid(path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn installed_once() {
+        crate::testing::setup();
+        let ours = crate::testing::server();
+        assert!(ours.install().is_ok());
+        let other = Config {
+            roots: vec!["/elsewhere".into()],
+            ..Default::default()
+        };
+        assert!(matches!(install(other), Err(ConfigError::Installed)));
+        assert_eq!(config().roots, [PathBuf::from("/man")]);
+    }
+}
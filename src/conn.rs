@@ -0,0 +1,220 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Protection from clients holding connections: a limit on
+//! connections at once, shared by every handoc process like render
+//! slots, timeouts for sending a request head and for idling between
+//! requests, and a limit on requests per connection.  Also accepting
+//! connections, when given a listening socket.
+//!
+//! hyper's own header read timeout also runs while waiting for the
+//! next request, so both are kept here instead, from what the
+//! connection reads and which requests are in flight.
+//!
+//! That state is the process's, as is its connection: [`layer`] is
+//! applied by [`crate::Server::serve_socket`] only, never to the
+//! router given out by [`crate::Server::router`].
+
+use std::fs::File;
+use std::io::{self, ErrorKind, Write};
+use std::mem::ManuallyDrop;
+use std::net::{TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use axum::extract::Request;
+use axum::http::{header, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::config::config;
+use crate::{access, guard};
+
+struct State {
+    in_flight: usize,
+    /// Requests begun on the connection.
+    served: usize,
+    /// When the connection last went idle.
+    idle: Instant,
+    /// When the next request head started arriving.
+    head: Option<Instant>,
+}
+
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+fn state<R>(f: impl FnOnce(&mut State) -> R) -> R {
+    let mut state = STATE.lock().unwrap();
+    f(state.get_or_insert_with(|| {
+        let now = Instant::now();
+        // the first request is due from connecting
+        State {
+            in_flight: 0,
+            served: 0,
+            idle: now,
+            head: Some(now),
+        }
+    }))
+}
+
+/// One of the `connections.max` places, released on drop.
+pub struct Place {
+    _lock: Option<File>,
+}
+
+/// A place for `sock`, or none once it is sent 503 instead.
+pub fn admit(mut sock: &TcpStream) -> Option<Place> {
+    let max = config().connections.max;
+    let Some(dir) = guard::runtime_dir().filter(|_| max > 0) else {
+        return Some(Place { _lock: None });
+    };
+    if let Some(f) = guard::lock_one(dir, "conn", max) {
+        return Some(Place { _lock: Some(f) });
+    }
+    eprintln!("{max} connections open, refusing another");
+    let res = format!(
+        "HTTP/1.1 503 Service Unavailable\r\nRetry-After: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        config().render.retry_after
+    );
+    sock.write_all(res.as_bytes()).ok();
+    None
+}
+
+/// Accept connections on the listening socket at fd 0, forking a
+/// process for each with its connection on fd 0 instead.  Returns in
+/// those processes only, unless accepting fails.  As it forks, it
+/// must come before any other thread is started.
+pub fn fork_each() -> io::Result<()> {
+    let listener = ManuallyDrop::new(unsafe { TcpListener::from_raw_fd(0) });
+    // reaped by the kernel, not waited for
+    unsafe { libc::signal(libc::SIGCHLD, libc::SIG_IGN) };
+    loop {
+        let sock = match listener.accept() {
+            Ok((sock, _)) => sock,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::Interrupted | ErrorKind::ConnectionAborted
+                ) =>
+            {
+                continue
+            }
+            Err(e) => return Err(e),
+        };
+        match unsafe { libc::fork() } {
+            0 => {
+                // renderers are waited for
+                unsafe { libc::signal(libc::SIGCHLD, libc::SIG_DFL) };
+                if unsafe { libc::dup2(sock.as_raw_fd(), 0) } < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                return Ok(());
+            }
+            -1 => eprintln!("cannot fork: {}", io::Error::last_os_error()),
+            _ => {}
+        }
+    }
+}
+
+/// Counts requests in flight, until their bodies are through, and
+/// closes the connection after the last one allowed.
+pub async fn layer(req: Request, next: Next) -> Response {
+    let served = state(|s| {
+        s.in_flight += 1;
+        s.served += 1;
+        s.head = None;
+        s.served
+    });
+    let mut res = next.run(req).await;
+    let max = config().connections.max_requests;
+    if max > 0 && served >= max {
+        res.headers_mut()
+            .insert(header::CONNECTION, HeaderValue::from_static("close"));
+    }
+    access::on_end(res, |_| {
+        state(|s| {
+            s.in_flight -= 1;
+            s.idle = Instant::now();
+        })
+    })
+}
+
+/// Resolves once the connection has taken too long sending a request
+/// head, or been idle too long; nothing is in flight by then, so the
+/// connection can just be dropped.
+pub async fn expired() {
+    let limits = &config().connections;
+    let secs = |n| (n > 0).then(|| Duration::from_secs(n));
+    loop {
+        let due = state(|s| match s.head {
+            _ if s.in_flight > 0 => None,
+            Some(head) => secs(limits.header_timeout).map(|d| head + d),
+            None => secs(limits.idle_timeout).map(|d| s.idle + d),
+        });
+        match due {
+            Some(due) if due <= Instant::now() => return,
+            Some(due) => tokio::time::sleep_until(due.into()).await,
+            None => tokio::time::sleep(Duration::from_secs(1)).await,
+        }
+    }
+}
+
+/// A connection noting when request heads start arriving.
+pub struct Watched<T>(pub T);
+
+impl<T: AsyncRead + Unpin> AsyncRead for Watched<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.0).poll_read(cx, buf);
+        if buf.filled().len() > before {
+            state(|s| {
+                if s.in_flight == 0 && s.head.is_none() {
+                    s.head = Some(Instant::now());
+                }
+            });
+        }
+        poll
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Watched<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.0.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
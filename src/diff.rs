@@ -0,0 +1,233 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! `/diff?a=/1/tar.1&b=/local/1/tar.1`: a word-level diff of the text
+//! renderings of two pages, e.g. the copies of a page in two roots,
+//! named by their labels, as after a package upgrade.
+
+use std::path::PathBuf;
+
+use axum::http::Uri;
+use axum::response::{IntoResponse, Response};
+
+use crate::error::{Context, Error};
+use crate::prefs::Prefs;
+use crate::source::source;
+use crate::{
+    bg, browse, cache, escape_html, guard, html, lookup, query_param, render, with_base, Format,
+};
+
+/// Past this many cells of the comparison table, the differing middle
+/// is shown as replaced in whole.
+const MAX_CELLS: usize = 1 << 24;
+
+pub async fn diff(uri: Uri, prefs: Prefs) -> Response {
+    let (a, b) = (query_param(&uri, "a"), query_param(&uri, "b"));
+    let mut body = format!(
+        "<h1>Compare pages</h1>\n<form class=\"diff\" action=\"{}\" method=\"get\">\
+         <input name=\"a\" placeholder=\"/1/tar.1\" value=\"{}\"/> \
+         <input name=\"b\" placeholder=\"/local/1/tar.1\" value=\"{}\"/> \
+         <button>Compare</button></form>\n",
+        with_base("/diff"),
+        escape_html(a.as_deref().unwrap_or_default()),
+        escape_html(b.as_deref().unwrap_or_default()),
+    );
+    if let (Some(a), Some(b)) = (a, b) {
+        let lang = prefs.lang.clone();
+        let (a, b) = match tokio::join!(text(&a, lang.clone()), text(&b, lang)) {
+            (Ok(a), Ok(b)) => (a, b),
+            (Err(e), _) | (_, Err(e)) => return e.into_response(),
+        };
+        body += "<pre class=\"diff\">";
+        body += &markup(&a, &b);
+        body += "</pre>";
+    }
+    browse::document(&prefs, "Compare pages", html::Place::Other, &body).into_response()
+}
+
+/// Source of `spec`, `[/<label>]/<section>/<name>.<section>`: the copy
+/// in the root of that label, else the one a page URL shows.
+fn locate(spec: &str, lang: Option<&str>) -> Option<PathBuf> {
+    let spec = spec.trim_start_matches('/');
+    let spec = [".html", ".txt"]
+        .iter()
+        .find_map(|ext| spec.strip_suffix(ext))
+        .unwrap_or(spec);
+    let mut parts = spec.rsplitn(3, '/');
+    let (name, section) = (parts.next()?, parts.next()?);
+    let file = format!("{name}.gz");
+    match parts.next() {
+        Some(label) => lookup::versions(section, &file, lang)
+            .into_iter()
+            .find_map(|(l, p)| (l.trim_matches('/') == label.trim_matches('/')).then_some(p)),
+        None => lookup::resolve(section, &file, lang),
+    }
+}
+
+/// The text rendering of the page `spec` names.
+async fn text(spec: &str, lang: Option<String>) -> Result<String, Error> {
+    let spec = spec.to_owned();
+    let (path, mtime) = bg({
+        let lang = lang.clone();
+        move || -> Result<_, Error> {
+            let path = locate(&spec, lang.as_deref()).ok_or(Error::NotFound)?;
+            let mtime = source().metadata(&path).at("stat", &path)?.modified;
+            Ok((path, mtime))
+        }
+    })
+    .await?;
+    let key = cache::Key {
+        path,
+        mtime,
+        format: Format::Txt,
+        prefs: Prefs {
+            lang,
+            ..Default::default()
+        },
+    };
+    let (cached, key) = bg(move || (cache::get(&key), key)).await;
+    let text = match cached {
+        Some(text) => text,
+        None => {
+            bg(guard::check).await?;
+            let slot = guard::slot().await?;
+            render::collect(key, Some(slot)).await?
+        }
+    };
+    Ok(String::from_utf8_lossy(&text).into_owned())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    Same,
+    Del,
+    Ins,
+}
+
+/// Edits turning `a` into `b`, each with the length of the run of
+/// tokens it covers.
+fn edits<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(Op, usize)> {
+    let pre = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let (a, b) = (&a[pre..], &b[pre..]);
+    let post = a
+        .iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (a, b) = (&a[..a.len() - post], &b[..b.len() - post]);
+    let mut ret = vec![(Op::Same, pre)];
+    if (a.len() + 1).saturating_mul(b.len() + 1) > MAX_CELLS {
+        ret.extend([(Op::Del, a.len()), (Op::Ins, b.len())]);
+    } else {
+        // longest common subsequence of the suffixes, then walk it
+        let w = b.len() + 1;
+        let mut lcs = vec![0u32; (a.len() + 1) * w];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lcs[i * w + j] = if a[i] == b[j] {
+                    lcs[(i + 1) * w + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * w + j].max(lcs[i * w + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() || j < b.len() {
+            let op = if i < a.len() && j < b.len() && a[i] == b[j] {
+                Op::Same
+            } else if j == b.len() || (i < a.len() && lcs[(i + 1) * w + j] >= lcs[i * w + j + 1]) {
+                Op::Del
+            } else {
+                Op::Ins
+            };
+            i += (op != Op::Ins) as usize;
+            j += (op != Op::Del) as usize;
+            ret.push((op, 1));
+        }
+    }
+    ret.push((Op::Same, post));
+    // merge runs
+    let mut merged: Vec<(Op, usize)> = Vec::new();
+    for (op, n) in ret.into_iter().filter(|&(_, n)| n > 0) {
+        match merged.last_mut() {
+            Some((last, m)) if *last == op => *m += n,
+            _ => merged.push((op, n)),
+        }
+    }
+    merged
+}
+
+/// Words and the runs of white space between them.
+fn words(s: &str) -> Vec<&str> {
+    let mut ret = Vec::new();
+    let (mut start, mut space) = (0, None);
+    for (i, c) in s.char_indices() {
+        if space.is_some_and(|sp| sp != c.is_whitespace()) {
+            ret.push(&s[start..i]);
+            start = i;
+        }
+        space = Some(c.is_whitespace());
+    }
+    if start < s.len() {
+        ret.push(&s[start..]);
+    }
+    ret
+}
+
+/// `a` and `b` as one text, with `del` and `ins` around the words
+/// only either has.  Lines are compared first, then the words of each
+/// run of changed lines.
+fn markup(a: &str, b: &str) -> String {
+    let (la, lb): (Vec<_>, Vec<_>) = (
+        a.split_inclusive('\n').collect(),
+        b.split_inclusive('\n').collect(),
+    );
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    let ops = edits(&la, &lb);
+    let mut k = 0;
+    while k < ops.len() {
+        let (op, n) = ops[k];
+        if op == Op::Same {
+            out += &escape_html(&la[i..i + n].concat());
+            (i, j, k) = (i + n, j + n, k + 1);
+            continue;
+        }
+        // a run of changed lines: deleted, inserted, or both
+        let (mut dn, mut inn) = (0, 0);
+        while let Some(&(op, n)) = ops.get(k).filter(|(op, _)| *op != Op::Same) {
+            match op {
+                Op::Del => dn += n,
+                _ => inn += n,
+            }
+            k += 1;
+        }
+        let (old, new) = (la[i..i + dn].concat(), lb[j..j + inn].concat());
+        (i, j) = (i + dn, j + inn);
+        let (wa, wb) = (words(&old), words(&new));
+        let (mut x, mut y) = (0, 0);
+        for (op, n) in edits(&wa, &wb) {
+            match op {
+                Op::Same => {
+                    out += &escape_html(&wa[x..x + n].concat());
+                    (x, y) = (x + n, y + n);
+                }
+                Op::Del => {
+                    out += &format!("<del>{}</del>", escape_html(&wa[x..x + n].concat()));
+                    x += n;
+                }
+                Op::Ins => {
+                    out += &format!("<ins>{}</ins>", escape_html(&wb[y..y + n].concat()));
+                    y += n;
+                }
+            }
+        }
+    }
+    out
+}
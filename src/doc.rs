@@ -0,0 +1,158 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! The rest of what packages document, under `/doc/<package>/`: the
+//! files of `/usr/share/doc`, listed by directory, decompressed when
+//! gzipped, and as HTML when Markdown, else as plain text.
+
+use std::io::Read;
+use std::path::PathBuf;
+
+use axum::extract::Path;
+use axum::http::{header, Uri};
+use axum::response::{IntoResponse, Redirect, Response};
+use percent_encoding::utf8_percent_encode;
+
+use crate::config::config;
+use crate::error::{Context, Error};
+use crate::prefs::Prefs;
+use crate::{bg, browse, escape_html, html, lookup, markdown, url_path, SEGMENT};
+
+/// The directory of `package`'s documentation, if there is one.
+pub fn dir(package: &str) -> Option<PathBuf> {
+    let doc = &config().doc;
+    let dir = doc.dir.join(package);
+    (doc.enabled && lookup::valid_component(package) && dir.is_dir()).then_some(dir)
+}
+
+pub async fn root(prefs: Prefs) -> Result<Response, Error> {
+    listing(String::new(), prefs).await
+}
+
+pub async fn path(Path(path): Path<String>, uri: Uri, prefs: Prefs) -> Result<Response, Error> {
+    if path.ends_with('/') || uri.path().ends_with('/') {
+        return listing(path, prefs).await;
+    }
+    let title = path.clone();
+    let found = bg(move || -> Result<_, Error> {
+        let file = resolve(&path).ok_or(Error::NotFound)?;
+        if file.is_dir() {
+            return Ok(None);
+        }
+        let mut src = Vec::new();
+        let f = std::fs::File::open(&file).at("open", &file)?;
+        let gz = file.extension().is_some_and(|e| e == "gz");
+        if gz {
+            flate2::read::GzDecoder::new(f).read_to_end(&mut src)
+        } else {
+            std::io::BufReader::new(f).read_to_end(&mut src)
+        }
+        .at("read", &file)?;
+        let name = file.file_name().unwrap().to_string_lossy().into_owned();
+        let name = if gz {
+            name.trim_end_matches(".gz").to_owned()
+        } else {
+            name
+        };
+        Ok(Some((name, src)))
+    })
+    .await?;
+    let Some((name, src)) = found else {
+        // directories are listed under their URL with a slash
+        return Ok(Redirect::permanent(&(url(&title) + "/")).into_response());
+    };
+    let markdown = [".md", ".markdown"].iter().any(|e| name.ends_with(e));
+    Ok(match String::from_utf8(src) {
+        Ok(text) if markdown => {
+            let body = markdown::render(&text);
+            browse::document(&prefs, &title, html::Place::Other, &body).into_response()
+        }
+        Ok(text) => (
+            [
+                (header::CONTENT_TYPE, "text/plain; charset=utf-8"),
+                (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+            ],
+            text,
+        )
+            .into_response(),
+        Err(e) => (
+            [
+                (header::CONTENT_TYPE, "application/octet-stream"),
+                (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+            ],
+            e.into_bytes(),
+        )
+            .into_response(),
+    })
+}
+
+/// URL of `path` in the documentation tree.
+fn url(path: &str) -> String {
+    let segments: Vec<_> = std::iter::once("doc")
+        .chain(path.split('/').filter(|p| !p.is_empty()))
+        .collect();
+    url_path(&segments)
+}
+
+/// The file at relative `path` in the documentation tree, which links
+/// inside the tree may lead to but nothing else.  Blocks.
+fn resolve(path: &str) -> Option<PathBuf> {
+    let doc = &config().doc;
+    if !doc.enabled {
+        return None;
+    }
+    let parts: Vec<_> = path.split('/').filter(|p| !p.is_empty()).collect();
+    if !parts.iter().all(|p| lookup::valid_component(p)) {
+        return None;
+    }
+    let file = parts.iter().fold(doc.dir.clone(), |p, c| p.join(c));
+    let top = doc.dir.canonicalize().ok()?;
+    file.canonicalize()
+        .ok()
+        .filter(|real| real.starts_with(&top))
+        .map(|_| file)
+}
+
+async fn listing(path: String, prefs: Prefs) -> Result<Response, Error> {
+    let entries = bg({
+        let path = path.clone();
+        move || {
+            let dir = resolve(&path)?;
+            let mut entries: Vec<_> = std::fs::read_dir(dir)
+                .ok()?
+                .flatten()
+                .filter_map(|e| {
+                    let name = e.file_name().into_string().ok()?;
+                    Some((name, e.path().is_dir()))
+                })
+                .collect();
+            entries.sort_unstable();
+            Some(entries)
+        }
+    })
+    .await
+    .ok_or(Error::NotFound)?;
+    let path = path.trim_matches('/');
+    let title = if path.is_empty() {
+        "Documentation".to_owned()
+    } else {
+        format!("Documentation: {path}")
+    };
+    let here = url(path) + "/";
+    let mut body = format!("<h1>{}</h1>\n<ul class=\"pages\">\n", escape_html(&title));
+    for (name, is_dir) in &entries {
+        let slash = if *is_dir { "/" } else { "" };
+        body += &format!(
+            "<li><a href=\"{here}{}{slash}\">{}{slash}</a></li>\n",
+            utf8_percent_encode(name, SEGMENT),
+            escape_html(name)
+        );
+    }
+    body += "</ul>";
+    Ok(browse::document(&prefs, &title, html::Place::Other, &body).into_response())
+}
@@ -0,0 +1,104 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Character encodings of page sources.  Older and localized pages
+//! are often not UTF-8, but renderers are always given UTF-8: other
+//! sources are converted, from what an Emacs-style `coding:` line in
+//! their first two lines says, as preconv(1) goes by, or else from
+//! what is usual in the language of their tree, or else Latin-1.
+
+use std::path::Path;
+
+use encoding_rs::Encoding;
+
+use crate::lookup;
+
+/// `src`, the source of the page at `path`, in UTF-8, or none if it
+/// is already.  What is not valid in its encoding either is replaced
+/// rather than failing the page over it.
+pub fn convert(src: &[u8], path: &Path) -> Option<Vec<u8>> {
+    if std::str::from_utf8(src).is_ok() {
+        return None;
+    }
+    let enc = declared(src)
+        .or_else(|| by_locale(&lookup::language(path)?))
+        .unwrap_or(encoding_rs::WINDOWS_1252);
+    let (text, _, lossy) = enc.decode(src);
+    if lossy {
+        eprintln!("{}: not valid {}", path.display(), enc.name());
+    }
+    Some(text.into_owned().into_bytes())
+}
+
+/// The encoding named by a coding line, as `.\" -*- coding: latin-1 -*-`.
+fn declared(src: &[u8]) -> Option<&'static Encoding> {
+    src.split(|&b| b == b'\n').take(2).find_map(|line| {
+        let line = String::from_utf8_lossy(line);
+        let (_, name) = line.split_once("-*-")?.1.split_once("coding:")?;
+        let name = name.trim_start().split([' ', '\t', ';']).next()?;
+        label(emacs(name))
+    })
+}
+
+/// The standard name for an Emacs coding system.
+fn emacs(name: &str) -> &str {
+    let name = ["-unix", "-dos", "-mac"]
+        .iter()
+        .find_map(|s| name.strip_suffix(s))
+        .unwrap_or(name);
+    match name {
+        "latin-1" => "iso-8859-1",
+        "latin-2" => "iso-8859-2",
+        "latin-5" => "iso-8859-9",
+        "latin-9" => "iso-8859-15",
+        "euc-japan" | "japanese-iso-8bit" => "euc-jp",
+        "euc-korea" | "korean-iso-8bit" => "euc-kr",
+        "chinese-iso-8bit" | "cn-gb" => "gb2312",
+        "chinese-big5" | "cn-big5" => "big5",
+        "cyrillic-koi8" => "koi8-r",
+        name => name,
+    }
+}
+
+/// The encoding called `name`, also in locale spellings like `eucJP`.
+fn label(name: &str) -> Option<&'static Encoding> {
+    let name = name.to_ascii_lowercase();
+    Encoding::for_label(name.as_bytes()).or_else(|| {
+        let prefix = ["euc", "koi8"].into_iter().find(|p| name.starts_with(p))?;
+        let (head, tail) = name.split_at(prefix.len());
+        Encoding::for_label(format!("{head}-{tail}").as_bytes())
+    })
+}
+
+/// The encoding for sources in trees for `locale`, like `ja` or
+/// `pl_PL.ISO8859-2`.
+fn by_locale(locale: &str) -> Option<&'static Encoding> {
+    let locale = locale.split('@').next()?;
+    let (lang, charset) = locale.split_once('.').unwrap_or((locale, ""));
+    // a tree said to be UTF-8 holding something else is no help
+    if let Some(enc) = label(charset).filter(|&e| e != encoding_rs::UTF_8) {
+        return Some(enc);
+    }
+    let (lang, country) = lang.split_once('_').unwrap_or((lang, ""));
+    Some(match (lang, country) {
+        ("ja", _) => encoding_rs::EUC_JP,
+        ("ko", _) => encoding_rs::EUC_KR,
+        ("zh", "TW" | "HK") => encoding_rs::BIG5,
+        ("zh", _) => encoding_rs::GB18030,
+        ("th", _) => encoding_rs::WINDOWS_874,
+        ("ru", _) => encoding_rs::KOI8_R,
+        ("uk", _) => encoding_rs::KOI8_U,
+        ("be" | "bg", _) => encoding_rs::WINDOWS_1251,
+        ("cs" | "hr" | "hu" | "pl" | "ro" | "sk" | "sl", _) => encoding_rs::ISO_8859_2,
+        ("el", _) => encoding_rs::ISO_8859_7,
+        ("he", _) => encoding_rs::ISO_8859_8,
+        ("tr", _) => encoding_rs::WINDOWS_1254,
+        ("lt" | "lv", _) => encoding_rs::ISO_8859_13,
+        _ => return None,
+    })
+}
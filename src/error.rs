@@ -0,0 +1,195 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Why a request failed, turned into a response in one place: server
+//! errors are logged with what was being done to which file, and the
+//! client gets a short page saying what went wrong, or under the API,
+//! the same as JSON.
+
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+
+use crate::config::config;
+use crate::guard::Overloaded;
+use crate::prefs::Prefs;
+use crate::render::RenderError;
+use crate::{browse, escape_html, html};
+
+#[derive(Debug)]
+pub enum Error {
+    /// No such page, or nothing served at the path.
+    NotFound,
+    /// What is wrong with the request.
+    BadRequest(&'static str),
+    TooLarge,
+    /// `stage` failed, on `path` where there is one.
+    Io {
+        stage: &'static str,
+        path: Option<PathBuf>,
+        error: io::Error,
+    },
+    /// The renderer, or another program run for the page, failed.
+    Render(RenderError),
+    /// Too busy to take on the request; try again later.
+    Overloaded,
+}
+
+/// What an error response says, for the API to give as JSON instead.
+#[derive(Clone)]
+pub struct Message(pub String);
+
+impl Error {
+    fn status(&self) -> StatusCode {
+        match self {
+            Error::NotFound => StatusCode::NOT_FOUND,
+            Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::Io { error, .. } | Error::Render(RenderError::Io(error)) => match error.kind() {
+                ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            },
+            Error::Render(RenderError::Failed(..)) => StatusCode::BAD_GATEWAY,
+            Error::Render(RenderError::Timeout) => StatusCode::GATEWAY_TIMEOUT,
+            Error::Render(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    /// What the client is told beyond the status; paths, I/O errors
+    /// and what the renderer was and said stay in the log.
+    fn detail(&self) -> Option<String> {
+        match self {
+            Error::BadRequest(why) => Some(why.to_string()),
+            Error::Render(RenderError::Io(_)) => None,
+            Error::Render(RenderError::Spawn(..)) => Some("cannot run the renderer".into()),
+            Error::Render(RenderError::Failed(..)) => Some("the renderer failed".into()),
+            Error::Render(e) => Some(e.to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NotFound => f.write_str("not found"),
+            Error::BadRequest(why) => f.write_str(why),
+            Error::TooLarge => f.write_str("request body too large"),
+            Error::Io {
+                stage,
+                path: Some(path),
+                error,
+            } => write!(f, "{stage} {}: {error}", path.display()),
+            Error::Io {
+                stage,
+                path: None,
+                error,
+            } => write!(f, "{stage}: {error}"),
+            Error::Render(e) => write!(f, "render failed: {e}"),
+            Error::Overloaded => f.write_str("overloaded"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        // overloads are reported where they are found
+        if status.is_server_error() && !matches!(self, Error::Overloaded) {
+            eprintln!("{self}");
+        }
+        let detail = self.detail();
+        let mut body = format!("<h1>{status}</h1>\n");
+        if let Some(detail) = &detail {
+            body += &format!("<pre>{}</pre>\n", escape_html(detail));
+        }
+        let title = status.to_string();
+        let page = browse::document(&Prefs::default(), &title, html::Place::Other, &body);
+        let mut res = (status, page).into_response();
+        if let Error::Overloaded = self {
+            res.headers_mut().insert(
+                header::RETRY_AFTER,
+                config().render.retry_after.to_string().parse().unwrap(),
+            );
+        }
+        let reason = status.canonical_reason().unwrap_or("error");
+        res.extensions_mut()
+            .insert(Message(detail.unwrap_or_else(|| reason.to_owned())));
+        res
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io {
+            stage: "I/O",
+            path: None,
+            error,
+        }
+    }
+}
+
+impl From<RenderError> for Error {
+    fn from(e: RenderError) -> Self {
+        Error::Render(e)
+    }
+}
+
+impl From<Overloaded> for Error {
+    fn from(_: Overloaded) -> Self {
+        Error::Overloaded
+    }
+}
+
+/// Saying what failed on I/O errors.
+pub trait Context<T> {
+    /// Failed at `stage` on `path`, as `"read"` or `"stat"`.
+    fn at(self, stage: &'static str, path: &Path) -> Result<T, Error>;
+}
+
+impl<T> Context<T> for io::Result<T> {
+    fn at(self, stage: &'static str, path: &Path) -> Result<T, Error> {
+        self.map_err(|error| Error::Io {
+            stage,
+            path: Some(path.to_owned()),
+            error,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    #[test]
+    fn renderer_output_kept_in() {
+        crate::testing::setup();
+        let failed = RenderError::Failed(
+            "/opt/secret/mandoc".into(),
+            std::process::ExitStatus::from_raw(1 << 8),
+            "mandoc: /home/someone/page.1: ERROR".into(),
+        );
+        let spawn = RenderError::Spawn("/opt/secret/mandoc".into(), io::ErrorKind::NotFound.into());
+        for e in [failed, spawn] {
+            let res = Error::Render(e).into_response();
+            let Message(told) = res.extensions().get::<Message>().unwrap().clone();
+            assert!(
+                !told.contains("secret") && !told.contains("someone"),
+                "{told}"
+            );
+        }
+    }
+}
@@ -0,0 +1,151 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Self-contained copies of pages, for mailing or archiving: the
+//! built-in stylesheet and favicon inlined, and with the URL of the
+//! site known, a `<base>` so links still lead there along with the
+//! canonical URL of the page.  Served for `?standalone=1`, and
+//! written by `handoc export [--host NAME] [--url URL] SECTION/NAME`.
+
+use std::process::ExitCode;
+
+use lol_html::html_content::ContentType;
+use lol_html::{element, rewrite_str, RewriteStrSettings};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+
+use crate::prefs::Prefs;
+use crate::source::source;
+use crate::{assets, base, cache, check_so, escape_html, lookup, render, url_path, vhost, Format};
+
+/// `doc` made standalone; `site` is where the site is, as
+/// `scheme://host`, and `path` the page under it.
+pub fn standalone(doc: &str, site: Option<&str>, path: &str) -> String {
+    let head = site.map(|site| {
+        format!(
+            "<base href=\"{0}{1}/\">\n<link rel=\"canonical\" href=\"{0}{2}\">\n",
+            escape_html(site),
+            escape_html(base()),
+            escape_html(path)
+        )
+    });
+    let handlers = vec![
+        element!("head", move |el| {
+            if let Some(head) = &head {
+                el.prepend(head, ContentType::Html);
+            }
+            el.append(
+                "<meta name=\"generator\" content=\"handoc\">\n",
+                ContentType::Html,
+            );
+            Ok(())
+        }),
+        element!("link[rel=stylesheet][href]", |el| {
+            let href = el.get_attribute("href").unwrap_or_default();
+            if let Some((_, css)) = assets::linked(&href) {
+                let css = String::from_utf8_lossy(css);
+                el.replace(&format!("<style>\n{css}</style>"), ContentType::Html);
+            }
+            Ok(())
+        }),
+        element!("link[rel=icon][href]", |el| {
+            let href = el.get_attribute("href").unwrap_or_default();
+            if let Some((mime, body)) = assets::linked(&href) {
+                let data = utf8_percent_encode(&String::from_utf8_lossy(body), NON_ALPHANUMERIC)
+                    .to_string();
+                el.set_attribute("href", &format!("data:{mime},{data}"))?;
+            }
+            Ok(())
+        }),
+        // nothing to add as a search engine from a file
+        element!("link[rel=search]", |el| {
+            el.remove();
+            Ok(())
+        }),
+    ];
+    let settings = handlers
+        .into_iter()
+        .fold(RewriteStrSettings::new(), |s, h| {
+            s.append_element_content_handler(h)
+        });
+    rewrite_str(doc, settings).unwrap_or_else(|e| {
+        eprintln!("cannot make page standalone: {e}");
+        doc.to_owned()
+    })
+}
+
+pub fn run(args: &[String]) -> ExitCode {
+    let mut site = None;
+    let mut page = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--host" => match args.next() {
+                Some(v) => vhost::select(v),
+                None => return usage(),
+            },
+            "--url" => match args.next() {
+                Some(v) => site = Some(v.trim_end_matches('/').to_owned()),
+                None => return usage(),
+            },
+            _ if page.is_none() => page = Some(arg.as_str()),
+            _ => return usage(),
+        }
+    }
+    let Some((section, name)) = page.and_then(|p| p.trim_matches('/').split_once('/')) else {
+        return usage();
+    };
+    let name = name.strip_suffix(".html").unwrap_or(name);
+    let name = if name.ends_with(&format!(".{section}")) {
+        name.to_owned()
+    } else {
+        format!("{name}.{section}")
+    };
+    let Some(path) = lookup::resolve(section, &format!("{name}.gz"), None) else {
+        eprintln!("export: no page {section}/{name}");
+        return ExitCode::FAILURE;
+    };
+    if let Ok(Some(so)) = check_so(&path) {
+        eprintln!("export: {section}/{name} is only a link to {so}");
+        return ExitCode::FAILURE;
+    }
+    let key = match source().metadata(&path).map(|m| m.modified) {
+        Ok(mtime) => cache::Key {
+            path,
+            mtime,
+            format: Format::Html,
+            prefs: Prefs::default(),
+        },
+        Err(e) => {
+            eprintln!("export: {section}/{name}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    match rt.block_on(render::collect(key, None)) {
+        Ok(doc) => {
+            let url = url_path(&[section, &format!("{name}.html")]);
+            print!(
+                "{}",
+                standalone(&String::from_utf8_lossy(&doc), site.as_deref(), &url)
+            );
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("export: {section}/{name}: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn usage() -> ExitCode {
+    eprintln!("usage: handoc export [--host NAME] [--url URL] SECTION/NAME");
+    ExitCode::from(2)
+}
@@ -0,0 +1,142 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! `/feed.atom`: pages whose sources changed lately, newest first,
+//! e.g. after package upgrades.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::http::{header, HeaderMap};
+use axum::response::{IntoResponse, Response};
+
+use crate::config::config;
+use crate::source::source;
+use crate::{bg, check_so, escape_html, lookup, meta, origin, url_path, vhost, with_base};
+
+struct Entry {
+    section: String,
+    /// Page file without `.gz`.
+    file: String,
+    mtime: SystemTime,
+    description: Option<String>,
+}
+
+pub async fn handler(headers: HeaderMap) -> Response {
+    let origin = origin(&headers);
+    let feed = escape_html(&format!("{origin}{}", with_base("/feed.atom")));
+    let entries = bg(recent).await;
+    let updated = entries.first().map_or(UNIX_EPOCH, |e| e.mtime);
+    let mut body = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+<title>Recently updated man pages</title>
+<id>{feed}</id>
+<link rel="self" href="{feed}"/>
+<updated>{}</updated>
+<author><name>handoc</name></author>
+"#,
+        rfc3339(updated)
+    );
+    for e in &entries {
+        let (name, _) = e.file.rsplit_once('.').unwrap_or((&e.file, ""));
+        let url = escape_html(&format!(
+            "{origin}{}",
+            url_path(&[&e.section, &format!("{}.html", e.file)])
+        ));
+        let updated = rfc3339(e.mtime);
+        body += &format!(
+            "<entry>\n<title>{}({})</title>\n<id>{url}#{updated}</id>\n<link href=\"{url}\"/>\n<updated>{updated}</updated>\n",
+            escape_html(name),
+            escape_html(&e.section),
+        );
+        if let Some(desc) = &e.description {
+            body += &format!("<summary>{}</summary>\n", escape_html(desc));
+        }
+        body += "</entry>\n";
+    }
+    body += "</feed>\n";
+    (
+        [(header::CONTENT_TYPE, "application/atom+xml; charset=utf-8")],
+        body,
+    )
+        .into_response()
+}
+
+/// Pages of all roots modified within the configured window, newest
+/// first, leaving out `.so` redirects.  Blocks.
+fn recent() -> Vec<Entry> {
+    let feed = &config().feed;
+    let since = SystemTime::now() - Duration::from_secs(feed.days * 86400);
+    let mut found = Vec::new();
+    for root in vhost::roots() {
+        for dir in lookup::sections(root) {
+            let dir = root.join(format!("man{dir}"));
+            let Ok(names) = source().list(&dir) else {
+                continue;
+            };
+            for name in names {
+                let Some(file) = name.to_str().and_then(|n| n.strip_suffix(".gz")) else {
+                    continue;
+                };
+                let Some((_, sec)) = file
+                    .rsplit_once('.')
+                    .filter(|(_, s)| lookup::valid_section(s))
+                else {
+                    continue;
+                };
+                let path = dir.join(&name);
+                let Ok(mtime) = source().metadata(&path).map(|m| m.modified) else {
+                    continue;
+                };
+                if mtime >= since {
+                    found.push((mtime, sec.to_owned(), file.to_owned(), path));
+                }
+            }
+        }
+    }
+    found.sort_unstable_by(|a, b| b.cmp(a));
+    found
+        .into_iter()
+        .filter(|(_, _, _, path)| matches!(check_so(path), Ok(None)))
+        .take(feed.entries)
+        .map(|(mtime, section, file, path)| Entry {
+            description: meta::read(&path).ok().and_then(|m| m.description),
+            section,
+            file,
+            mtime,
+        })
+        .collect()
+}
+
+/// Year, month, day and seconds into the day of `t`, in UTC.
+pub fn civil(t: SystemTime) -> (i64, i64, i64, u64) {
+    let secs = t.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // civil from days, after Howard Hinnant
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day, rem)
+}
+
+/// `t` as an RFC 3339 timestamp in UTC.
+fn rfc3339(t: SystemTime) -> String {
+    let (year, month, day, rem) = civil(t);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
@@ -0,0 +1,265 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Load protection: self-monitoring of open fds and RSS, read from
+//! procfs, where unavailable the checks pass; a limit on concurrent
+//! renders and on requests queueing for them; and a deadline for
+//! every request.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use crate::config::config;
+use crate::error::Error;
+
+pub struct Overloaded;
+
+/// One of the `render.concurrency` render slots, released on drop.
+pub struct Slot {
+    _lock: Option<File>,
+}
+
+/// Take a render slot, waiting up to `render.queue_timeout` seconds
+/// in one of the `render.max_queue` places in the queue.
+///
+/// With one process per connection an in-process semaphore would
+/// limit nothing, so slots and places are sets of lock files shared
+/// by every handoc process.
+pub async fn slot() -> Result<Slot, Overloaded> {
+    let render = &config().render;
+    let Some(dir) = slot_dir() else {
+        return Ok(Slot { _lock: None });
+    };
+    let deadline = Instant::now() + Duration::from_secs(render.queue_timeout);
+    let mut place = None;
+    loop {
+        if let Some(f) = lock_one(dir, "slot", render.concurrency) {
+            return Ok(Slot { _lock: Some(f) });
+        }
+        if place.is_none() && render.max_queue > 0 {
+            place = lock_one(dir, "queue", render.max_queue);
+            if place.is_none() {
+                eprintln!("render queue full, shedding request");
+                return Err(Overloaded);
+            }
+        }
+        if Instant::now() >= deadline {
+            eprintln!("no render slot in time, shedding request");
+            return Err(Overloaded);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// The first of lock files `<name>.0` to `<name>.<n-1>` free, locked.
+pub fn lock_one(dir: &Path, name: &str, n: usize) -> Option<File> {
+    (0..n).find_map(|i| {
+        let f = File::create(dir.join(format!("{name}.{i}"))).ok()?;
+        f.try_lock().is_ok().then_some(f)
+    })
+}
+
+/// Answer 503 to requests taking longer than `request_timeout` to
+/// start their response; rendering bodies have `render.timeout`.
+pub async fn deadline(req: Request, next: Next) -> Response {
+    let limit = config().request_timeout;
+    if limit == 0 {
+        return next.run(req).await;
+    }
+    let path = req.uri().path().to_owned();
+    match tokio::time::timeout(Duration::from_secs(limit), next.run(req)).await {
+        Ok(res) => res,
+        Err(_) => {
+            eprintln!("{path}: no response within {limit}s");
+            Error::Overloaded.into_response()
+        }
+    }
+}
+
+/// Directory of the slot lock files, or none when unlimited.
+fn slot_dir() -> Option<&'static PathBuf> {
+    if config().render.concurrency == 0 {
+        return None;
+    }
+    runtime_dir()
+}
+
+/// Where state shared between handoc processes lives: `lock_dir`,
+/// `$RUNTIME_DIRECTORY`, else `handoc` in the temp directory, made
+/// 0700; none if another user owns it or may write in it.
+pub fn runtime_dir() -> Option<&'static PathBuf> {
+    static DIR: OnceLock<Option<PathBuf>> = OnceLock::new();
+    DIR.get_or_init(|| {
+        let dir = config().render.lock_dir.clone().unwrap_or_else(|| {
+            std::env::var_os("RUNTIME_DIRECTORY")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| std::env::temp_dir().join("handoc"))
+        });
+        let made = std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            .and_then(|()| own(&dir));
+        match made {
+            Ok(()) => Some(dir),
+            Err(e) => {
+                eprintln!("cannot use {}: {e}", dir.display());
+                None
+            }
+        }
+    })
+    .as_ref()
+}
+
+/// Refuse a directory another user could have made, or could write
+/// in, as anyone can `/tmp/handoc`.
+fn own(dir: &Path) -> io::Result<()> {
+    let meta = std::fs::symlink_metadata(dir)?;
+    if !meta.is_dir() {
+        return Err(io::Error::other("not a directory"));
+    }
+    if meta.uid() != unsafe { libc::geteuid() } {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "owned by another user",
+        ));
+    }
+    if meta.mode() & 0o022 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "writable by other users",
+        ));
+    }
+    Ok(())
+}
+
+pub fn check() -> Result<(), Overloaded> {
+    let guard = &config().guard;
+    if let Some(max) = guard.max_fds {
+        if let Some(n) = fd_count().filter(|&n| n > max) {
+            eprintln!("fd limit exceeded: {n} open, limit {max}; top consumers:");
+            report(fd_consumers(), guard.report_top, "");
+            return Err(Overloaded);
+        }
+    }
+    if let Some(max) = guard.max_rss {
+        if let Some(n) = rss().filter(|&n| n > max) {
+            eprintln!("RSS limit exceeded: {n} bytes, limit {max}; top consumers:");
+            report(rss_consumers(), guard.report_top, " bytes");
+            return Err(Overloaded);
+        }
+    }
+    Ok(())
+}
+
+fn report<N: Ord + std::fmt::Display>(consumers: HashMap<String, N>, top: usize, unit: &str) {
+    let mut consumers: Vec<_> = consumers.into_iter().collect();
+    consumers.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+    for (what, n) in consumers.into_iter().take(top) {
+        eprintln!("  {n}{unit}\t{what}");
+    }
+}
+
+fn fd_count() -> Option<usize> {
+    // read_dir holds one fd itself while iterating
+    Some(
+        std::fs::read_dir("/proc/self/fd")
+            .ok()?
+            .count()
+            .saturating_sub(1),
+    )
+}
+
+fn fd_consumers() -> HashMap<String, usize> {
+    let mut ret = HashMap::new();
+    let Ok(dir) = std::fs::read_dir("/proc/self/fd") else {
+        return ret;
+    };
+    for ent in dir.flatten() {
+        let Ok(target) = std::fs::read_link(ent.path()) else {
+            continue;
+        };
+        let target = target.to_string_lossy();
+        // socket:[1234], pipe:[5678], anon_inode:[eventpoll]
+        let key = match target.split_once(":[") {
+            Some((kind, _)) if !target.starts_with('/') => kind.to_owned(),
+            _ => target.into_owned(),
+        };
+        *ret.entry(key).or_default() += 1;
+    }
+    ret
+}
+
+fn rss() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|l| l.strip_prefix("VmRSS:"))
+        .and_then(|v| v.trim().strip_suffix(" kB"))
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+}
+
+fn rss_consumers() -> HashMap<String, u64> {
+    let mut ret = HashMap::new();
+    let Ok(smaps) = std::fs::read_to_string("/proc/self/smaps") else {
+        return ret;
+    };
+    let mut current = String::new();
+    for line in smaps.lines() {
+        if let Some(kb) = line.strip_prefix("Rss:") {
+            let kb: u64 = kb
+                .trim()
+                .trim_end_matches(" kB")
+                .parse()
+                .unwrap_or_default();
+            *ret.entry(current.clone()).or_default() += kb * 1024;
+        } else if line.split_whitespace().next().is_some_and(|range| {
+            range.contains('-') && range.chars().all(|c| c == '-' || c.is_ascii_hexdigit())
+        }) {
+            // address perms offset dev inode [pathname]
+            current = line
+                .split_whitespace()
+                .nth(5)
+                .unwrap_or("[anon]")
+                .to_owned();
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn shared_runtime_dirs() {
+        let dir = std::env::temp_dir().join(format!("handoc-own-{}", std::process::id()));
+        std::fs::DirBuilder::new().mode(0o755).create(&dir).unwrap();
+        assert!(own(&dir).is_ok());
+        let chmod = |mode| std::fs::set_permissions(&dir, PermissionsExt::from_mode(mode));
+        chmod(0o1777).unwrap();
+        assert!(own(&dir).is_err());
+        chmod(0o700).unwrap();
+        let link = dir.with_extension("link");
+        std::os::unix::fs::symlink(&dir, &link).unwrap();
+        assert!(own(&link).is_err());
+        std::fs::remove_file(&link).unwrap();
+        std::fs::remove_dir(&dir).unwrap();
+    }
+}
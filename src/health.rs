@@ -0,0 +1,74 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! `/healthz` for load balancers, and `/version` for telling what is
+//! deployed where.
+
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde_json::json;
+
+use crate::source::source;
+use crate::{bg, render, vhost};
+
+fn reply(status: StatusCode, body: serde_json::Value) -> Response {
+    (
+        status,
+        [
+            (header::CONTENT_TYPE, "application/json"),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        body.to_string(),
+    )
+        .into_response()
+}
+
+/// 200 if every man root of the host can be read and the renderer
+/// renders HTML, else 503, saying which failed.
+pub async fn healthz() -> Response {
+    let (roots, renderer) = bg(|| {
+        let roots: Vec<_> = vhost::roots()
+            .iter()
+            .map(|root| {
+                let ok = source().list(root).is_ok();
+                json!({ "path": root, "ok": ok })
+            })
+            .collect();
+        (roots, render::caps().format("html"))
+    })
+    .await;
+    let ok = renderer && roots.iter().all(|r| r["ok"] == true);
+    let status = if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    reply(
+        status,
+        json!({
+            "status": if ok { "ok" } else { "failing" },
+            "roots": roots,
+            "renderer": { "name": render::renderer().name(), "ok": renderer },
+        }),
+    )
+}
+
+pub async fn version() -> Response {
+    let caps = bg(render::caps).await;
+    let git = env!("HANDOC_GIT_HASH");
+    reply(
+        StatusCode::OK,
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "git": (!git.is_empty()).then_some(git),
+            "renderer": render::renderer().name(),
+            "renderer_version": (!caps.version.is_empty()).then_some(&caps.version),
+            "roots": vhost::roots(),
+        }),
+    )
+}
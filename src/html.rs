@@ -0,0 +1,699 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Post-processing of rendered HTML.  The document, the page template
+//! around the renderer's output, streams through lol_html,
+//! where each stage below gets to rewrite the elements it selects.
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use lol_html::errors::RewritingError;
+use lol_html::html_content::{ContentType, TextChunk};
+use lol_html::send::{Element, ElementContentHandlers, HtmlRewriter, Settings};
+use lol_html::{element, OutputSink, Selector};
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode};
+
+use crate::lookup::{self, Installed};
+use crate::package::Package;
+use crate::{base, escape_html, tldr, url_path, with_base, SEGMENT};
+
+/// What the stages know of the document.
+pub struct Page {
+    /// `name(section)` for man pages.
+    pub title: String,
+    pub description: Option<String>,
+    pub place: Place,
+}
+
+/// Where a document is, for the navigation header.
+pub enum Place {
+    Other,
+    /// The listing of a section.
+    Section(String),
+    Man(Box<Man>),
+}
+
+/// A man page, with the pages before and after it in its section,
+/// the installed ones of its SEE ALSO, the package providing it, and
+/// the pages referring to it.
+pub struct Man {
+    pub name: String,
+    pub section: String,
+    pub prev: Option<String>,
+    pub next: Option<String>,
+    pub see_also: Vec<(String, String)>,
+    pub package: Option<Package>,
+    pub referenced_by: Vec<(String, String)>,
+    /// Labels of the roots having the page, telling the one shown.
+    pub versions: Vec<(String, bool)>,
+    /// tldr-pages examples, as HTML.
+    pub tldr: Option<String>,
+}
+
+/// Handlers of a stage, each for the elements its selector matches.
+type Handlers = Vec<(Cow<'static, Selector>, ElementContentHandlers<'static>)>;
+
+/// The stages for a whole document about `page`, or with none, for
+/// a fragment; links are checked against `installed` if given.
+fn stages(page: Option<Page>, installed: Option<Installed>) -> Handlers {
+    let outline = Outline::default();
+    let mut ret = xref_links(installed.clone());
+    ret.extend(anchors(outline.clone()));
+    ret.extend(linkify(installed));
+    if let Some(page) = page {
+        ret.extend(nav(&page.place));
+        ret.extend(toc(outline));
+        ret.extend(referrers(&page.place));
+        ret.extend(head(page));
+    }
+    ret
+}
+
+/// A header of breadcrumbs, home, section and page, a search box,
+/// links to the neighbouring pages, those of SEE ALSO, and the
+/// package.
+fn nav(place: &Place) -> Handlers {
+    let section_link = |sec: &str| {
+        format!(
+            "<li><a href=\"{}/\">{}</a></li>",
+            url_path(&[sec]),
+            escape_html(sec)
+        )
+    };
+    let mut crumbs = format!("<li><a href=\"{}\">man</a></li>", with_base("/"));
+    let mut links = String::new();
+    let mut examples = String::new();
+    match place {
+        Place::Other => {}
+        Place::Section(sec) => crumbs += &section_link(sec),
+        Place::Man(man) => {
+            let Man {
+                name,
+                section,
+                prev,
+                next,
+                see_also,
+                package,
+                versions,
+                tldr,
+                ..
+            } = &**man;
+            if let Some(tldr) = tldr {
+                examples = tldr::details(tldr);
+            }
+            crumbs += &section_link(section);
+            crumbs += &format!("<li>{}</li>", escape_html(name));
+            for (rel, page) in [("prev", prev), ("next", next)] {
+                if let Some(page) = page {
+                    links += &format!(
+                        "<a rel=\"{rel}\" href=\"{}\">{}</a>\n",
+                        url_path(&[section, &format!("{page}.{section}.html")]),
+                        escape_html(page)
+                    );
+                }
+            }
+            if !see_also.is_empty() {
+                links += "<ul class=\"see-also\">\n";
+                for (name, sec) in see_also {
+                    links += &format!(
+                        "<li><a href=\"{}\">{}({})</a></li>\n",
+                        url_path(&[sec, &format!("{name}.{sec}.html")]),
+                        escape_html(name),
+                        escape_html(sec)
+                    );
+                }
+                links += "</ul>\n";
+            }
+            if versions.len() > 1 {
+                let page = url_path(&[section, &format!("{name}.{section}.html")]);
+                links += "<ul class=\"versions\">\n";
+                for (i, (label, current)) in versions.iter().enumerate() {
+                    let label_html = escape_html(label);
+                    links += &if *current {
+                        format!("<li aria-current=\"page\">{label_html}</li>\n")
+                    } else if i == 0 {
+                        format!("<li><a href=\"{page}\">{label_html}</a></li>\n")
+                    } else {
+                        let q = utf8_percent_encode(label, SEGMENT);
+                        format!("<li><a href=\"{page}?version={q}\">{label_html}</a></li>\n")
+                    };
+                }
+                links += "</ul>\n";
+            }
+            if let Some(package) = package {
+                links += &format!(
+                    "<p class=\"package\">Provided by <a href=\"{}\">{}</a> {}</p>\n",
+                    url_path(&["package", &package.name]),
+                    escape_html(&package.name),
+                    escape_html(&package.version)
+                );
+            }
+        }
+    }
+    let search = with_base("/search");
+    let header = format!(
+        "<nav class=\"handoc-nav\">\n<ol class=\"breadcrumbs\">{crumbs}</ol>\n\
+         <form action=\"{search}\" method=\"get\" role=\"search\">\
+         <input type=\"search\" name=\"q\" placeholder=\"Search pages\"/></form>\n\
+         {links}</nav>\n{examples}"
+    );
+    vec![element!("body", move |el| {
+        el.prepend(&header, ContentType::Html);
+        Ok(())
+    })]
+}
+
+/// Section headings seen so far: (subsection, id, heading HTML).
+type Outline = Arc<Mutex<Vec<(bool, String, String)>>>;
+
+/// A visible ¶ permalink on every section heading and option tag.
+/// Headings keep the ids the renderer gave them, or get one from
+/// their text the way mandoc makes them; option tags get `opt-` and
+/// their first long option, or else the first one, as in
+/// `#opt--archive`.  Headings are recorded in `outline`.
+fn anchors(outline: Outline) -> Handlers {
+    #[derive(Default)]
+    struct State {
+        used: HashSet<String>,
+        /// Text of the element being looked at.
+        text: String,
+    }
+    let state = Arc::new(Mutex::new(State::default()));
+    let text = |state: &Arc<Mutex<State>>| {
+        let state = state.clone();
+        move |t: &mut TextChunk| {
+            state.lock().unwrap().text.push_str(t.as_str());
+            Ok(())
+        }
+    };
+    let headings = {
+        let state = state.clone();
+        move |el: &mut Element| {
+            let given = el.get_attribute("id");
+            let sub = el.tag_name() == "h2";
+            let mut st = state.lock().unwrap();
+            st.text.clear();
+            st.used.extend(given.clone());
+            let (state, outline) = (state.clone(), outline.clone());
+            el.on_end_tag(Box::new(move |end| {
+                let mut st = state.lock().unwrap();
+                let html = std::mem::take(&mut st.text);
+                let id = match given {
+                    Some(id) => {
+                        end.before(&permalink(&id, false), ContentType::Html);
+                        id
+                    }
+                    None => {
+                        let id = unique(&mut st.used, unescape_html(html.trim()).replace(' ', "_"));
+                        end.before(&permalink(&id, true), ContentType::Html);
+                        id
+                    }
+                };
+                outline.lock().unwrap().push((sub, id, html));
+                Ok(())
+            }))
+        }
+    };
+    let tags = {
+        let state = state.clone();
+        move |el: &mut Element| {
+            state.lock().unwrap().text.clear();
+            let state = state.clone();
+            el.on_end_tag(Box::new(move |end| {
+                let mut st = state.lock().unwrap();
+                let text = unescape_html(&std::mem::take(&mut st.text));
+                if let Some(opt) = option(&text) {
+                    let id = unique(&mut st.used, format!("opt{opt}"));
+                    end.before(&permalink(&id, true), ContentType::Html);
+                }
+                Ok(())
+            }))
+        }
+    };
+    vec![
+        (
+            Cow::Owned("h1.Sh, h2.Ss".parse().unwrap()),
+            ElementContentHandlers::default()
+                .element(headings)
+                .text(text(&state)),
+        ),
+        (
+            Cow::Owned("dt".parse().unwrap()),
+            ElementContentHandlers::default()
+                .element(tags)
+                .text(text(&state)),
+        ),
+    ]
+}
+
+fn permalink(id: &str, with_id: bool) -> String {
+    let id = utf8_percent_encode(id, SEGMENT).to_string();
+    let target = if with_id {
+        format!(" id=\"{id}\"")
+    } else {
+        String::new()
+    };
+    format!("<a class=\"anchor\"{target} href=\"#{id}\">\u{b6}</a>")
+}
+
+/// `id`, or with a number appended if taken already.
+fn unique(used: &mut HashSet<String>, id: String) -> String {
+    let id = (1..)
+        .map(|n| {
+            if n == 1 {
+                id.clone()
+            } else {
+                format!("{id}_{n}")
+            }
+        })
+        .find(|id| !used.contains(id))
+        .unwrap();
+    used.insert(id.clone());
+    id
+}
+
+/// The option a tag like `-a, --archive` is about.
+fn option(text: &str) -> Option<&str> {
+    if !text.trim_start().starts_with('-') {
+        return None;
+    }
+    let opts: Vec<&str> = text
+        .split(|c: char| c.is_whitespace() || ",=[]<>|".contains(c))
+        .filter(|w| {
+            let name = w.trim_start_matches('-');
+            matches!(w.len() - name.len(), 1 | 2)
+                && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+        })
+        .collect();
+    opts.iter()
+        .find(|o| o.starts_with("--"))
+        .or(opts.first())
+        .copied()
+}
+
+/// Links for `name(section)` references and http(s) URLs in running
+/// text, which renderers only link when marked up as such.  Text
+/// within links, code, preformatted blocks other than the terminal
+/// view, and the page header and footer is left alone.
+fn linkify(installed: Option<Installed>) -> Handlers {
+    #[derive(Default)]
+    struct State {
+        /// How many of the elements left alone the text is in.
+        skip: usize,
+        /// The text node so far, when it came in pieces.
+        text: String,
+    }
+    let state = Arc::new(Mutex::new(State::default()));
+    let skipped = {
+        let state = state.clone();
+        move |el: &mut Element| {
+            if el.tag_name() == "pre" && el.get_attribute("class").as_deref() == Some("terminal") {
+                return Ok(());
+            }
+            state.lock().unwrap().skip += 1;
+            let state = state.clone();
+            el.on_end_tag(Box::new(move |_| {
+                state.lock().unwrap().skip -= 1;
+                Ok(())
+            }))
+        }
+    };
+    let text = move |t: &mut TextChunk| {
+        let mut st = state.lock().unwrap();
+        if st.skip > 0 {
+            return Ok(());
+        }
+        st.text.push_str(t.as_str());
+        if !t.last_in_text_node() {
+            t.remove();
+            return Ok(());
+        }
+        let text = std::mem::take(&mut st.text);
+        t.replace(&links(&text, installed.as_ref()), ContentType::Html);
+        Ok(())
+    };
+    vec![
+        element!("a, pre, code, table.head, table.foot", skipped),
+        (
+            Cow::Owned("body".parse().unwrap()),
+            ElementContentHandlers::default().text(text),
+        ),
+    ]
+}
+
+/// `html`, text with its entities still escaped, with references and
+/// URLs made links; only references to `installed` pages, if given,
+/// as guesses that are not are more often wrong than right.
+fn links(html: &str, installed: Option<&Installed>) -> String {
+    let mut ret = String::with_capacity(html.len());
+    let mut last = 0;
+    for (start, end, href) in targets(html) {
+        ret.push_str(&html[last..start]);
+        let missing = target(&href)
+            .zip(installed)
+            .is_some_and(|((name, sec), installed)| !installed.has(&name, sec));
+        if missing {
+            ret.push_str(&html[start..end]);
+        } else {
+            ret.push_str(&format!(
+                "<a class=\"link\" href=\"{href}\">{}</a>",
+                &html[start..end]
+            ));
+        }
+        last = end;
+    }
+    ret.push_str(&html[last..]);
+    ret
+}
+
+/// The references and URLs in `s`, found in one pass as it runs
+/// within the rewriter: where each starts and ends, and where it
+/// points.
+fn targets(s: &str) -> impl Iterator<Item = (usize, usize, String)> + '_ {
+    let mut urls = urls(s).peekable();
+    let mut refs = lookup::references(s)
+        .map(|(at, name, section)| {
+            let href = url_path(&[section, &format!("{name}.{section}.html")]);
+            (at.start, at.end, href)
+        })
+        .peekable();
+    let mut end = 0;
+    std::iter::from_fn(move || loop {
+        let next = match (urls.peek(), refs.peek()) {
+            (Some(u), Some(r)) if r.0 < u.0 => refs.next(),
+            (Some(_), _) => urls.next(),
+            (None, _) => refs.next(),
+        }?;
+        // those within the one before are part of it
+        if next.0 >= end {
+            end = next.1;
+            return Some(next);
+        }
+    })
+}
+
+/// The http(s) URLs in `s`, as for [`targets`].
+fn urls(s: &str) -> impl Iterator<Item = (usize, usize, String)> + '_ {
+    let mut from = 0;
+    s.match_indices("http").filter_map(move |(start, _)| {
+        if start < from
+            || !["https://", "http://"]
+                .iter()
+                .any(|p| s[start..].starts_with(p))
+        {
+            return None;
+        }
+        let len = s[start..]
+            .char_indices()
+            .find(|&(i, c)| {
+                c.is_whitespace()
+                    || "<>\"'".contains(c)
+                    || ["&lt;", "&gt;", "&quot;", "&#39;"]
+                        .iter()
+                        .any(|e| s[start + i..].starts_with(e))
+            })
+            .map_or(s.len() - start, |(i, _)| i);
+        from = start + len;
+        let url = s[start..from].trim_end_matches(|c: char| ".,;:!?)".contains(c));
+        (url.len() > "https://".len()).then(|| (start, start + url.len(), url.to_owned()))
+    })
+}
+
+/// A collapsible table of contents of the headings in `outline`, at
+/// the end of the body for the stylesheet to place; left out when the
+/// renderer made one itself, as mandoc does with `-O toc`.
+fn toc(outline: Outline) -> Handlers {
+    let renderer_made = Arc::new(Mutex::new(false));
+    let own = {
+        let renderer_made = renderer_made.clone();
+        move |_: &mut Element| {
+            *renderer_made.lock().unwrap() = true;
+            Ok(())
+        }
+    };
+    let body = move |el: &mut Element| {
+        let (outline, renderer_made) = (outline.clone(), renderer_made.clone());
+        el.on_end_tag(Box::new(move |end| {
+            let entries = outline.lock().unwrap();
+            if *renderer_made.lock().unwrap() || entries.len() < 2 {
+                return Ok(());
+            }
+            let mut tree: Vec<(String, Vec<String>)> = Vec::new();
+            for (sub, id, html) in entries.iter() {
+                let href = utf8_percent_encode(id, SEGMENT);
+                let link = format!("<a href=\"#{href}\">{}</a>", html.trim());
+                match tree.last_mut() {
+                    Some((_, subs)) if *sub => subs.push(link),
+                    _ => tree.push((link, Vec::new())),
+                }
+            }
+            let mut list = String::from("<ul>\n");
+            for (link, subs) in tree {
+                list += &format!("<li>{link}");
+                if !subs.is_empty() {
+                    list += "\n<ul>\n";
+                    for sub in subs {
+                        list += &format!("<li>{sub}</li>\n");
+                    }
+                    list += "</ul>\n";
+                }
+                list += "</li>\n";
+            }
+            list += "</ul>";
+            end.before(
+                &format!(
+                    "<details class=\"toc\" open>\n<summary>Contents</summary>\n{list}\n</details>\n"
+                ),
+                ContentType::Html,
+            );
+            Ok(())
+        }))
+    };
+    vec![element!("nav.toc", own), element!("body", body)]
+}
+
+/// A footer listing the pages that refer to this one.
+fn referrers(place: &Place) -> Handlers {
+    let Place::Man(man) = place else {
+        return Vec::new();
+    };
+    let referenced_by = &man.referenced_by;
+    if referenced_by.is_empty() {
+        return Vec::new();
+    }
+    let mut footer =
+        String::from("<footer class=\"referenced-by\">\n<h2>Referenced by</h2>\n<ul>\n");
+    for (name, sec) in referenced_by {
+        footer += &format!(
+            "<li><a href=\"{}\">{}({})</a></li>\n",
+            url_path(&[sec, &format!("{name}.{sec}.html")]),
+            escape_html(name),
+            escape_html(sec)
+        );
+    }
+    footer += "</ul>\n</footer>\n";
+    let body = move |el: &mut Element| {
+        let footer = footer.clone();
+        el.on_end_tag(Box::new(move |end| {
+            end.before(&footer, ContentType::Html);
+            Ok(())
+        }))
+    };
+    vec![element!("body", body)]
+}
+
+/// `<title>` and the meta tags for search results and link previews.
+fn head(page: Page) -> Handlers {
+    let title = escape_html(&page.title);
+    let mut meta = format!(
+        "<title>{title}</title>\n<meta property=\"og:title\" content=\"{title}\"/>\n\
+         <meta property=\"og:type\" content=\"article\"/>\n"
+    );
+    if let Some(desc) = page.description.as_deref().map(escape_html) {
+        meta += &format!(
+            "<meta name=\"description\" content=\"{desc}\"/>\n\
+             <meta property=\"og:description\" content=\"{desc}\"/>\n"
+        );
+    }
+    vec![element!("head", move |el| {
+        el.append(&meta, ContentType::Html);
+        Ok(())
+    })]
+}
+
+/// mandoc substitutes page names into `man=` links verbatim, only
+/// escaping them for HTML; percent-encode the path segments of those
+/// links so names like `[` or `operator+` stay intact.  Links to
+/// pages not `installed` lose their href, and get class `missing`.
+fn xref_links(installed: Option<Installed>) -> Handlers {
+    vec![element!("a.Xr[href^='/']", move |el| {
+        let Some(href) = el.get_attribute("href") else {
+            return Ok(());
+        };
+        let href = unescape_html(&href);
+        let segments: Vec<_> = href[1..].split('/').collect();
+        let href = url_path(&segments);
+        let missing = target(&href)
+            .zip(installed.as_ref())
+            .is_some_and(|((name, sec), installed)| !installed.has(&name, sec));
+        if missing {
+            el.remove_attribute("href");
+            el.set_attribute("class", "Xr missing")?;
+            el.set_attribute("title", "not installed")?;
+        } else {
+            el.set_attribute("href", &href)?;
+        }
+        Ok(())
+    })]
+}
+
+/// The page and section a `/<section>/<name>.<section>.html` link
+/// is for, with or without the base path.
+fn target(href: &str) -> Option<(String, &str)> {
+    let href = href.strip_prefix(base()).unwrap_or(href);
+    let (section, file) = href.strip_prefix('/')?.split_once('/')?;
+    let name = file
+        .strip_suffix(".html")?
+        .strip_suffix(section)?
+        .strip_suffix('.')?;
+    Some((
+        percent_decode_str(name).decode_utf8().ok()?.into_owned(),
+        section,
+    ))
+}
+
+fn unescape_html(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Rewritten output so far, shared with the rewriter.
+#[derive(Clone, Default)]
+struct Sink(Arc<Mutex<Vec<u8>>>);
+
+impl OutputSink for Sink {
+    fn handle_chunk(&mut self, chunk: &[u8]) {
+        self.0.lock().unwrap().extend_from_slice(chunk);
+    }
+}
+
+impl Sink {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut self.0.lock().unwrap())
+    }
+}
+
+pub struct Rewriter {
+    inner: HtmlRewriter<'static, Sink>,
+    out: Sink,
+}
+
+impl Rewriter {
+    pub fn new(page: Option<Page>, installed: Option<Installed>) -> Self {
+        let out = Sink::default();
+        let settings = stages(page, installed).into_iter().fold(
+            Settings::new_send(),
+            Settings::append_element_content_handler,
+        );
+        Self {
+            inner: HtmlRewriter::new(settings, out.clone()),
+            out,
+        }
+    }
+
+    /// Feed in `chunk`, returning what is ready of the output.
+    pub fn write(&mut self, chunk: &[u8]) -> Result<Vec<u8>, RewritingError> {
+        self.inner.write(chunk)?;
+        Ok(self.out.take())
+    }
+
+    /// The rest of the output.
+    pub fn end(self) -> Result<Vec<u8>, RewritingError> {
+        self.inner.end()?;
+        Ok(self.out.take())
+    }
+}
+
+/// Pass a whole document about `page` through the stages.
+pub fn rewrite(doc: &str, page: Page) -> String {
+    let mut rewriter = Rewriter::new(Some(page), None);
+    let ret = rewriter
+        .write(doc.as_bytes())
+        .and_then(|mut out| {
+            out.extend(rewriter.end()?);
+            Ok(out)
+        })
+        .unwrap_or_else(|e| {
+            eprintln!("cannot rewrite page: {e}");
+            doc.as_bytes().to_owned()
+        });
+    String::from_utf8_lossy(&ret).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn found(s: &str) -> Vec<(&str, String)> {
+        targets(s)
+            .map(|(start, end, href)| (&s[start..end], href))
+            .collect()
+    }
+
+    #[test]
+    fn reference_after_non_ascii() {
+        crate::testing::setup();
+        let s = "see systemd\u{2010}journald(8) and «ls(1)»";
+        assert_eq!(
+            found(s),
+            [
+                ("journald(8)", "/8/journald.8.html".into()),
+                ("ls(1)", "/1/ls.1.html".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn urls_and_references() {
+        crate::testing::setup();
+        let s = "at https://example.com/x. or tar(1), not https://a/b(1)";
+        assert_eq!(
+            found(s),
+            [
+                ("https://example.com/x", "https://example.com/x".into()),
+                ("tar(1)", "/1/tar.1.html".into()),
+                ("https://a/b(1", "https://a/b(1".into())
+            ]
+        );
+        assert!(found("foo(bar) and (1)").is_empty());
+    }
+
+    #[test]
+    fn hostile_text() {
+        crate::testing::setup();
+        let n = 1 << 20;
+        for s in [
+            "(".repeat(n),
+            "a(".repeat(n / 2),
+            format!("{}1)", "(".repeat(n)),
+            "https://".repeat(n / 8),
+            "http:// x(".repeat(n / 10),
+        ] {
+            assert!(links(&s, None).len() >= s.len());
+        }
+    }
+}
@@ -0,0 +1,154 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Per-root index of page names to the sections they exist in, built
+//! from directory listings on first lookup.  Each connection has its
+//! own process, so it is kept on disk, in `cache.dir` or else the
+//! runtime directory, for as long as the directory mtimes it was
+//! built from hold; in a process, they are checked again every
+//! `index_refresh` seconds.
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::config;
+use crate::source::source;
+use crate::{guard, lookup, vhost};
+
+#[derive(Serialize, Deserialize)]
+pub struct Index {
+    /// Page name to its sections, best first.
+    pub pages: BTreeMap<String, Vec<String>>,
+    /// The man<sec> directories and the root itself, with their mtimes.
+    dirs: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl Index {
+    /// Whether none of the directories changed since.
+    fn fresh(&self) -> bool {
+        self.dirs.iter().all(|(d, t)| mtime(d) == *t)
+    }
+
+    /// Changes whenever the index is built from changed directories.
+    pub fn stamp(&self) -> u64 {
+        let mut h = std::hash::DefaultHasher::new();
+        self.dirs.hash(&mut h);
+        h.finish()
+    }
+}
+
+/// Indexes by root, with when they were last checked.
+type Indexes = HashMap<PathBuf, (Instant, Arc<Index>)>;
+
+static INDEXES: LazyLock<Mutex<Indexes>> = LazyLock::new(Default::default);
+
+/// The index of `root`, built or refreshed as needed.  May block.
+pub fn get(root: &Path) -> Arc<Index> {
+    let mut indexes = INDEXES.lock().unwrap();
+    let refresh = Duration::from_secs(config().index_refresh);
+    if let Some((checked, index)) = indexes.get_mut(root) {
+        if checked.elapsed() < refresh {
+            return index.clone();
+        }
+        if index.fresh() {
+            *checked = Instant::now();
+            return index.clone();
+        }
+    }
+    let index = Arc::new(load(root).filter(Index::fresh).unwrap_or_else(|| {
+        let index = build(root);
+        if let Err(e) = store(root, &index) {
+            eprintln!("cannot store index of {}: {e}", root.display());
+        }
+        index
+    }));
+    indexes.insert(root.to_owned(), (Instant::now(), index.clone()));
+    index
+}
+
+/// Build the indexes of all configured roots ahead of the first
+/// lookup.
+pub fn prebuild() {
+    for root in vhost::all_roots() {
+        get(root);
+    }
+}
+
+fn disk_path(root: &Path) -> Option<PathBuf> {
+    let dir = config().cache.dir.as_ref().or(guard::runtime_dir())?;
+    let mut h = std::hash::DefaultHasher::new();
+    root.hash(&mut h);
+    Some(dir.join(format!("index-{:016x}.json", h.finish())))
+}
+
+fn load(root: &Path) -> Option<Index> {
+    serde_json::from_slice(&std::fs::read(disk_path(root)?).ok()?).ok()
+}
+
+fn store(root: &Path, index: &Index) -> std::io::Result<()> {
+    let Some(path) = disk_path(root) else {
+        return Ok(());
+    };
+    // write aside and rename, as other processes may be reading
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    std::fs::write(&tmp, serde_json::to_vec(index)?)?;
+    std::fs::rename(tmp, path)
+}
+
+fn mtime(p: &Path) -> Option<SystemTime> {
+    source().metadata(p).map(|m| m.modified).ok()
+}
+
+fn build(root: &Path) -> Index {
+    let sections = lookup::sections(root);
+    let mut dirs = vec![(root.to_owned(), mtime(root))];
+    // (name) -> [(extended, dir rank, section)]
+    let mut found: BTreeMap<String, Vec<(bool, usize, String)>> = BTreeMap::new();
+    for (rank, dsec) in sections.iter().enumerate() {
+        let dir = root.join(format!("man{dsec}"));
+        dirs.push((dir.clone(), mtime(&dir)));
+        let Ok(files) = source().list(&dir) else {
+            continue;
+        };
+        for file in files {
+            let Some((name, sec)) = file
+                .to_str()
+                .and_then(|f| f.strip_suffix(".gz"))
+                .and_then(|f| f.rsplit_once('.'))
+            else {
+                continue;
+            };
+            if name.is_empty() || !sec.starts_with(dsec.as_str()) || !lookup::valid_section(sec) {
+                continue;
+            }
+            found
+                .entry(name.to_owned())
+                .or_default()
+                .push((sec != dsec, rank, sec.to_owned()));
+        }
+    }
+    let pages = found
+        .into_iter()
+        .map(|(name, mut secs)| {
+            secs.sort_unstable();
+            let mut ret: Vec<String> = Vec::with_capacity(secs.len());
+            for (_, _, sec) in secs {
+                if !ret.contains(&sec) {
+                    ret.push(sec);
+                }
+            }
+            (name, ret)
+        })
+        .collect();
+    Index { pages, dirs }
+}
@@ -0,0 +1,248 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! GNU info manuals at `/info/<manual>/<node>`, read from the
+//! configured directories and shown as the preformatted text they
+//! are, with menu entries, cross references and the pointers to the
+//! neighbouring nodes made links.  `/info/` lists the manuals.
+
+use std::io::Read;
+use std::path::Path;
+
+use axum::extract::Path as UrlPath;
+use axum::response::{IntoResponse, Redirect, Response};
+
+use crate::config::config;
+use crate::error::Error;
+use crate::prefs::Prefs;
+use crate::{bg, browse, escape_html, html, lookup, url_path};
+
+struct Node {
+    name: String,
+    /// Next, Prev and Up, as they appear in the header.
+    pointers: Vec<(String, String)>,
+    body: String,
+}
+
+/// An info file, compressed or not.
+fn open(dir: &Path, file: &str) -> Option<String> {
+    if let Ok(src) = std::fs::read_to_string(dir.join(file)) {
+        return Some(src);
+    }
+    let gz = std::fs::File::open(dir.join(format!("{file}.gz"))).ok()?;
+    let mut src = String::new();
+    flate2::read::GzDecoder::new(gz)
+        .read_to_string(&mut src)
+        .ok()?;
+    Some(src)
+}
+
+/// The whole of `manual`, along with the parts its indirect table
+/// names.  Blocks.
+fn read(manual: &str) -> Option<String> {
+    if !lookup::valid_component(manual) {
+        return None;
+    }
+    config().info.dirs.iter().find_map(|dir| {
+        let mut src = open(dir, &format!("{manual}.info")).or_else(|| open(dir, manual))?;
+        let indirect = src
+            .split('\x1f')
+            .find_map(|chunk| chunk.trim_start().strip_prefix("Indirect:"))
+            .map(|table| {
+                table
+                    .lines()
+                    .filter_map(|l| Some(l.rsplit_once(": ")?.0.to_owned()))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        for part in indirect.iter().filter(|p| lookup::valid_component(p)) {
+            src += "\x1f";
+            src += &open(dir, part)?;
+        }
+        Some(src)
+    })
+}
+
+fn nodes(src: &str) -> impl Iterator<Item = Node> + '_ {
+    src.split('\x1f').filter_map(|chunk| {
+        let chunk = chunk.trim_start_matches('\n');
+        let (header, body) = chunk.split_once('\n').unwrap_or((chunk, ""));
+        if !header.starts_with("File:") {
+            return None;
+        }
+        let fields: Vec<_> = header
+            .split([',', '\t'])
+            .filter_map(|f| f.trim().split_once(": "))
+            .map(|(k, v)| (k.to_owned(), v.trim().to_owned()))
+            .collect();
+        let name = fields.iter().find(|(k, _)| k == "Node")?.1.clone();
+        let pointers = ["Prev", "Up", "Next"]
+            .iter()
+            .filter_map(|p| fields.iter().find(|(k, _)| k == p).cloned())
+            .collect();
+        Some(Node {
+            name,
+            pointers,
+            body: body.to_owned(),
+        })
+    })
+}
+
+/// Manuals in the configured directories.
+fn manuals() -> Vec<String> {
+    let mut ret: Vec<_> = config()
+        .info
+        .dirs
+        .iter()
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flatten()
+        .flatten()
+        .filter_map(|ent| {
+            let file = ent.file_name().into_string().ok()?;
+            let file = file.strip_suffix(".gz").unwrap_or(&file);
+            file.strip_suffix(".info").map(str::to_owned)
+        })
+        .collect();
+    ret.sort_unstable();
+    ret.dedup();
+    ret
+}
+
+pub async fn top(prefs: Prefs) -> Response {
+    let manuals = bg(manuals).await;
+    if manuals.is_empty() {
+        return Error::NotFound.into_response();
+    }
+    let mut body = String::from("<h1>Info manuals</h1>\n<ul class=\"pages\">\n");
+    for manual in &manuals {
+        body += &format!(
+            "<li><a href=\"{}\">{}</a></li>\n",
+            url_path(&["info", manual, "Top"]),
+            escape_html(manual)
+        );
+    }
+    body += "</ul>";
+    browse::document(&prefs, "Info manuals", html::Place::Other, &body).into_response()
+}
+
+pub async fn manual(UrlPath(manual): UrlPath<String>) -> Redirect {
+    Redirect::temporary(&url_path(&["info", &manual, "Top"]))
+}
+
+pub async fn node(
+    UrlPath((manual, node)): UrlPath<(String, String)>,
+    prefs: Prefs,
+) -> Result<Response, Error> {
+    let found = bg({
+        let (manual, name) = (manual.clone(), node);
+        move || {
+            let src = read(&manual)?;
+            let mut nodes: Vec<_> = nodes(&src).collect();
+            let i = nodes.iter().position(|n| n.name == name).or_else(|| {
+                nodes
+                    .iter()
+                    .position(|n| n.name.eq_ignore_ascii_case(&name))
+            })?;
+            Some(nodes.swap_remove(i))
+        }
+    })
+    .await
+    .ok_or(Error::NotFound)?;
+    let mut body = String::from("<nav class=\"info\">");
+    for (rel, target) in &found.pointers {
+        body += &format!(
+            "<a rel=\"{}\" href=\"{}\">{rel}: {}</a>\n",
+            rel.to_lowercase(),
+            href(&manual, target),
+            escape_html(target)
+        );
+    }
+    body += "</nav>\n<pre class=\"info\">";
+    body += &linkify(&found.body, &manual);
+    body += "</pre>";
+    let title = format!("{} ({manual})", found.name);
+    Ok(browse::document(&prefs, &title, html::Place::Other, &body).into_response())
+}
+
+/// URL of info reference `target`, `(manual)node` or a node of
+/// `manual`.
+fn href(manual: &str, target: &str) -> String {
+    let (manual, node) = match target.strip_prefix('(').and_then(|t| t.split_once(')')) {
+        Some((other, node)) => (other.strip_suffix(".info").unwrap_or(other), node.trim()),
+        None => (manual, target),
+    };
+    url_path(&["info", manual, if node.is_empty() { "Top" } else { node }])
+}
+
+/// `body` made HTML, with links for `* Menu:` entries and `*Note`
+/// references, which take one of the forms `Node::` and `Label:
+/// (manual)Node.`; those of notes may span lines.
+fn linkify(body: &str, manual: &str) -> String {
+    let mut out = String::new();
+    let mut done = 0;
+    // the star of `* Menu:`
+    let menu = body.find("\n* Menu:").map_or(body.len(), |i| i + 1);
+    for (i, _) in body.match_indices('*') {
+        if i < done {
+            continue;
+        }
+        let rest = &body[i..];
+        let note = rest
+            .strip_prefix("*Note")
+            .or_else(|| rest.strip_prefix("*note"))
+            .filter(|r| r.starts_with(char::is_whitespace));
+        let (start, in_menu) = if let Some(r) = note {
+            (body.len() - r.trim_start().len(), false)
+        } else if i > menu && body[..i].ends_with('\n') && rest.starts_with("* ") {
+            (i + 2, true)
+        } else {
+            continue;
+        };
+        let Some((end, target)) = reference(&body[start..], in_menu) else {
+            continue;
+        };
+        out += &escape_html(&body[done..start]);
+        out += &format!(
+            "<a href=\"{}\">{}</a>",
+            href(manual, &target),
+            escape_html(&body[start..start + end])
+        );
+        done = start + end;
+    }
+    out + &escape_html(&body[done..])
+}
+
+/// Length of the reference `s` starts with, and its target.
+fn reference(s: &str, in_menu: bool) -> Option<(usize, String)> {
+    let colon = s.find(':')?;
+    let label = &s[..colon];
+    if label.trim().is_empty() || label.len() > 200 || (in_menu && label.contains('\n')) {
+        return None;
+    }
+    let normal = |s: &str| s.split_whitespace().collect::<Vec<_>>().join(" ");
+    if s[colon + 1..].starts_with(':') {
+        return Some((colon + 2, normal(label)));
+    }
+    let after = &s[colon + 1..];
+    let start = colon + 1 + (after.len() - after.trim_start_matches([' ', '\t', '\n']).len());
+    if in_menu && s[colon + 1..start].contains('\n') {
+        return None;
+    }
+    let node = match s[start..].strip_prefix('(') {
+        Some(r) => start + 2 + r.find(')')?,
+        None => start,
+    };
+    let stop: &[char] = if in_menu {
+        &['.', ',', '\t', '\n']
+    } else {
+        &['.', ',']
+    };
+    let end = node + s[node..].find(stop)?;
+    let target = normal(&s[start..end]);
+    (!target.is_empty() && target.len() <= 200).then_some((end, target))
+}
@@ -0,0 +1,1094 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! handoc serves man pages as HTML.  The handoc binary serves one
+//! socket-activated connection per process; [`Server`] also gives
+//! the routes alone, to nest in another axum app or call directly.
+
+use std::convert::Infallible;
+use std::io::BufRead;
+use std::path::{Path as StdPath, PathBuf};
+use std::process::ExitCode;
+use std::time::SystemTime;
+use std::{mem::ManuallyDrop, os::fd::FromRawFd};
+
+use async_trait::async_trait;
+use axum::body::{Body, Bytes};
+use axum::extract::FromRequestParts;
+use axum::http::{header, request::Parts, HeaderMap, Method, Uri};
+use axum::response::{IntoResponseParts, Redirect, Response, ResponseParts};
+use axum::{extract::Path, http::StatusCode, response::IntoResponse, Router};
+use httpdate::HttpDate;
+use hyper_util::rt::{TokioIo, TokioTimer};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::Deserialize;
+
+mod access;
+mod alias;
+mod api;
+mod archive;
+mod assets;
+mod auth;
+mod browse;
+mod cache;
+mod compat;
+pub mod config;
+mod conn;
+mod diff;
+mod doc;
+mod encoding;
+mod error;
+mod export;
+mod feed;
+mod guard;
+mod health;
+mod html;
+mod index;
+mod info;
+mod lint;
+mod lookup;
+mod markdown;
+mod meta;
+mod opensearch;
+mod package;
+mod pod;
+mod popular;
+mod prefs;
+mod preview;
+mod privilege;
+mod ratelimit;
+mod refs;
+mod render;
+#[cfg(feature = "builtin-renderer")]
+mod roff;
+mod sandbox;
+mod scope;
+mod secure;
+mod source;
+mod stats;
+mod template;
+#[cfg(test)]
+mod testing;
+mod tldr;
+mod trace;
+mod vhost;
+mod warm;
+
+use config::ConnectionMode;
+pub use config::{Config, ConfigError, RendererKind};
+use error::{Context, Error};
+use prefs::Prefs;
+use render::RenderError;
+use source::source;
+pub use source::{Local, ManSource, Metadata};
+
+/// Builder for the service, over the configuration it starts from.
+/// What is configured is shared by the whole process, the way the
+/// binary runs one server each: the first server put to use fixes
+/// it.
+pub struct Server {
+    config: Config,
+    source: Option<Box<dyn ManSource>>,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Server {
+    /// Everything at its defaults.
+    pub fn new() -> Self {
+        Self::with_config(Config::default())
+    }
+
+    /// As configured by `$HANDOC_CONFIG` or `/etc/handoc.toml`.
+    pub fn from_config_file() -> Result<Self, ConfigError> {
+        config::load().map(Self::with_config)
+    }
+
+    pub fn with_config(config: Config) -> Self {
+        Self {
+            config,
+            source: None,
+        }
+    }
+
+    /// Man trees to serve, earlier ones taking precedence.
+    pub fn roots<P: Into<PathBuf>>(mut self, roots: impl IntoIterator<Item = P>) -> Self {
+        self.config.roots = roots.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn renderer(mut self, kind: RendererKind) -> Self {
+        self.config.render.renderer = kind;
+        self
+    }
+
+    /// Where the roots are read from, instead of the local
+    /// filesystem.
+    pub fn source(mut self, source: impl ManSource + 'static) -> Self {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    /// Directory for the disk cache of rendered pages.
+    pub fn cache(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.cache.dir = Some(dir.into());
+        self
+    }
+
+    /// Directory of templates replacing the built-in ones.
+    pub fn template(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.templates = Some(dir.into());
+        self
+    }
+
+    /// Put the config and source to use for the process; the config
+    /// may not differ from one in use already.
+    fn install(self) -> Result<(), ConfigError> {
+        config::install(self.config)?;
+        if let Some(s) = self.source {
+            source::install(s);
+        }
+        Ok(())
+    }
+
+    /// [`Server::install`] for commands, saying why it failed.
+    fn installed(self) -> bool {
+        self.install()
+            .map_err(|e| eprintln!("cannot use config: {e}"))
+            .is_ok()
+    }
+
+    /// Every route handoc serves, with its middleware, but without
+    /// the per-connection handling of [`Server::serve_socket`].
+    pub fn router(self) -> Result<Router, ConfigError> {
+        self.install()?;
+        Ok(routes())
+    }
+
+    /// Serve the established connection on fd 0, as under systemd
+    /// socket activation, to its end; or with `connections.mode =
+    /// "listen"`, accept connections on fd 0, each served so by a
+    /// process of its own.
+    pub fn serve_socket(self) -> ExitCode {
+        if !self.installed() {
+            return ExitCode::FAILURE;
+        }
+        let limits = &config::config().connections;
+        if !limits.keep_alive || limits.max_requests == 1 {
+            // nothing in memory would outlive the one request
+            cache::disk_only();
+        }
+        // the socket at fd 0 is bound already, so nothing after needs
+        // root, not even accepting connections in listen mode
+        if let Err(e) = privilege::lower() {
+            eprintln!("cannot drop privileges: {e}");
+            return ExitCode::FAILURE;
+        }
+        if limits.mode == ConnectionMode::Listen {
+            if let Err(e) = conn::fork_each() {
+                eprintln!("cannot accept connections: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+        let sock = ManuallyDrop::new(unsafe { std::net::TcpStream::from_raw_fd(0) });
+        let (Ok(_sa), Ok(peer)) = (sock.local_addr(), sock.peer_addr()) else {
+            return ExitCode::SUCCESS;
+        };
+        render::renderer();
+        let Some(_place) = conn::admit(&sock) else {
+            return ExitCode::SUCCESS;
+        };
+        if !sandbox::confine() {
+            return ExitCode::FAILURE;
+        }
+        sock.set_nonblocking(true).unwrap();
+        let mut runtime = match config::config().runtime.worker_threads {
+            0 => tokio::runtime::Builder::new_current_thread(),
+            n => {
+                let mut b = tokio::runtime::Builder::new_multi_thread();
+                b.worker_threads(n);
+                b
+            }
+        };
+        runtime.enable_all().build().unwrap().block_on(async move {
+            let tokiosock =
+                tokio::net::TcpStream::from_std(ManuallyDrop::into_inner(sock)).unwrap();
+            let io = TokioIo::new(conn::Watched(tokiosock));
+            let hs = hyper_util::service::TowerToHyperService::new(
+                access::wrap(
+                    routes()
+                        .layer(axum::middleware::from_fn(trace::layer))
+                        .layer(axum::middleware::from_fn(conn::layer)),
+                    peer,
+                )
+                .into_service(),
+            );
+            let export = tokio::spawn(stats::export_loop());
+            let popular = tokio::spawn(popular::flush_loop());
+            tokio::task::spawn_blocking(render::caps);
+            let serve = hyper::server::conn::http1::Builder::new()
+                .timer(TokioTimer::new())
+                .header_read_timeout(None)
+                .keep_alive(limits.keep_alive)
+                .half_close(limits.half_close)
+                .serve_connection(io, hs);
+            tokio::select! {
+                _ = serve => {}
+                _ = conn::expired() => {}
+            }
+            export.abort();
+            popular.abort();
+            stats::flush().await;
+            popular::flush().await;
+            trace::flush().await;
+            bg(archive::finish).await;
+            bg(refs::finish).await;
+        });
+        ExitCode::SUCCESS
+    }
+
+    /// `handoc warm`: fill the disk cache.
+    pub fn warm(self, args: &[String]) -> ExitCode {
+        if !self.installed() {
+            return ExitCode::FAILURE;
+        }
+        warm::run(args)
+    }
+
+    /// `handoc export`: write a page as a standalone document.
+    pub fn export(self, args: &[String]) -> ExitCode {
+        if !self.installed() {
+            return ExitCode::FAILURE;
+        }
+        let code = export::run(args);
+        archive::finish();
+        refs::finish();
+        code
+    }
+
+    /// `handoc preview`: serve a page being written, reloading it as
+    /// it changes.
+    pub fn preview(self, args: &[String]) -> ExitCode {
+        if !self.installed() {
+            return ExitCode::FAILURE;
+        }
+        preview::run(args)
+    }
+}
+
+fn routes() -> Router {
+    use axum::routing::*;
+    let app = Router::new()
+        .route("/style.css", get(assets::style))
+        .route("/favicon.ico", get(assets::favicon))
+        .nest("/static", assets::routes())
+        .route("/prefs", get(prefs::handler))
+        .route("/status", get(render::status))
+        .route("/healthz", get(health::healthz))
+        .route("/version", get(health::version))
+        .route("/robots.txt", get(robots))
+        .route("/feed.atom", get(feed::handler))
+        .route("/popular", get(popular::page))
+        .route("/opensearch.xml", get(opensearch::description))
+        .route("/diff", get(diff::diff))
+        .route("/preview", post(preview::preview))
+        .route("/info/", get(info::top))
+        .route("/info/:manual", get(info::manual))
+        .route("/info/:manual/:node", get(info::node))
+        .route("/pod/:module", get(pod::pod))
+        .route("/tldr/:name", get(tldr::tldr))
+        .route("/doc/", get(doc::root))
+        .route("/doc/*path", get(doc::path))
+        .route("/suggest", get(opensearch::suggest))
+        .route("/man.cgi", get(compat::man_cgi))
+        .route("/cgi-bin/man.cgi", get(compat::man_cgi))
+        .nest("/api/v1", api::routes())
+        .route("/", get(browse::home))
+        .route("/search", get(browse::search))
+        .route("/package/:name", get(browse::package))
+        .route("/:section/", get(browse::section))
+        .route("/:section/:name", get(render))
+        .route("/:suite/:package/:page", get(compat::debian))
+        .route("/:name", get(find));
+    let app = if base().is_empty() {
+        app
+    } else {
+        // nesting takes /base for the home page, but not /base/
+        let home = format!("{}/", base());
+        Router::new()
+            .route(&home, get(browse::home))
+            .nest(base(), app)
+    };
+    app.layer(axum::middleware::from_fn(alias::layer))
+        .layer(axum::middleware::from_fn(vhost::layer))
+        .layer(axum::middleware::from_fn(guard::deadline))
+        .layer(axum::middleware::from_fn(ratelimit::layer))
+        .layer(axum::middleware::from_fn(auth::layer))
+        .layer(axum::middleware::from_fn(secure::layer))
+}
+
+async fn robots() -> Response {
+    let policy = &config::config().robots;
+    let mut body = String::from("User-agent: *\n");
+    for path in &policy.allow {
+        body += &format!("Allow: {}\n", with_base(path));
+    }
+    for path in &policy.disallow {
+        body += &format!("Disallow: {}\n", with_base(path));
+    }
+    if policy.disallow.is_empty() {
+        body += "Disallow:\n";
+    }
+    ([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], body).into_response()
+}
+
+#[derive(Deserialize)]
+struct ManPath {
+    section: String,
+    name: String,
+}
+
+/// Output formats, named by file extension in request paths.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Format {
+    Html,
+    /// Terminal rendering with the overstrikes taken out.
+    Txt,
+    Pdf,
+    Ps,
+    Md,
+    /// The HTML of the page alone, for the API; never named in paths.
+    Fragment,
+}
+
+impl Format {
+    const ALL: [Format; 5] = [
+        Format::Html,
+        Format::Txt,
+        Format::Pdf,
+        Format::Ps,
+        Format::Md,
+    ];
+
+    fn ext(self) -> &'static str {
+        match self {
+            Format::Html => "html",
+            Format::Txt => "txt",
+            Format::Pdf => "pdf",
+            Format::Ps => "ps",
+            Format::Md => "md",
+            Format::Fragment => "frag",
+        }
+    }
+
+    fn mime(self) -> &'static str {
+        match self {
+            Format::Html | Format::Fragment => "text/html; charset=utf-8",
+            Format::Txt => "text/plain; charset=utf-8",
+            Format::Pdf => "application/pdf",
+            Format::Ps => "application/postscript",
+            Format::Md => "text/markdown; charset=utf-8",
+        }
+    }
+
+    /// The renderer output it comes from, by mandoc -T name.
+    fn output(self) -> &'static str {
+        match self {
+            Format::Html | Format::Fragment => "html",
+            Format::Txt => "utf8",
+            Format::Pdf => "pdf",
+            Format::Ps => "ps",
+            Format::Md => "markdown",
+        }
+    }
+
+    /// Passed on from the renderer without post-processing.
+    fn raw(self) -> bool {
+        matches!(self, Format::Pdf | Format::Ps | Format::Md)
+    }
+
+    /// Documents rather than text, sent with a file name.
+    fn binary(self) -> bool {
+        matches!(self, Format::Pdf | Format::Ps)
+    }
+
+    /// Split a known format extension off `name`.
+    fn split(name: &str) -> Option<(&str, Format)> {
+        let (stem, ext) = name.rsplit_once('.')?;
+        Some((stem, Self::ALL.into_iter().find(|f| f.ext() == ext)?))
+    }
+}
+
+async fn find(
+    Path(name): Path<String>,
+    headers: HeaderMap,
+    prefs: Prefs,
+) -> Result<Response, Error> {
+    let (name, format) = Format::split(&name).unwrap_or((&name, negotiate(&headers)));
+    let name = name.to_owned();
+    let (name, section) = bg(move || lookup::parse(&name, prefs.lang.as_deref()))
+        .await
+        .ok_or(Error::NotFound)?;
+    let ext = format.ext();
+    Ok((
+        [(header::VARY, "Accept, User-Agent, Cookie")],
+        Redirect::temporary(&url_path(&[&section, &format!("{name}.{section}.{ext}")])),
+    )
+        .into_response())
+}
+
+/// The format for a request naming none: plain text when Accept
+/// prefers it over HTML, or when it does not say and the client is a
+/// command line tool.
+fn negotiate(headers: &HeaderMap) -> Format {
+    let accept: Vec<(&str, f32)> = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let media = parts.next().unwrap_or_default();
+            let q = parts
+                .find_map(|p| p.strip_prefix("q="))
+                .and_then(|q| q.parse().ok())
+                .unwrap_or(1.0);
+            (media, q)
+        })
+        .collect();
+    // the most specific match decides, RFC 9110 12.5.1
+    let quality = |media: &str| {
+        [media, "text/*", "*/*"]
+            .into_iter()
+            .find_map(|range| accept.iter().find(|(m, _)| *m == range))
+            .map_or(0.0, |&(_, q)| q)
+    };
+    let (html, plain) = (quality("text/html"), quality("text/plain"));
+    if plain > html {
+        return Format::Txt;
+    }
+    if html > plain {
+        return Format::Html;
+    }
+    let agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    if ["curl/", "wget/", "httpie/"]
+        .iter()
+        .any(|t| agent.starts_with(t))
+    {
+        Format::Txt
+    } else {
+        Format::Html
+    }
+}
+
+async fn render(
+    method: Method,
+    Path(ManPath { section, name }): Path<ManPath>,
+    IfChangedSince(when): IfChangedSince,
+    IfNoneMatch(tags): IfNoneMatch,
+    uri: Uri,
+    headers: HeaderMap,
+    mut prefs: Prefs,
+) -> Result<Response, Error> {
+    if let Some(name) = name.strip_suffix(".lint") {
+        return lint::page(section, name.to_owned(), prefs).await;
+    }
+    let (name, format) = Format::split(&name).ok_or(Error::NotFound)?;
+    let ext = format.ext();
+    let caps = bg(render::caps).await;
+    if !caps.format(format.output()) {
+        return Err(Error::NotFound);
+    }
+    if format != Format::Html {
+        // only the width applies, and only to text
+        prefs = Prefs {
+            lang: prefs.lang,
+            width: prefs.width.filter(|_| format == Format::Txt),
+            ..Default::default()
+        };
+    } else if prefs.terminal && !caps.format("utf8") {
+        prefs.terminal = false;
+    }
+    let fp = bg({
+        let (section, file) = (section.clone(), format!("{name}.gz"));
+        let lang = prefs.lang.clone();
+        // ?version=LABEL picks the page of that root over the others
+        let version = query_param(&uri, "version");
+        move || match version {
+            Some(v) => lookup::versions(&section, &file, lang.as_deref())
+                .into_iter()
+                .find_map(|(label, p)| (label == v).then_some(p)),
+            None => lookup::resolve(&section, &file, lang.as_deref()),
+        }
+    })
+    .await
+    .ok_or(Error::NotFound)?;
+    let meta = bg({
+        let fp = fp.clone();
+        move || source().metadata(&fp).at("stat", &fp)
+    })
+    .await?;
+    let date = meta.modified;
+    let installed = bg({
+        let (fp, lang) = (fp.clone(), prefs.lang.clone());
+        move || cache::installed(&fp, format, lang.as_deref())
+    })
+    .await;
+    let etag = ETag::new(&fp, date, meta.len, format, &prefs, installed);
+    // If-None-Match takes precedence, RFC 9110 13.2.2.
+    let fresh = match tags {
+        Some(tags) => etag.matches(&tags),
+        // on my system, mtime of manpages seems to have second resolution.
+        None => when.is_some_and(|when| when >= date),
+    };
+    if fresh {
+        stats::hit(&format!("{section}/{name}"));
+        return Ok((
+            LastModified(date),
+            etag,
+            CacheControl,
+            [(header::VARY, "Cookie")],
+            StatusCode::NOT_MODIFIED,
+        )
+            .into_response());
+    }
+    let so = bg({
+        let fp = fp.clone();
+        move || check_so(&fp).at("read", &fp)
+    })
+    .await?;
+    if let Some(so) = so {
+        let dst = if so.contains('/') {
+            let part = so.strip_prefix("man").ok_or(Error::NotFound)?;
+            url_path(&format!("{part}.{ext}").split('/').collect::<Vec<_>>())
+        } else {
+            url_path(&[&format!("{so}.{ext}")])
+        };
+        Ok((LastModified(date), CacheControl, Redirect::temporary(&dst)).into_response())
+    } else if method == Method::HEAD {
+        Ok((
+            LastModified(date),
+            etag,
+            CacheControl,
+            [(header::VARY, "Cookie")],
+            ContentType(format, name),
+            // length unknown without rendering, rather than 0
+            Body::from_stream(futures_util::stream::empty::<Result<Bytes, Infallible>>()),
+        )
+            .into_response())
+    } else {
+        let key = cache::Key {
+            path: fp,
+            mtime: date,
+            format,
+            prefs,
+        };
+        let standalone =
+            format == Format::Html && query_param(&uri, "standalone").is_some_and(|v| v != "0");
+        let (cached, key) = bg(move || (cache::get(&key), key)).await;
+        let html = match cached {
+            Some(html) => Body::from(html),
+            None => {
+                bg(guard::check).await?;
+                let slot = guard::slot().await?;
+                if standalone {
+                    render::collect(key, Some(slot)).await.map(Body::from)?
+                } else {
+                    render::stream(key, slot).await?
+                }
+            }
+        };
+        stats::hit(&format!("{section}/{name}"));
+        if standalone {
+            let doc = axum::body::to_bytes(html, usize::MAX)
+                .await
+                .map_err(|e| RenderError::Io(std::io::Error::other(e)))?;
+            let page = url_path(&[&section, &format!("{name}.html")]);
+            let doc = export::standalone(
+                &String::from_utf8_lossy(&doc),
+                Some(&origin(&headers)),
+                &page,
+            );
+            return Ok((
+                LastModified(date),
+                CacheControl,
+                [
+                    (header::VARY, "Cookie".to_owned()),
+                    (
+                        header::CONTENT_DISPOSITION,
+                        format!("attachment; filename=\"{}.html\"", name.replace('"', "_")),
+                    ),
+                ],
+                ContentType(format, name),
+                doc,
+            )
+                .into_response());
+        }
+        Ok((
+            LastModified(date),
+            etag,
+            CacheControl,
+            [(header::VARY, "Cookie")],
+            ContentType(format, name),
+            html,
+        )
+            .into_response())
+    }
+}
+
+/// Characters left alone in a path segment: RFC 3986 unreserved, plus
+/// `:` and `@` which are common in Perl and other library pages.
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~')
+    .remove(b':')
+    .remove(b'@');
+
+/// Build an absolute URL path from unencoded segments.
+fn url_path(segments: &[&str]) -> String {
+    segments.iter().fold(base().to_owned(), |path, s| {
+        path + "/" + &utf8_percent_encode(s, SEGMENT).to_string()
+    })
+}
+
+/// The configured base path without its trailing slash, empty for
+/// the root.
+fn base() -> &'static str {
+    config::config().base_path.trim_end_matches('/')
+}
+
+/// Absolute `path` under the base path.
+fn with_base(path: &str) -> String {
+    format!("{}{path}", base())
+}
+
+/// Where absolute URLs point: the Host the client used, and https
+/// when a proxy in front says so.
+fn origin(headers: &HeaderMap) -> String {
+    let get = |h| headers.get(h).and_then(|v| v.to_str().ok());
+    let scheme = get("x-forwarded-proto").unwrap_or("http");
+    let host = get("host").unwrap_or("localhost");
+    format!("{scheme}://{host}")
+}
+
+/// The decoded value of `key` in the query string.
+fn query_param(uri: &Uri, key: &str) -> Option<String> {
+    let (_, v) = uri
+        .query()?
+        .split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .find(|(k, _)| *k == key)?;
+    Some(
+        percent_decode_str(&v.replace('+', " "))
+            .decode_utf8_lossy()
+            .into_owned(),
+    )
+}
+
+fn check_so(p: &StdPath) -> Result<Option<String>, std::io::Error> {
+    let _span = trace::span("decompress");
+    let dec = flate2::read::GzDecoder::new(source().open(p)?);
+    let mut decr = std::io::BufReader::new(dec);
+    let mut line = Default::default();
+    decr.read_line(&mut line)?;
+    if line.ends_with('\n') {
+        line.pop();
+    }
+    if line.starts_with(".so ") {
+        line.replace_range(..4, "");
+        Ok(Some(line))
+    } else {
+        Ok(None)
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => ret.push_str("&amp;"),
+            '<' => ret.push_str("&lt;"),
+            '>' => ret.push_str("&gt;"),
+            '"' => ret.push_str("&quot;"),
+            _ => ret.push(c),
+        }
+    }
+    ret
+}
+
+async fn bg<R: Send + 'static>(f: impl FnOnce() -> R + Send + 'static) -> R {
+    let scope = scope::current();
+    tokio::task::spawn_blocking(move || scope::within(scope, f))
+        .await
+        .unwrap()
+}
+
+struct IfChangedSince(Option<SystemTime>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for IfChangedSince {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        use axum::http::header;
+        Ok(Self(
+            parts
+                .headers
+                .get(header::IF_MODIFIED_SINCE)
+                .map(|s| httpdate::parse_http_date(s.to_str().unwrap_or_default()))
+                .transpose()
+                .map_err(|_| Error::BadRequest("cannot parse If-Modified-Since"))?,
+        ))
+    }
+}
+
+/// Entity tags from If-None-Match; an empty list stands for `*`.
+struct IfNoneMatch(Option<Vec<String>>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for IfNoneMatch {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        use axum::http::header;
+        let mut values = parts
+            .headers
+            .get_all(header::IF_NONE_MATCH)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .peekable();
+        if values.peek().is_none() {
+            return Ok(Self(None));
+        }
+        let tags: Vec<_> = values.map(str::to_owned).collect();
+        Ok(Self(Some(if tags.iter().any(|t| t == "*") {
+            Vec::new()
+        } else {
+            tags
+        })))
+    }
+}
+
+/// Bumped whenever a change here alters rendered output.
+const RENDER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Weak validator over the source file and everything else the
+/// rendering depends on.
+struct ETag(String);
+
+impl ETag {
+    fn new(
+        p: &StdPath,
+        mtime: SystemTime,
+        size: u64,
+        format: Format,
+        prefs: &Prefs,
+        installed: u64,
+    ) -> Self {
+        use std::hash::{Hash, Hasher};
+        let mut h = std::hash::DefaultHasher::new();
+        let renderer = render::renderer().name();
+        let template = template::stamp();
+        (
+            p,
+            mtime,
+            size,
+            RENDER_VERSION,
+            renderer,
+            template,
+            format,
+            prefs,
+            installed,
+        )
+            .hash(&mut h);
+        Self(format!("W/\"{:016x}\"", h.finish()))
+    }
+
+    /// Weak comparison against If-None-Match tags.
+    fn matches(&self, tags: &[String]) -> bool {
+        let opaque = |t: &str| t.strip_prefix("W/").unwrap_or(t).to_owned();
+        let ours = opaque(&self.0);
+        tags.is_empty() || tags.iter().any(|t| opaque(t) == ours)
+    }
+}
+
+impl IntoResponseParts for ETag {
+    type Error = StatusCode;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        use axum::http::header;
+        res.headers_mut().insert(
+            header::ETAG,
+            self.0
+                .parse()
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        );
+        Ok(res)
+    }
+}
+
+struct LastModified(SystemTime);
+
+impl IntoResponseParts for LastModified {
+    type Error = StatusCode;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        use axum::http::header;
+        res.headers_mut().insert(
+            header::LAST_MODIFIED,
+            HttpDate::from(self.0)
+                .to_string()
+                .parse()
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        );
+        Ok(res)
+    }
+}
+
+/// Content-Type of a page in some format, along with a file name
+/// for the binary ones.
+struct ContentType<'a>(Format, &'a str);
+
+impl IntoResponseParts for ContentType<'_> {
+    type Error = StatusCode;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        let ContentType(format, name) = self;
+        let headers = res.headers_mut();
+        headers.insert(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_static(format.mime()),
+        );
+        if format.binary() {
+            let file = format!("{name}.{}", format.ext());
+            headers.insert(
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "inline; filename*=UTF-8''{}",
+                    utf8_percent_encode(&file, SEGMENT)
+                )
+                .parse()
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+            );
+        }
+        Ok(res)
+    }
+}
+
+/// Cache-Control for page responses, from config.
+struct CacheControl;
+
+impl IntoResponseParts for CacheControl {
+    type Error = StatusCode;
+
+    fn into_response_parts(self, mut res: ResponseParts) -> Result<ResponseParts, Self::Error> {
+        use axum::http::header;
+        let policy = &config::config().cache_control;
+        if !policy.is_empty() {
+            res.headers_mut().insert(
+                header::CACHE_CONTROL,
+                policy
+                    .parse()
+                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+            );
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::{header, HeaderMap, Request, StatusCode};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn get(host: &str, path: &str) -> (StatusCode, String) {
+        let (status, _, body) = get_with(host, path, HeaderMap::new()).await;
+        (status, body)
+    }
+
+    async fn get_with(
+        host: &str,
+        path: &str,
+        headers: HeaderMap,
+    ) -> (StatusCode, HeaderMap, String) {
+        let mut req = Request::get(path)
+            .header(header::HOST, host)
+            .body(Body::empty())
+            .unwrap();
+        req.headers_mut().extend(headers);
+        let res = crate::testing::server()
+            .router()
+            .unwrap()
+            .oneshot(req)
+            .await
+            .unwrap();
+        let (status, headers) = (res.status(), res.headers().clone());
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, headers, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn health_of_roots() {
+        for (host, root) in [("man", "/man"), ("other.test", "/other")] {
+            let (_, body) = get(host, "/healthz").await;
+            let health: serde_json::Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(
+                health["roots"],
+                serde_json::json!([{ "path": root, "ok": true }])
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn section_listing() {
+        let (status, body) = get("man", "/1/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("/1/ls.1.html") && body.contains("/1/dir.1.html"));
+        assert!(!body.contains("only"));
+        assert_eq!(get("man", "/5/").await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn api_find() {
+        let (status, body) = get("man", "/api/v1/find/mount").await;
+        assert_eq!(status, StatusCode::OK);
+        let found: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(found["section"], "8");
+        assert_eq!(found["url"], "/8/mount.8.html");
+        let (status, body) = get("man", "/api/v1/find/nothing").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body, r#"{"error":"Not Found"}"#);
+    }
+
+    #[test]
+    fn negotiate_format() {
+        let cases = [
+            (&[][..], None, Format::Html),
+            (&["text/plain"], None, Format::Txt),
+            (&["text/html,text/plain;q=0.5"], None, Format::Html),
+            (&["text/plain, text/html;q=0.9"], None, Format::Txt),
+            (&["text/html;q=0.2", "text/plain"], None, Format::Txt),
+            // the most specific range decides
+            (&["text/*;q=0.1, text/plain"], None, Format::Txt),
+            (&["text/html;q=0, */*"], None, Format::Txt),
+            // left to the agent when even
+            (&["*/*"], Some("curl/8.5.0"), Format::Txt),
+            (&[], Some("Wget/1.21.4"), Format::Txt),
+            (&["*/*"], Some("Mozilla/5.0"), Format::Html),
+            (&["text/html"], Some("curl/8.5.0"), Format::Html),
+        ];
+        for (accept, agent, format) in cases {
+            let mut headers = HeaderMap::new();
+            for a in accept {
+                headers.append(header::ACCEPT, a.parse().unwrap());
+            }
+            if let Some(agent) = agent {
+                headers.insert(header::USER_AGENT, agent.parse().unwrap());
+            }
+            assert!(negotiate(&headers) == format, "{accept:?} {agent:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn if_none_match_parsing() {
+        let tags = |values: &[&str]| {
+            let mut req = Request::get("/").body(()).unwrap();
+            for v in values {
+                let v = v.parse().unwrap();
+                req.headers_mut().append(header::IF_NONE_MATCH, v);
+            }
+            let (mut parts, ()) = req.into_parts();
+            async move {
+                let IfNoneMatch(tags) = IfNoneMatch::from_request_parts(&mut parts, &())
+                    .await
+                    .unwrap();
+                tags
+            }
+        };
+        assert_eq!(tags(&[]).await, None);
+        assert_eq!(tags(&[" , "]).await, None);
+        assert_eq!(tags(&["*"]).await, Some(vec![]));
+        assert_eq!(tags(&["\"a\", *"]).await, Some(vec![]));
+        assert_eq!(
+            tags(&["\"a\", W/\"b\"", "\"c\""]).await,
+            Some(vec!["\"a\"".into(), "W/\"b\"".into(), "\"c\"".into()])
+        );
+    }
+
+    #[test]
+    fn etag_weak_comparison() {
+        let etag = ETag("W/\"0123456789abcdef\"".into());
+        let matches =
+            |tags: &[&str]| etag.matches(&tags.iter().map(|&t| t.into()).collect::<Vec<_>>());
+        assert!(matches(&[]));
+        assert!(matches(&["W/\"0123456789abcdef\""]));
+        assert!(matches(&["\"0123456789abcdef\""]));
+        assert!(matches(&["\"other\"", "W/\"0123456789abcdef\""]));
+        assert!(!matches(&["\"other\""]));
+        assert!(!matches(&["0123456789abcdef"]));
+    }
+
+    // pages are only served in formats a renderer has
+    #[cfg(feature = "builtin-renderer")]
+    #[tokio::test]
+    async fn not_modified() {
+        let if_none_match = |tag: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::IF_NONE_MATCH, tag.parse().unwrap());
+            headers
+        };
+        let (status, headers, _) = get_with("man", "/1/ls.1.html", if_none_match("*")).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        let etag = headers[header::ETAG].to_str().unwrap().to_owned();
+        assert!(etag.starts_with("W/\""), "{etag}");
+        let strong = etag.strip_prefix("W/").unwrap();
+        for tag in [&etag[..], strong, &format!("\"x\", {etag}")] {
+            let (status, ..) = get_with("man", "/1/ls.1.html", if_none_match(tag)).await;
+            assert_eq!(status, StatusCode::NOT_MODIFIED, "{tag}");
+        }
+        // another format is another representation
+        let (status, headers, _) = get_with("man", "/1/ls.1.txt", if_none_match("*")).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        assert_ne!(headers[header::ETAG], etag);
+        let (status, ..) = get_with("man", "/1/ls.1.html", if_none_match("\"x\"")).await;
+        assert_ne!(status, StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn hosts_at_once() {
+        let requests = (0..32).map(|i| async move {
+            let host = if i % 2 == 0 { "man" } else { "other.test:8080" };
+            let (status, body) = get(host, "/1/").await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body.contains("/1/only.1.html"), i % 2 == 1, "{host}");
+            assert_eq!(body.contains("/1/ls.1.html"), i % 2 == 0, "{host}");
+        });
+        futures_util::future::join_all(requests.map(tokio::spawn))
+            .await
+            .into_iter()
+            .for_each(|r| r.unwrap());
+    }
+}
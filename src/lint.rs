@@ -0,0 +1,130 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! What `mandoc -T lint` finds wrong with a page, at
+//! `/<section>/<name>.<section>.lint`, `/api/v1/lint/<section>/<name>`
+//! and atop previews.  Needs mandoc, whichever renderer is in use.
+
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+
+use crate::config::config;
+use crate::error::Error;
+use crate::prefs::Prefs;
+use crate::render::RenderError;
+use crate::{bg, browse, escape_html, guard, html, lookup, url_path};
+
+#[derive(Serialize)]
+pub struct Issue {
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+    /// style, warning, error, unsupp or bad, from least to most
+    /// serious.
+    pub level: String,
+    pub message: String,
+}
+
+/// Lint `p`, holding `slot` until done.
+pub async fn check(p: &Path, slot: guard::Slot) -> Result<Vec<Issue>, Error> {
+    let mandoc = &config().render.mandoc;
+    let child = tokio::process::Command::new(mandoc)
+        .args(["-T", "lint"])
+        .arg(p)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    let limit = Duration::from_secs(config().render.timeout);
+    let out = tokio::time::timeout(limit, child)
+        .await
+        .map_err(|_| RenderError::Timeout)?
+        .map_err(|e| RenderError::Spawn(mandoc.display().to_string(), e))?;
+    drop(slot);
+    // 2 to 4 for what it found, above for failing to look
+    if out.status.code().is_none_or(|c| c > 4) {
+        let stderr = String::from_utf8_lossy(&out.stderr).trim_end().to_owned();
+        let prog = format!("{} -T lint", mandoc.display());
+        return Err(RenderError::Failed(prog, out.status, stderr).into());
+    }
+    let text = String::from_utf8_lossy(&out.stdout);
+    Ok(text.lines().filter_map(|l| parse(l, p)).collect())
+}
+
+/// One message, `mandoc: FILE[:LINE[:COL]]: LEVEL: MESSAGE`.
+fn parse(line: &str, p: &Path) -> Option<Issue> {
+    let (_, mut rest) = line.split_once(&format!("{}:", p.display()))?;
+    let mut position = [None, None];
+    for n in &mut position {
+        let Some((num, tail)) = rest.split_once(':') else {
+            break;
+        };
+        let Ok(num) = num.trim().parse() else {
+            break;
+        };
+        *n = Some(num);
+        rest = tail;
+    }
+    let (level, message) = rest.trim_start().split_once(": ")?;
+    Some(Issue {
+        line: position[0],
+        column: position[1],
+        level: level.to_ascii_lowercase(),
+        message: message.to_owned(),
+    })
+}
+
+/// The issues as a table, `table.lint`, a row per issue of its level
+/// as class.
+pub fn table(issues: &[Issue]) -> String {
+    if issues.is_empty() {
+        return "<p>No problems found.</p>\n".to_owned();
+    }
+    let mut out = String::from(
+        "<table class=\"lint\">\n<tr><th>Line</th><th>Level</th><th>Message</th></tr>\n",
+    );
+    for issue in issues {
+        let at = match (issue.line, issue.column) {
+            (Some(l), Some(c)) => format!("{l}:{c}"),
+            (Some(l), None) => l.to_string(),
+            _ => String::new(),
+        };
+        out += &format!(
+            "<tr class=\"{0}\"><td>{at}</td><td>{0}</td><td>{1}</td></tr>\n",
+            escape_html(&issue.level),
+            escape_html(&issue.message)
+        );
+    }
+    out + "</table>\n"
+}
+
+/// `/<section>/<name>.lint`, `name` without the `.lint`.
+pub async fn page(section: String, name: String, prefs: Prefs) -> Result<Response, Error> {
+    let fp = bg({
+        let (section, name) = (section.clone(), name.clone());
+        let lang = prefs.lang.clone();
+        move || lookup::resolve(&section, &format!("{name}.gz"), lang.as_deref())
+    })
+    .await
+    .ok_or(Error::NotFound)?;
+    bg(guard::check).await?;
+    let slot = guard::slot().await?;
+    let issues = check(&fp, slot).await?;
+    let (stem, sec) = name.rsplit_once('.').unwrap_or((&name, &section));
+    let title = format!("{stem}({sec})");
+    let body = format!(
+        "<h1>Lint: <a href=\"{}\">{}</a></h1>\n{}",
+        url_path(&[&section, &format!("{name}.html")]),
+        escape_html(&title),
+        table(&issues)
+    );
+    let title = format!("Lint: {title}");
+    Ok(browse::document(&prefs, &title, html::Place::Other, &body).into_response())
+}
@@ -0,0 +1,488 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Locating page sources under the configured man roots.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+use crate::config::config;
+use crate::source::source;
+use crate::{archive, index, refs, trace, vhost};
+
+/// Sections tried first, in this order, when guessing; any other
+/// section found on disk follows in lexical order.  Taken from
+/// `$MANSECT`, else the `mansect` config key, else a built-in order.
+fn preferred() -> &'static [String] {
+    static PREFERRED: OnceLock<Vec<String>> = OnceLock::new();
+    PREFERRED.get_or_init(|| {
+        std::env::var("MANSECT")
+            .ok()
+            .filter(|s| !s.is_empty())
+            .map(|s| s.split([':', ',']).map(str::to_owned).collect())
+            .or_else(|| config().mansect.clone())
+            .unwrap_or_else(|| {
+                ["1", "8", "6", "2", "3", "5", "7", "4", "9", "3p"]
+                    .map(String::from)
+                    .into()
+            })
+    })
+}
+
+/// Sections present under `root`, i.e. the suffixes of its man*
+/// directories.
+pub fn sections(root: &Path) -> Vec<String> {
+    let Ok(names) = source().list(root) else {
+        return Vec::new();
+    };
+    let mut ret: Vec<_> = names
+        .iter()
+        .filter_map(|name| {
+            let section = name
+                .to_str()?
+                .strip_prefix("man")
+                .filter(|s| !s.is_empty())?;
+            let dir = source().metadata(&root.join(name)).is_ok_and(|m| m.dir);
+            dir.then(|| section.to_owned())
+        })
+        .collect();
+    ret.sort_unstable_by_key(|s| order(s));
+    ret
+}
+
+/// Sort key for sections: preferred ones first, then lexical.
+fn order(s: &str) -> (usize, String) {
+    let preferred = preferred();
+    (
+        preferred
+            .iter()
+            .position(|p| p == s)
+            .unwrap_or(preferred.len()),
+        s.to_owned(),
+    )
+}
+
+/// Roots to search for a reader preferring `lang`: within each
+/// configured root, the localized trees for `lang` and for its bare
+/// language code come before the root itself.
+fn roots(lang: Option<&str>) -> Vec<PathBuf> {
+    vhost::roots()
+        .iter()
+        .flat_map(|root| localized(root, lang))
+        .collect()
+}
+
+/// The trees of `root` for `lang`, most preferred first.
+fn localized(root: &Path, lang: Option<&str>) -> Vec<PathBuf> {
+    // e.g. pt_BR.UTF-8@euro -> pt_BR, then pt
+    let full = lang.and_then(|l| l.split(['.', '@']).next());
+    let short = full
+        .and_then(|l| l.split('_').next())
+        .filter(|s| Some(*s) != full);
+    [full, short]
+        .into_iter()
+        .flatten()
+        .filter(|l| valid_component(l))
+        .map(|l| root.join(l))
+        .chain([root.to_owned()])
+        .collect()
+}
+
+/// Name of configured root `root`, as shown when switching between
+/// its pages and those of other roots.
+pub fn label(root: &Path) -> String {
+    config()
+        .labels
+        .get(root)
+        .cloned()
+        .unwrap_or_else(|| root.display().to_string())
+}
+
+/// Language of the localized tree `path` lies in, if any.
+pub fn language(path: &Path) -> Option<String> {
+    vhost::roots().iter().find_map(|root| {
+        let first = path.strip_prefix(root).ok()?.components().next()?;
+        let first = first.as_os_str().to_str()?;
+        (!first.starts_with("man")).then(|| first.to_owned())
+    })
+}
+
+/// Interpret `name` as either `<page>.<section>` or a bare page name,
+/// returning the page and its section.  A suffix only counts as a
+/// section if that page really exists in it, so dots inside names,
+/// like systemd.unit(5), are left alone.
+pub fn parse(name: &str, lang: Option<&str>) -> Option<(String, String)> {
+    name.rsplit_once('.')
+        .filter(|(page, section)| {
+            !page.is_empty()
+                && section.starts_with(|c: char| c.is_ascii_alphanumeric())
+                && resolve(section, &format!("{name}.gz"), lang).is_some()
+        })
+        .map(|(page, section)| (page.to_owned(), section.to_owned()))
+        .or_else(|| Some((name.to_owned(), locate(name, lang)?)))
+}
+
+/// Find the section of page `name`, from the index of each root in
+/// order, then the package archives.  Besides
+/// `man<sec>/<name>.<sec>.gz`, pages filed under a plain directory
+/// with an extended suffix, like `man3/CRYPTO_free.3ssl.gz`, are
+/// found too.
+pub fn locate(name: &str, lang: Option<&str>) -> Option<String> {
+    if !valid_component(name) {
+        return None;
+    }
+    roots(lang)
+        .iter()
+        .find_map(|root| index::get(root).pages.get(name)?.first().cloned())
+        .or_else(|| archive::sections(name).into_iter().min_by_key(|s| order(s)))
+}
+
+/// Pages whose name contains `q`, ignoring case, with their
+/// sections: those starting with it first, each group by name.
+pub fn search(q: &str, lang: Option<&str>, limit: usize) -> Vec<(String, Vec<String>)> {
+    let q = q.to_lowercase();
+    let mut found: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for root in roots(lang) {
+        for (name, secs) in &index::get(&root).pages {
+            if !name.to_lowercase().contains(&q) {
+                continue;
+            }
+            let ours = found.entry(name.clone()).or_default();
+            for sec in secs {
+                if !ours.contains(sec) {
+                    ours.push(sec.clone());
+                }
+            }
+        }
+    }
+    let mut ret: Vec<_> = found.into_iter().collect();
+    ret.sort_by_key(|(name, _)| !name.to_lowercase().starts_with(&q));
+    ret.truncate(limit);
+    ret
+}
+
+/// Sections having any pages, in probing order.
+pub fn all_sections(lang: Option<&str>) -> Vec<String> {
+    let mut ret: Vec<String> = roots(lang)
+        .iter()
+        .flat_map(|root| {
+            let index = index::get(root);
+            let secs: BTreeSet<String> = index.pages.values().flatten().cloned().collect();
+            secs
+        })
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    ret.sort_unstable_by_key(|s| order(s));
+    ret
+}
+
+/// Names of the pages in `section`, sorted.
+pub fn section_pages(section: &str, lang: Option<&str>) -> Vec<String> {
+    roots(lang)
+        .iter()
+        .flat_map(|root| {
+            let index = index::get(root);
+            let names: Vec<String> = index
+                .pages
+                .iter()
+                .filter(|(_, secs)| secs.iter().any(|s| s == section))
+                .map(|(name, _)| name.clone())
+                .collect();
+            names
+        })
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Pages referring to `name` in `section`, sorted.  Unless `wait`,
+/// none from roots whose references are still being found.  May
+/// block, for long if `wait`.
+pub fn referenced_by(
+    name: &str,
+    section: &str,
+    lang: Option<&str>,
+    wait: bool,
+) -> Vec<(String, String)> {
+    let key = (name.to_owned(), section.to_owned());
+    roots(lang)
+        .iter()
+        .filter_map(|root| refs::get(root, wait))
+        .flat_map(|refs| refs.get(&key).cloned().unwrap_or_default())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// What pages are installed, from the indexes of the roots for
+/// `lang`, to check references against without blocking.
+#[derive(Clone)]
+pub struct Installed(Vec<Arc<index::Index>>);
+
+impl Installed {
+    pub fn has(&self, name: &str, section: &str) -> bool {
+        self.0.iter().any(|index| {
+            index
+                .pages
+                .get(name)
+                .is_some_and(|secs| secs.iter().any(|s| s == section))
+        })
+    }
+}
+
+/// May block.
+pub fn installed(lang: Option<&str>) -> Installed {
+    Installed(roots(lang).iter().map(|root| index::get(root)).collect())
+}
+
+/// Changes with the indexes of the roots for `lang`, and so with what
+/// pages link to and the versions they list, and as references are
+/// found.  May block.
+pub fn stamp(lang: Option<&str>) -> u64 {
+    let mut h = std::hash::DefaultHasher::new();
+    for root in roots(lang) {
+        let stamp = index::get(&root).stamp();
+        (stamp, refs::built(&root, stamp)).hash(&mut h);
+    }
+    h.finish()
+}
+
+/// Path of source `file` in `section`, from the first root that has
+/// it, else extracted from the package archives.  Extended sections
+/// fall back to the directory of their base section, e.g. 3ssl pages
+/// live in man3 on Debian.
+pub fn resolve(section: &str, file: &str, lang: Option<&str>) -> Option<PathBuf> {
+    if !valid_section(section) || !valid_component(file) {
+        return None;
+    }
+    let mut span = trace::span("lookup");
+    span.attr("page", format!("{section}/{file}"));
+    let base = section
+        .find(|c: char| !c.is_ascii_digit())
+        .filter(|&i| i > 0)
+        .map(|i| &section[..i]);
+    roots(lang)
+        .iter()
+        .find_map(|root| find_in(root, section, base, file))
+        .or_else(|| archive::fetch(section, file))
+}
+
+/// Source `file` of `section` in tree `root`, falling back to the
+/// directory of `base`.  Pages missing from the index of `root` are
+/// not looked for, so misses cost no more than checking it is fresh.
+fn find_in(root: &Path, section: &str, base: Option<&str>, file: &str) -> Option<PathBuf> {
+    let page = file.strip_suffix(".gz").and_then(|f| f.rsplit_once('.'));
+    if let Some((name, sec)) = page {
+        let index = index::get(root);
+        if !index
+            .pages
+            .get(name)
+            .is_some_and(|secs| secs.iter().any(|s| s == sec))
+        {
+            return None;
+        }
+    }
+    std::iter::once(section)
+        .chain(base)
+        .map(|dir| root.join(format!("man{dir}/{file}")))
+        .find(|p| source().exists(p) && within(p, root))
+}
+
+/// Every configured root's own copy of source `file` in `section`, in
+/// order of precedence, with the label of the root.  Blocks.
+pub fn versions(section: &str, file: &str, lang: Option<&str>) -> Vec<(String, PathBuf)> {
+    if !valid_section(section) || !valid_component(file) {
+        return Vec::new();
+    }
+    let base = section
+        .find(|c: char| !c.is_ascii_digit())
+        .filter(|&i| i > 0)
+        .map(|i| &section[..i]);
+    vhost::roots()
+        .iter()
+        .filter_map(|root| {
+            let found = localized(root, lang)
+                .iter()
+                .find_map(|tree| find_in(tree, section, base, file))?;
+            Some((label(root), found))
+        })
+        .collect()
+}
+
+/// Sections are directory name suffixes like `3` or `3ssl`.
+pub fn valid_section(s: &str) -> bool {
+    !s.is_empty() && s.len() <= 16 && s.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// The `name(section)` references in `text`: where each starts and
+/// ends, and its name and section.
+pub fn references(text: &str) -> impl Iterator<Item = (Range<usize>, &str, &str)> {
+    text.match_indices('(').filter_map(|(i, _)| {
+        // no further than the longest section, or text full of
+        // parentheses takes quadratic time
+        let len = text[i + 1..].bytes().take(17).position(|b| b == b')')?;
+        let section = &text[i + 1..i + 1 + len];
+        let start = text[..i]
+            .char_indices()
+            .rev()
+            .take_while(|&(_, c)| c.is_alphanumeric() || "_.:+-@".contains(c))
+            .last()
+            .map_or(i, |(j, _)| j);
+        let name = text[start..i].trim_start_matches(['.', '-', ':']);
+        (!name.is_empty()
+            && section.starts_with(|c: char| c.is_ascii_digit())
+            && valid_section(section))
+        .then(|| (i - name.len()..i + len + 2, name, section))
+    })
+}
+
+/// Whether `s` can only ever name an entry of a directory: no
+/// separators or control characters, and not `.` or `..`.
+pub fn valid_component(s: &str) -> bool {
+    !s.is_empty()
+        && s != "."
+        && s != ".."
+        && !s.contains(|c: char| c == '/' || c == '\\' || c.is_control())
+}
+
+/// Whether the directory holding `p` really is inside `root` once
+/// symlinks are resolved.  The file itself may still be a symlink
+/// elsewhere, as with Debian's /etc/alternatives.  Trees not on the
+/// local filesystem have no symlinks to follow.
+fn within(p: &Path, root: &Path) -> bool {
+    let (Some(p), Some(root)) = (source().local_path(p), source().local_path(root)) else {
+        return true;
+    };
+    inside(&p, &root)
+}
+
+/// Whether the directory of local file `p` resolves to inside `root`.
+fn inside(p: &Path, root: &Path) -> bool {
+    let (Some(Ok(dir)), Ok(root)) = (p.parent().map(std::fs::canonicalize), root.canonicalize())
+    else {
+        return false;
+    };
+    dir.starts_with(root)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decoded(s: &str) -> String {
+        percent_encoding::percent_decode_str(s)
+            .decode_utf8_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn hostile_components() {
+        for s in [
+            "",
+            ".",
+            "..",
+            "../etc",
+            "a/b",
+            "/etc/passwd",
+            "a\\b",
+            "a\0b",
+            "a\nb",
+        ] {
+            assert!(!valid_component(s), "{s:?}");
+        }
+        for s in [
+            "..%2fetc%2fpasswd",
+            "%2fetc",
+            "a%5cb",
+            "ls.1%00.gz",
+            "%2e%2e",
+        ] {
+            assert!(!valid_component(&decoded(s)), "{s:?}");
+        }
+        for s in [
+            "ls.1.gz",
+            "..foo.1.gz",
+            "g++.1.gz",
+            "x86_64-linux-gnu-ld.1.gz",
+        ] {
+            assert!(valid_component(s), "{s:?}");
+        }
+    }
+
+    #[test]
+    fn hostile_sections() {
+        for s in [
+            "",
+            "..",
+            "1/../..",
+            "/etc",
+            "1\0",
+            "3%2f",
+            "1 ",
+            "ö",
+            "12345678901234567",
+        ] {
+            assert!(!valid_section(s), "{s:?}");
+        }
+        assert!(!valid_section(&decoded("1%2f..")));
+        for s in ["1", "3p", "3pm", "n", "8"] {
+            assert!(valid_section(s), "{s:?}");
+        }
+    }
+
+    #[test]
+    fn through_a_source() {
+        crate::testing::setup();
+        assert_eq!(
+            resolve("1", "ls.1.gz", None),
+            Some("/man/man1/ls.1.gz".into())
+        );
+        assert_eq!(resolve("1", "only.1.gz", None), None);
+        assert_eq!(resolve("1", "../man8/mount.8.gz", None), None);
+        assert_eq!(locate("mount", None).as_deref(), Some("8"));
+        assert_eq!(section_pages("1", None), ["dir", "list", "ls"]);
+        assert_eq!(all_sections(None), ["1", "8"]);
+    }
+
+    #[test]
+    fn misses_not_probed() {
+        crate::testing::setup();
+        for _ in 0..2 {
+            assert_eq!(resolve("1", "nosuchpage.1.gz", None), None);
+            assert_eq!(resolve("1", "ls.8.gz", None), None);
+        }
+        let probed = crate::testing::probed();
+        assert!(
+            !probed.iter().any(|p| p.ends_with("nosuchpage.1.gz")),
+            "{probed:?}"
+        );
+        assert!(!probed.iter().any(|p| p.ends_with("ls.8.gz")), "{probed:?}");
+    }
+
+    #[test]
+    fn symlinks_out_of_root() {
+        let dir = std::env::temp_dir().join(format!("handoc-within-{}", std::process::id()));
+        let (root, outside) = (dir.join("root"), dir.join("outside"));
+        std::fs::create_dir_all(root.join("man1")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("man8")).unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", root.join("man1/alt.1.gz")).unwrap();
+        assert!(inside(&root.join("man1/ls.1.gz"), &root));
+        // a symlinked page file is followed, as with alternatives
+        assert!(inside(&root.join("man1/alt.1.gz"), &root));
+        assert!(!inside(&root.join("man8/evil.8.gz"), &root));
+        assert!(!inside(&root.join("man1/../../outside/x.1.gz"), &root));
+        assert!(!inside(Path::new("/etc/passwd"), &root));
+        assert!(!inside(&root.join("missing/x.1.gz"), &root));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,245 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! The common part of Markdown, enough for the READMEs packages ship:
+//! ATX headings, paragraphs, one level of lists, block quotes, fenced
+//! and indented code, rules, and inline code, emphasis, links and
+//! autolinks.  Raw HTML is shown as text.
+
+use crate::escape_html;
+
+#[derive(PartialEq)]
+enum Block {
+    None,
+    Para,
+    List(&'static str),
+    Quote,
+    Code,
+}
+
+pub fn render(src: &str) -> String {
+    let mut out = String::new();
+    let mut block = Block::None;
+    // the text of the paragraph, list item or quote being read
+    let mut text = String::new();
+    let mut fence: Option<&str> = None;
+    let flush = |out: &mut String, block: &Block, text: &mut String| {
+        let t = inline(text.trim());
+        match block {
+            Block::Para if !t.is_empty() => *out += &format!("<p>{t}</p>\n"),
+            Block::List(_) => *out += &format!("<li>{t}</li>\n"),
+            Block::Quote => *out += &format!("<blockquote><p>{t}</p></blockquote>\n"),
+            _ => {}
+        }
+        text.clear();
+    };
+    let close = |out: &mut String, block: &mut Block, text: &mut String| {
+        flush(out, block, text);
+        match block {
+            Block::List(tag) => *out += &format!("</{tag}>\n"),
+            Block::Code => *out += "</code></pre>\n",
+            _ => {}
+        }
+        *block = Block::None;
+    };
+    for line in src.lines() {
+        if let Some(f) = fence {
+            if line.trim_start().starts_with(f) {
+                fence = None;
+                close(&mut out, &mut block, &mut text);
+            } else {
+                out += &escape_html(line);
+                out.push('\n');
+            }
+            continue;
+        }
+        let trimmed = line.trim_start();
+        if let Some(f) = ["```", "~~~"].into_iter().find(|f| trimmed.starts_with(f)) {
+            close(&mut out, &mut block, &mut text);
+            fence = Some(f);
+            block = Block::Code;
+            out += "<pre><code>";
+            continue;
+        }
+        if line.trim().is_empty() {
+            if block != Block::Code {
+                close(&mut out, &mut block, &mut text);
+            } else {
+                out.push('\n');
+            }
+            continue;
+        }
+        let indented = line.starts_with("    ") || line.starts_with('\t');
+        if indented && matches!(block, Block::None | Block::Code) {
+            if block == Block::None {
+                block = Block::Code;
+                out += "<pre><code>";
+            }
+            let code = line.strip_prefix('\t').unwrap_or_else(|| &line[4..]);
+            out += &escape_html(code);
+            out.push('\n');
+            continue;
+        }
+        if block == Block::Code {
+            close(&mut out, &mut block, &mut text);
+        }
+        let level = trimmed.chars().take_while(|&c| c == '#').count();
+        if (1..=6).contains(&level) && trimmed[level..].starts_with([' ', '\t']) {
+            close(&mut out, &mut block, &mut text);
+            let title = trimmed[level..].trim().trim_end_matches('#').trim_end();
+            out += &format!("<h{level}>{}</h{level}>\n", inline(title));
+            continue;
+        }
+        // a setext underline makes the paragraph so far a heading
+        if block == Block::Para {
+            let mark = trimmed.trim_end();
+            let level = match mark.chars().next() {
+                Some('=') if mark.chars().all(|c| c == '=') => 1,
+                Some('-') if mark.chars().all(|c| c == '-') => 2,
+                _ => 0,
+            };
+            if level > 0 {
+                out += &format!("<h{level}>{}</h{level}>\n", inline(text.trim()));
+                text.clear();
+                block = Block::None;
+                continue;
+            }
+        }
+        let bare: String = trimmed.chars().filter(|c| !c.is_whitespace()).collect();
+        if bare.len() >= 3
+            && ['-', '*', '_']
+                .iter()
+                .any(|&r| bare.chars().all(|c| c == r))
+        {
+            close(&mut out, &mut block, &mut text);
+            out += "<hr/>\n";
+            continue;
+        }
+        if let Some(item) = list_item(trimmed).filter(|_| !indented) {
+            let (tag, rest) = item;
+            if block != Block::List(tag) {
+                close(&mut out, &mut block, &mut text);
+                out += &format!("<{tag}>\n");
+                block = Block::List(tag);
+            } else {
+                flush(&mut out, &block, &mut text);
+            }
+            text += rest;
+            text.push('\n');
+            continue;
+        }
+        if let Some(quoted) = trimmed.strip_prefix('>') {
+            if block != Block::Quote {
+                close(&mut out, &mut block, &mut text);
+                block = Block::Quote;
+            }
+            text += quoted;
+            text.push('\n');
+            continue;
+        }
+        if block == Block::None {
+            block = Block::Para;
+        }
+        text += trimmed;
+        text.push('\n');
+    }
+    close(&mut out, &mut block, &mut text);
+    out
+}
+
+/// The list tag and text of a list item line.
+fn list_item(line: &str) -> Option<(&'static str, &str)> {
+    if let Some(rest) = ["- ", "* ", "+ "].iter().find_map(|m| line.strip_prefix(m)) {
+        return Some(("ul", rest));
+    }
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    let rest = line[digits..]
+        .strip_prefix(". ")
+        .or_else(|| line[digits..].strip_prefix(") "))?;
+    (digits > 0).then_some(("ol", rest))
+}
+
+/// Code spans, `**strong**`, `*emphasis*`, `[links](url)` and
+/// `<url>`.
+fn inline(s: &str) -> String {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(i) = rest.find(['`', '*', '_', '[', '<']) {
+        out += &escape_html(&rest[..i]);
+        // no emphasis within snake_case words
+        rest = &rest[i..];
+        let in_word = rest.starts_with('_') && out.ends_with(|c: char| c.is_alphanumeric());
+        let (html, len) = span(rest)
+            .filter(|_| !in_word)
+            .unwrap_or_else(|| (escape_html(&rest[..1]), 1));
+        out += &html;
+        rest = &rest[len..];
+    }
+    out + &escape_html(rest)
+}
+
+/// The span `s` starts with as HTML, and its length.
+fn span(s: &str) -> Option<(String, usize)> {
+    let url_ok = |u: &str| {
+        ["https://", "http://", "mailto:", "#", "/", "./", "../"]
+            .iter()
+            .any(|p| u.starts_with(p))
+            || !u.contains(':')
+    };
+    match s.as_bytes()[0] {
+        b'`' => {
+            let ticks = s.chars().take_while(|&c| c == '`').count();
+            let end = s[ticks..].find(&s[..ticks])?;
+            let code = s[ticks..ticks + end].trim();
+            Some((
+                format!("<code>{}</code>", escape_html(code)),
+                2 * ticks + end,
+            ))
+        }
+        c @ (b'*' | b'_') => {
+            let strong = s.as_bytes().get(1) == Some(&c);
+            let mark = &s[..if strong { 2 } else { 1 }];
+            let body = &s[mark.len()..];
+            if body.starts_with(char::is_whitespace) {
+                return None;
+            }
+            let end = body.find(mark).filter(|&e| e > 0)?;
+            let tag = if strong { "strong" } else { "em" };
+            Some((
+                format!("<{tag}>{}</{tag}>", inline(&body[..end])),
+                2 * mark.len() + end,
+            ))
+        }
+        b'[' => {
+            let close = s.find("](")?;
+            let end = close + 2 + s[close + 2..].find(')')?;
+            let url = s[close + 2..end].split_whitespace().next().unwrap_or("");
+            if !url_ok(url) {
+                return None;
+            }
+            Some((
+                format!(
+                    "<a href=\"{}\">{}</a>",
+                    escape_html(url),
+                    inline(&s[1..close])
+                ),
+                end + 1,
+            ))
+        }
+        _ => {
+            let end = s.find('>')?;
+            let url = &s[1..end];
+            (url.starts_with("https://") || url.starts_with("http://")).then(|| {
+                (
+                    format!("<a href=\"{0}\">{0}</a>", escape_html(url)),
+                    end + 1,
+                )
+            })
+        }
+    }
+}
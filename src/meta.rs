@@ -0,0 +1,176 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! What can be told about a page from its source without rendering
+//! it: the one-line description from NAME, and the pages named under
+//! SEE ALSO.
+
+use std::io::Read;
+use std::path::Path;
+
+use crate::source::source;
+use crate::{lookup, trace};
+
+#[derive(Default)]
+pub struct Meta {
+    pub description: Option<String>,
+    /// (name, section) in order of appearance.
+    pub see_also: Vec<(String, String)>,
+}
+
+/// Read the metadata of the gzipped source `p`.  Blocks.
+pub fn read(p: &Path) -> std::io::Result<Meta> {
+    let _span = trace::span("decompress");
+    let mut src = Vec::new();
+    flate2::read::GzDecoder::new(source().open(p)?).read_to_end(&mut src)?;
+    let src = crate::encoding::convert(&src, p).unwrap_or(src);
+    Ok(parse(&String::from_utf8_lossy(&src)))
+}
+
+fn parse(src: &str) -> Meta {
+    let mut heading = String::new();
+    let mut name = String::new();
+    let mut description = None;
+    let mut see_also = Vec::new();
+    for line in src.lines() {
+        let (mac, args) = match line.strip_prefix(['.', '\'']) {
+            Some(req) => {
+                let req = req.trim_start();
+                req.split_once([' ', '\t']).unwrap_or((req, ""))
+            }
+            None => ("", line),
+        };
+        let text = match mac {
+            "SH" | "Sh" => {
+                heading = plain(&unquote(args, " ")).to_ascii_uppercase();
+                continue;
+            }
+            "Nd" => {
+                description = Some(plain(args));
+                continue;
+            }
+            "Xr" if heading == "SEE ALSO" => {
+                let mut words = args.split_whitespace();
+                if let (Some(page), Some(sec)) = (words.next(), words.next()) {
+                    let sec = sec.trim_end_matches(|c: char| c.is_ascii_punctuation());
+                    see_also.push((page.to_owned(), sec.to_owned()));
+                }
+                continue;
+            }
+            "" => plain(args),
+            "B" | "I" | "SM" | "SB" => plain(&unquote(args, " ")),
+            "BR" | "BI" | "IR" | "IB" | "RB" | "RI" => plain(&unquote(args, "")),
+            _ => continue,
+        };
+        match heading.as_str() {
+            "NAME" => {
+                name.push_str(&text);
+                name.push(' ');
+            }
+            "SEE ALSO" => see_also.extend(
+                lookup::references(&text).map(|(_, name, sec)| (name.to_owned(), sec.to_owned())),
+            ),
+            _ => {}
+        }
+    }
+    let description = description.or_else(|| {
+        let (_, desc) = name.split_once(" - ")?;
+        Some(desc.split_whitespace().collect::<Vec<_>>().join(" "))
+    });
+    let mut seen = std::collections::HashSet::new();
+    see_also.retain(|r| seen.insert(r.clone()));
+    Meta {
+        description: description.filter(|d| !d.is_empty()),
+        see_also,
+    }
+}
+
+/// Macro arguments with their quotes removed, joined by `sep`.
+fn unquote(args: &str, sep: &str) -> String {
+    let mut words = Vec::new();
+    let mut rest = args.trim_start();
+    while !rest.is_empty() {
+        let (word, tail) = match rest.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => rest.split_once([' ', '\t']).unwrap_or((rest, "")),
+        };
+        words.push(word);
+        rest = tail.trim_start();
+    }
+    words.join(sep)
+}
+
+/// Text with the escapes of font changes and such taken out.
+fn plain(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('-') => ret.push('-'),
+            Some('e' | '\\') => ret.push('\\'),
+            Some(' ' | '~' | '0') => ret.push(' '),
+            Some('"') | None => break,
+            Some('f' | '*') => {
+                escape_name(&mut chars);
+            }
+            Some('(') => {
+                let name: String = chars.by_ref().take(2).collect();
+                ret.push_str(glyph(&name));
+            }
+            Some('[') => {
+                let name: String = chars.by_ref().take_while(|&c| c != ']').collect();
+                ret.push_str(glyph(&name));
+            }
+            Some('&' | '|' | '^' | 'c' | ',' | '/') => {}
+            Some(c) => ret.push(c),
+        }
+    }
+    ret
+}
+
+/// The name after `\f` or `\*`: one character, two after `(`, or
+/// up to `]` after `[`.
+fn escape_name(chars: &mut std::str::Chars) -> String {
+    match chars.next() {
+        Some('(') => chars.by_ref().take(2).collect(),
+        Some('[') => chars.by_ref().take_while(|&c| c != ']').collect(),
+        c => c.into_iter().collect(),
+    }
+}
+
+fn glyph(name: &str) -> &'static str {
+    match name {
+        "em" | "en" | "hy" | "mi" => "-",
+        "aq" | "cq" | "oq" => "'",
+        "dq" | "lq" | "rq" => "\"",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn see_also_in_localized_text() {
+        let meta = parse(
+            ".SH NAME\nls \\- list\n.SH \"SEE ALSO\"\n、ls(1)、«dir(1)», \\fBcp\\fR(1)\n.BR mv (1)\n",
+        );
+        assert_eq!(meta.description.as_deref(), Some("list"));
+        let names: Vec<_> = meta
+            .see_also
+            .iter()
+            .map(|(n, s)| format!("{n}.{s}"))
+            .collect();
+        assert_eq!(names, ["ls.1", "dir.1", "cp.1", "mv.1"]);
+    }
+}
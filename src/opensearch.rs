@@ -0,0 +1,66 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! OpenSearch, so browsers can add handoc as a search engine: the
+//! description at `/opensearch.xml`, and completions of page names
+//! at `/suggest?q=`.
+
+use axum::http::{header, HeaderMap, Uri};
+use axum::response::{IntoResponse, Response};
+
+use crate::prefs::Prefs;
+use crate::{base, bg, escape_html, lookup, origin, query_param};
+
+/// Most completions offered.
+const SUGGEST_LIMIT: usize = 10;
+
+pub async fn description(headers: HeaderMap) -> Response {
+    let origin = escape_html(&(origin(&headers) + base()));
+    let body = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<OpenSearchDescription xmlns="http://a9.com/-/spec/opensearch/1.1/">
+<ShortName>handoc</ShortName>
+<Description>Manual pages</Description>
+<InputEncoding>UTF-8</InputEncoding>
+<Url type="text/html" method="get" template="{origin}/{{searchTerms}}"/>
+<Url type="application/x-suggestions+json" method="get" template="{origin}/suggest?q={{searchTerms}}"/>
+<Url type="application/opensearchdescription+xml" rel="self" template="{origin}/opensearch.xml"/>
+</OpenSearchDescription>
+"#
+    );
+    (
+        [(
+            header::CONTENT_TYPE,
+            "application/opensearchdescription+xml",
+        )],
+        body,
+    )
+        .into_response()
+}
+
+/// `["<query>", ["<page>", ...]]`, page names starting with or
+/// containing the query.
+pub async fn suggest(uri: Uri, prefs: Prefs) -> Response {
+    let q = query_param(&uri, "q").unwrap_or_default();
+    let q = q.trim().to_owned();
+    let names: Vec<String> = if q.is_empty() {
+        Vec::new()
+    } else {
+        let q = q.clone();
+        bg(move || lookup::search(&q, prefs.lang.as_deref(), SUGGEST_LIMIT))
+            .await
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect()
+    };
+    (
+        [(header::CONTENT_TYPE, "application/x-suggestions+json")],
+        serde_json::json!([q, names]).to_string(),
+    )
+        .into_response()
+}
@@ -0,0 +1,181 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Which package installed each page, from the dpkg, pacman or rpm
+//! database, read once on first use; only man pages are kept.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::LazyLock;
+use std::time::SystemTime;
+
+use crate::config::{config, PackageDb};
+
+const DPKG: &str = "/var/lib/dpkg";
+const PACMAN: &str = "/var/lib/pacman/local";
+const RPM: &str = "/var/lib/rpm";
+
+#[derive(Clone)]
+pub struct Package {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Default)]
+struct Db {
+    owners: HashMap<PathBuf, usize>,
+    /// Packages with their pages.
+    packages: Vec<(Package, Vec<PathBuf>)>,
+}
+
+impl Db {
+    fn add(&mut self, package: Package, files: impl IntoIterator<Item = PathBuf>) {
+        let pages: Vec<_> = files.into_iter().filter(|f| is_page(f)).collect();
+        if pages.is_empty() {
+            return;
+        }
+        let i = self.packages.len();
+        self.owners.extend(pages.iter().map(|p| (p.clone(), i)));
+        self.packages.push((package, pages));
+    }
+}
+
+fn is_page(p: &Path) -> bool {
+    p.components().any(|c| c.as_os_str() == "man") && p.extension().is_some_and(|e| e == "gz")
+}
+
+/// The configured database, or the one found.
+fn kind() -> PackageDb {
+    let exists = |p: &str| Path::new(p).is_dir();
+    match config().packages.db {
+        PackageDb::Auto if exists(DPKG) => PackageDb::Dpkg,
+        PackageDb::Auto if exists(PACMAN) => PackageDb::Pacman,
+        PackageDb::Auto => PackageDb::Rpm,
+        db => db,
+    }
+}
+
+static DB: LazyLock<Db> = LazyLock::new(|| {
+    let ret = match kind() {
+        PackageDb::Dpkg => dpkg(),
+        PackageDb::Pacman => pacman(),
+        PackageDb::Rpm => rpm(),
+        PackageDb::Auto | PackageDb::None => None,
+    };
+    ret.unwrap_or_default()
+});
+
+/// When the database last changed, without reading it.  Blocks.
+pub fn stamp() -> Option<SystemTime> {
+    let path = match kind() {
+        PackageDb::Dpkg => Path::new(DPKG).join("status"),
+        PackageDb::Pacman => PACMAN.into(),
+        PackageDb::Rpm => RPM.into(),
+        PackageDb::Auto | PackageDb::None => return None,
+    };
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// The package that installed page source `p`.  May block.
+pub fn owner(p: &Path) -> Option<Package> {
+    DB.owners.get(p).map(|&i| DB.packages[i].0.clone())
+}
+
+/// Package `name` and its page sources.  May block.
+pub fn pages(name: &str) -> Option<(Package, Vec<PathBuf>)> {
+    DB.packages.iter().find(|(p, _)| p.name == name).cloned()
+}
+
+/// `status` for the versions of installed packages, and their
+/// `info/<package>[:<arch>].list` for the files.
+fn dpkg() -> Option<Db> {
+    let status = std::fs::read_to_string(Path::new(DPKG).join("status")).ok()?;
+    let mut db = Db::default();
+    for para in status.split("\n\n") {
+        let field = |name: &str| {
+            para.lines()
+                .find_map(|l| l.strip_prefix(name)?.strip_prefix(": "))
+        };
+        let (Some(name), Some(version)) = (field("Package"), field("Version")) else {
+            continue;
+        };
+        if !field("Status").is_some_and(|s| s.ends_with(" installed")) {
+            continue;
+        }
+        let info = Path::new(DPKG).join("info");
+        let list = [format!("{name}.list")]
+            .into_iter()
+            .chain(field("Architecture").map(|a| format!("{name}:{a}.list")))
+            .find_map(|f| std::fs::read_to_string(info.join(f)).ok());
+        let Some(list) = list else {
+            continue;
+        };
+        let package = Package {
+            name: name.to_owned(),
+            version: version.to_owned(),
+        };
+        db.add(package, list.lines().map(PathBuf::from));
+    }
+    Some(db)
+}
+
+/// `local/<name>-<version>/desc` and `files`, the latter relative to
+/// the root.
+fn pacman() -> Option<Db> {
+    let mut db = Db::default();
+    for ent in std::fs::read_dir(PACMAN).ok()?.flatten() {
+        let read = |f| std::fs::read_to_string(ent.path().join(f)).unwrap_or_default();
+        let desc = read("desc");
+        let field = |name: &str| {
+            let mut lines = desc.lines();
+            lines.find(|l| *l == name)?;
+            lines.next().map(str::to_owned)
+        };
+        let (Some(name), Some(version)) = (field("%NAME%"), field("%VERSION%")) else {
+            continue;
+        };
+        let files = read("files");
+        let files = files
+            .lines()
+            .skip_while(|l| *l != "%FILES%")
+            .skip(1)
+            .take_while(|l| !l.is_empty())
+            .map(|f| Path::new("/").join(f));
+        db.add(Package { name, version }, files);
+    }
+    Some(db)
+}
+
+fn rpm() -> Option<Db> {
+    let out = Command::new("rpm")
+        .args([
+            "-qa",
+            "--qf",
+            "[%{NAME}\\t%{VERSION}-%{RELEASE}\\t%{FILENAMES}\\n]",
+        ])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())?;
+    let mut files: HashMap<(String, String), Vec<PathBuf>> = HashMap::new();
+    for line in String::from_utf8_lossy(&out.stdout).lines() {
+        let mut parts = line.splitn(3, '\t');
+        if let (Some(name), Some(version), Some(file)) = (parts.next(), parts.next(), parts.next())
+        {
+            files
+                .entry((name.to_owned(), version.to_owned()))
+                .or_default()
+                .push(file.into());
+        }
+    }
+    let mut db = Db::default();
+    for ((name, version), files) in files {
+        db.add(Package { name, version }, files);
+    }
+    Some(db)
+}
@@ -0,0 +1,125 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Perl documentation at `/pod/<module>`, e.g. `/pod/File::Temp`,
+//! found in perl's `@INC` and turned into HTML by the Pod::Simple
+//! that comes with perl, with links to other modules and to man pages
+//! pointing back here.
+
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::LazyLock;
+
+use axum::extract::Path;
+use axum::response::{IntoResponse, Response};
+
+use crate::config::config;
+use crate::error::Error;
+use crate::prefs::Prefs;
+use crate::render::RenderError;
+use crate::{bg, browse, guard, html, with_base};
+
+/// Prints the body of the POD document `$ARGV[0]`, linking modules
+/// under `$ARGV[1]` and man pages under `$ARGV[2]` as `name.section`,
+/// which the short page URLs take.
+const SCRIPT: &str = r#"
+use Pod::Simple::XHTML;
+@H::ISA = ('Pod::Simple::XHTML');
+sub H::resolve_man_page_link {
+    my ($self, $to) = @_;
+    my ($page, $sec) = $to =~ /^([^(]+)(?:\((\S*)\))?/ or return;
+    $page .= ".$sec" if defined $sec && length $sec;
+    $page =~ s/([^\w.~-])/sprintf '%%%02X', ord $1/ge;
+    return $self->man_url_prefix . $page;
+}
+my $p = H->new;
+$p->html_header('');
+$p->html_footer('');
+$p->index(0);
+$p->perldoc_url_prefix($ARGV[1]);
+$p->man_url_prefix($ARGV[2]);
+$p->output_string(\my $out);
+$p->parse_file($ARGV[0]);
+binmode STDOUT, ':utf8';
+print $out;
+"#;
+
+/// Perl's `@INC`, asked once.
+static INC: LazyLock<Vec<PathBuf>> = LazyLock::new(|| {
+    Command::new(&config().pod.perl)
+        .args(["-e", "print join qq(\\0), @INC"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| {
+            String::from_utf8_lossy(&o.stdout)
+                .split('\0')
+                .filter(|d| d.starts_with('/'))
+                .map(PathBuf::from)
+                .collect()
+        })
+        .unwrap_or_default()
+});
+
+/// The file documenting `module`, a `.pod` file before a `.pm` one,
+/// and the core documentation like perlfunc in `pod/`; modules with
+/// their POD stripped, as in Debian's perl-base, do not count.
+/// Blocks.
+fn locate(module: &str) -> Option<PathBuf> {
+    let parts: Vec<_> = module.split("::").collect();
+    if parts
+        .iter()
+        .any(|p| p.is_empty() || !p.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+    {
+        return None;
+    }
+    let path = parts.join("/");
+    INC.iter()
+        .flat_map(|dir| {
+            [
+                dir.join(format!("{path}.pod")),
+                dir.join(format!("{path}.pm")),
+                dir.join(format!("pod/{path}.pod")),
+            ]
+        })
+        .find(|p| {
+            std::fs::read(p)
+                .is_ok_and(|src| src.starts_with(b"=") || src.windows(2).any(|w| w == b"\n="))
+        })
+}
+
+pub async fn pod(Path(module): Path<String>, prefs: Prefs) -> Result<Response, Error> {
+    if !config().pod.enabled {
+        return Err(Error::NotFound);
+    }
+    let src = bg({
+        let module = module.clone();
+        move || locate(&module)
+    })
+    .await
+    .ok_or(Error::NotFound)?;
+    bg(guard::check).await?;
+    let slot = guard::slot().await?;
+    let perl = &config().pod.perl;
+    let out = bg(move || {
+        let _slot = slot;
+        Command::new(perl)
+            .args(["-e", SCRIPT, "--"])
+            .arg(src)
+            .args([with_base("/pod/"), with_base("/")])
+            .output()
+    })
+    .await
+    .map_err(|e| RenderError::Spawn(perl.display().to_string(), e))?;
+    if !out.status.success() || out.stdout.is_empty() {
+        let stderr = String::from_utf8_lossy(&out.stderr).trim_end().to_owned();
+        return Err(RenderError::Failed(perl.display().to_string(), out.status, stderr).into());
+    }
+    let body = String::from_utf8_lossy(&out.stdout);
+    Ok(browse::document(&prefs, &module, html::Place::Other, &body).into_response())
+}
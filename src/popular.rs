@@ -0,0 +1,162 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Which pages are viewed most, listed at `/popular`, given by the
+//! API and warmed first by `handoc warm --top`.  Each process counts
+//! its hits in memory and adds them to the totals on disk, shared by
+//! all connections, every `interval` seconds and once more when the
+//! connection is done.
+
+use std::cmp::Reverse;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use axum::response::{IntoResponse, Response};
+
+use crate::config::config;
+use crate::error::Error;
+use crate::prefs::Prefs;
+use crate::stats::{self, Counts};
+use crate::{bg, browse, escape_html, guard, html, lookup, url_path};
+
+static PENDING: Mutex<Counts> = Mutex::new(Counts::new());
+
+pub fn hit(page: &str) {
+    if config().popular.enabled {
+        *PENDING.lock().unwrap().entry(page.to_owned()).or_default() += 1;
+    }
+}
+
+/// Where the totals are kept, if enabled.
+pub fn path() -> Option<&'static PathBuf> {
+    static PATH: OnceLock<Option<PathBuf>> = OnceLock::new();
+    PATH.get_or_init(|| {
+        let cfg = config();
+        if !cfg.popular.enabled {
+            return None;
+        }
+        cfg.popular.path.clone().or_else(|| {
+            let dir = cfg.cache.dir.as_ref().or(guard::runtime_dir())?;
+            Some(dir.join("popular.json"))
+        })
+    })
+    .as_ref()
+}
+
+pub async fn flush_loop() {
+    if path().is_none() {
+        return;
+    }
+    let secs = config().popular.interval.max(1);
+    let mut interval = tokio::time::interval(Duration::from_secs(secs));
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        flush().await;
+    }
+}
+
+pub async fn flush() {
+    let counts = std::mem::take(&mut *PENDING.lock().unwrap());
+    let Some(path) = path().filter(|_| !counts.is_empty()) else {
+        return;
+    };
+    let res = bg(move || {
+        std::fs::create_dir_all(path.parent().unwrap_or(Path::new(".")))?;
+        stats::merge_json(path, counts)
+    });
+    if let Err(e) = res.await {
+        eprintln!("cannot write {}: {e}", path.display());
+    }
+}
+
+fn stored(path: &Path) -> io::Result<Counts> {
+    let mut f = match std::fs::File::open(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Default::default()),
+        f => f?,
+    };
+    f.lock_shared()?;
+    let mut text = String::new();
+    f.read_to_string(&mut text)?;
+    if text.trim().is_empty() {
+        return Ok(Default::default());
+    }
+    Ok(serde_json::from_str(&text)?)
+}
+
+/// The totals, with what this process has yet to write; none if not
+/// enabled.
+pub fn counts() -> Option<Counts> {
+    let path = path()?;
+    let mut total = stored(path).unwrap_or_else(|e| {
+        eprintln!("cannot read {}: {e}", path.display());
+        Default::default()
+    });
+    for (page, n) in PENDING.lock().unwrap().iter() {
+        *total.entry(page.clone()).or_default() += n;
+    }
+    Some(total)
+}
+
+/// Hits of `page`, as `1/tar.1`.
+pub fn hits(page: &str) -> Option<u64> {
+    counts().map(|c| c.get(page).copied().unwrap_or_default())
+}
+
+/// (section, page file without .gz, hits), most viewed first.
+pub fn ranked() -> Option<Vec<(String, String, u64)>> {
+    let mut pages: Vec<_> = counts()?
+        .into_iter()
+        .filter_map(|(page, n)| {
+            let (sec, file) = page.split_once('/')?;
+            Some((sec.to_owned(), file.to_owned(), n))
+        })
+        .collect();
+    // stable, so ties stay in name order
+    pages.sort_by_key(|&(.., n)| Reverse(n));
+    Some(pages)
+}
+
+/// Up to `limit` of the most viewed pages, leaving out those no
+/// longer found.
+pub fn top(limit: usize, lang: Option<&str>) -> Option<Vec<(String, String, u64)>> {
+    Some(
+        ranked()?
+            .into_iter()
+            .filter(|(sec, file, _)| lookup::resolve(sec, &format!("{file}.gz"), lang).is_some())
+            .take(limit)
+            .collect(),
+    )
+}
+
+/// `/popular`.
+pub async fn page(prefs: Prefs) -> Result<Response, Error> {
+    let lang = prefs.lang.clone();
+    let pages = bg(move || top(config().popular.entries, lang.as_deref()))
+        .await
+        .ok_or(Error::NotFound)?;
+    let mut body = String::from("<h1>Most viewed</h1>\n");
+    if pages.is_empty() {
+        body += "<p>No pages viewed yet.</p>";
+    } else {
+        body += "<ol class=\"popular\">\n";
+        for (sec, file, n) in &pages {
+            let (name, ext) = file.rsplit_once('.').unwrap_or((file, sec));
+            body += &format!(
+                "<li><a href=\"{}\">{}({})</a> {n}</li>\n",
+                url_path(&[sec, &format!("{file}.html")]),
+                escape_html(name),
+                escape_html(ext)
+            );
+        }
+        body += "</ol>";
+    }
+    Ok(browse::document(&prefs, "Most viewed", html::Place::Other, &body).into_response())
+}
@@ -0,0 +1,183 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Reader preferences, kept entirely client side: a `handoc` cookie
+//! holding `key=value&...`, overridden per request by the same keys
+//! in the query string.
+
+use async_trait::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::{header, request::Parts, HeaderMap, Uri};
+use axum::response::{Html, IntoResponse, Redirect, Response};
+
+use crate::error::Error;
+use crate::{base, escape_html, html, template, with_base};
+
+const COOKIE: &str = "handoc";
+
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct Prefs {
+    /// Added to <body> as class `theme-<name>`.
+    pub theme: Option<String>,
+    /// Preferred language of localized man trees, e.g. `de` or `pt_BR`.
+    pub lang: Option<String>,
+    /// Line width of the terminal view.
+    pub width: Option<u16>,
+    /// Show the terminal rendering instead of HTML markup.
+    pub terminal: bool,
+}
+
+impl Prefs {
+    fn set(&mut self, key: &str, value: &str) {
+        let value = (!value.is_empty()).then_some(value);
+        match key {
+            "theme" => self.theme = value.filter(|v| valid(v, "-")).map(str::to_owned),
+            "lang" => self.lang = value.filter(|v| valid(v, "_@.-")).map(str::to_owned),
+            "width" => {
+                self.width = value
+                    .and_then(|v| v.parse().ok())
+                    .filter(|w| (20..=500).contains(w))
+            }
+            "view" => self.terminal = value == Some("terminal"),
+            _ => {}
+        }
+    }
+
+    fn parse(&mut self, s: &str) {
+        for (k, v) in s.split('&').filter_map(|kv| kv.split_once('=')) {
+            self.set(k, v);
+        }
+    }
+
+    fn encode(&self) -> String {
+        let mut ret = Vec::new();
+        if let Some(theme) = &self.theme {
+            ret.push(format!("theme={theme}"));
+        }
+        if let Some(lang) = &self.lang {
+            ret.push(format!("lang={lang}"));
+        }
+        if let Some(width) = self.width {
+            ret.push(format!("width={width}"));
+        }
+        if self.terminal {
+            ret.push("view=terminal".into());
+        }
+        ret.join("&")
+    }
+
+    fn from_cookies(headers: &HeaderMap) -> Self {
+        let mut ret = Self::default();
+        headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .filter_map(|c| c.trim().split_once('='))
+            .filter(|(k, _)| *k == COOKIE)
+            .for_each(|(_, v)| ret.parse(v));
+        ret
+    }
+}
+
+/// Values end up in HTML attributes, paths and cookies, so keep them
+/// to a conservative character set.
+fn valid(v: &str, extra: &str) -> bool {
+    v.len() <= 32
+        && v.chars()
+            .all(|c| c.is_ascii_alphanumeric() || extra.contains(c))
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Prefs {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let mut ret = Self::from_cookies(&parts.headers);
+        if let Some(q) = parts.uri.query() {
+            ret.parse(q);
+        }
+        Ok(ret)
+    }
+}
+
+/// `/prefs?key=value...` stores the given preferences and goes back
+/// to `back`; `/prefs?clear=1` forgets them; plain `/prefs` shows a
+/// form.
+pub async fn handler(uri: Uri, headers: HeaderMap) -> Response {
+    let query = uri.query().unwrap_or_default();
+    let params: Vec<_> = query
+        .split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .collect();
+    let back = params
+        .iter()
+        .find(|(k, _)| *k == "back")
+        .map(|(_, v)| *v)
+        .filter(|v| v.starts_with('/') && !v.starts_with("//") && valid_path(v))
+        .map_or_else(|| with_base("/prefs"), str::to_owned);
+    let path = if base().is_empty() { "/" } else { base() };
+    let cookie = if params.iter().any(|(k, _)| *k == "clear") {
+        format!("{COOKIE}=; Path={path}; Max-Age=0; SameSite=Lax")
+    } else if params.iter().any(|(k, _)| k != &"back") {
+        let mut prefs = Prefs::from_cookies(&headers);
+        prefs.parse(query);
+        format!(
+            "{COOKIE}={}; Path={path}; Max-Age=31536000; SameSite=Lax",
+            prefs.encode()
+        )
+    } else {
+        return form(&Prefs::from_cookies(&headers)).into_response();
+    };
+    match cookie.parse::<axum::http::HeaderValue>() {
+        Ok(cookie) => ([(header::SET_COOKIE, cookie)], Redirect::to(&back)).into_response(),
+        Err(_) => Error::BadRequest("bad preferences").into_response(),
+    }
+}
+
+fn valid_path(v: &str) -> bool {
+    v.chars()
+        .all(|c| c.is_ascii_graphic() && !matches!(c, '"' | '<' | '>' | '\\'))
+}
+
+fn form(prefs: &Prefs) -> Html<String> {
+    let selected = |on: bool| if on { " selected" } else { "" };
+    let theme = prefs.theme.as_deref().unwrap_or_default();
+    let action = with_base("/prefs");
+    let body = format!(
+        r#"<h1>Preferences</h1>
+<form action="{action}" method="get">
+<p><label>Theme <select name="theme">
+<option value=""{}>default</option>
+<option value="light"{}>light</option>
+<option value="dark"{}>dark</option>
+</select></label></p>
+<p><label>Language <input name="lang" value="{}" placeholder="e.g. de, pt_BR"/></label></p>
+<p><label>View <select name="view">
+<option value="html"{}>HTML</option>
+<option value="terminal"{}>terminal</option>
+</select></label></p>
+<p><label>Terminal width <input name="width" type="number" min="20" max="500" value="{}"/></label></p>
+<p><button type="submit">Save</button> <a href="{action}?clear=1">Clear</a></p>
+</form>"#,
+        selected(theme.is_empty()),
+        selected(theme == "light"),
+        selected(theme == "dark"),
+        escape_html(prefs.lang.as_deref().unwrap_or_default()),
+        selected(!prefs.terminal),
+        selected(prefs.terminal),
+        prefs.width.map(|w| w.to_string()).unwrap_or_default(),
+    );
+    let page = html::Page {
+        title: "Preferences".into(),
+        description: None,
+        place: html::Place::Other,
+    };
+    let (pre, post) = template::wrap(prefs);
+    Html(html::rewrite(&(pre + &body + &post), page))
+}
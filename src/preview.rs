@@ -0,0 +1,435 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! `POST /preview`: a man or mdoc source in the request body, rendered
+//! as an installed page would be, for authors to check pages before
+//! installing them.  Sources are written to a scratch directory for
+//! the renderer, named after their `.TH` or `.Dt` title so the page
+//! header shows as it will, and never cached.
+//!
+//! `handoc preview FILE` serves one such source from disk on
+//! localhost instead, rendered afresh each time and reloading in the
+//! browser whenever it is saved, for writing pages.
+
+use std::convert::Infallible;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+use axum::body::{Body, Bytes};
+use axum::http::{header, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use hyper_util::rt::{TokioIo, TokioTimer};
+use tokio::sync::watch;
+
+use crate::config::config;
+use crate::error::{Context, Error};
+use crate::prefs::Prefs;
+use crate::{access, bg, cache, guard, index, lint, lookup, query_param, render, Format};
+
+/// A directory removed along with everything in it once dropped.
+struct Scratch(PathBuf);
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+pub async fn preview(prefs: Prefs, body: Body) -> Result<Response, Error> {
+    let preview = &config().preview;
+    if !preview.enabled {
+        return Err(Error::NotFound);
+    }
+    let src = axum::body::to_bytes(body, preview.max_source)
+        .await
+        .map_err(|_| Error::TooLarge)?;
+    if refused(&String::from_utf8_lossy(&src)) {
+        return Err(Error::BadRequest(
+            "sources may only use the requests of man and mdoc pages",
+        ));
+    }
+    Ok(page(show(src, prefs).await?))
+}
+
+/// What sources may use: requests setting out text, and the macros of
+/// man(7) and mdoc(7).  None read other files, or change the control
+/// or escape characters or names of requests to hide one that does.
+const ALLOWED: &[&str] = &[
+    // roff
+    ".", "ad", "af", "am", "as", "bp", "br", "ce", "cu", "de", "de1", "ds", "ds1", "el", "ev", "fc",
+    "fi", "ft", "fam", "hc", "hw", "hy", "ie", "if", "ig", "in", "ll", "ls", "mk", "na", "ne",
+    "nf", "nh", "nr", "ns", "pc", "po", "ps", "rm", "rr", "rs", "rt", "sp", "ss", "ta", "tc", "ti",
+    "tl", "tm", "tr", "ul", "vs", "while", "EQ", "EN", "TS", "TE", "T&", // man(7)
+    "TH", "SH", "SS", "PP", "LP", "P", "HP", "TP", "TQ", "IP", "RS", "RE", "B", "I", "BI", "BR",
+    "IB", "IR", "RB", "RI", "SB", "SM", "SY", "YS", "OP", "EX", "EE", "UR", "UE", "MT", "ME", "MR",
+    "PD", "AT", "UC", "DT", // mdoc(7)
+    "Dd", "Dt", "Os", "Sh", "Ss", "Pp", "Lp", "Bl", "El", "It", "Bd", "Ed", "Bk", "Ek", "Bf", "Ef",
+    "D1", "Dl", "Nm", "Nd", "Op", "Oo", "Oc", "Fl", "Ar", "Cm", "Ic", "Sy", "Em", "Li", "No", "Ns",
+    "Pa", "Va", "Vt", "Ev", "Er", "Fn", "Fa", "Ft", "Fd", "Fo", "Fc", "In", "Cd", "Ad", "Xr", "Sx",
+    "Rs", "Re", "%A", "%B", "%C", "%D", "%I", "%J", "%N", "%O", "%P", "%Q", "%R", "%T", "%U", "%V",
+    "Lk", "Mt", "An", "Aq", "Ao", "Ac", "Bq", "Bo", "Bc", "Brq", "Bro", "Brc", "Dq", "Do", "Dc",
+    "Pq", "Po", "Pc", "Qq", "Qo", "Qc", "Sq", "So", "Sc", "Eo", "Ec", "Ql", "Dv", "Ex", "Rv", "At",
+    "Bsx", "Bx", "Dx", "Fx", "Nx", "Ox", "St", "Ux", "Xo", "Xc", "Sm", "Tn", "Ms", "Ud", "Lb",
+    "Ot", "Hf", "Fr", "Es", "En", "Ta", "Db", "Pf", "Ap",
+];
+
+/// Whether `src` uses a request not allowed, nor a macro of its own.
+fn refused(src: &str) -> bool {
+    let mut defined = Vec::new();
+    src.lines().any(|line| {
+        let Some(req) = line.strip_prefix(['.', '\'']) else {
+            return false;
+        };
+        let req = req.split("\\\"").next().unwrap_or_default();
+        !allowed(req, &mut defined)
+    })
+}
+
+/// Whether request line `req`, past its control character, is
+/// allowed, given the macros `defined` so far.
+fn allowed<'a>(req: &'a str, defined: &mut Vec<&'a str>) -> bool {
+    let req = req.trim_start();
+    let req = req.strip_prefix("do ").map_or(req, str::trim_start);
+    let end = req
+        .find(|c: char| c.is_whitespace() || c == '\\')
+        .unwrap_or(req.len());
+    let (name, args) = req.split_at(end);
+    // names made of strings and escapes could be any
+    if args.starts_with('\\') && !args.starts_with("\\{") && !args.starts_with("\\}") {
+        return false;
+    }
+    if !name.is_empty() && !ALLOWED.contains(&name) && !defined.contains(&name) {
+        return false;
+    }
+    match name {
+        // not as replacing requests, all lowercase
+        "de" | "de1" | "am" => {
+            let new = args.split_whitespace().next().unwrap_or_default();
+            if !new.chars().all(|c| c.is_ascii_lowercase()) {
+                defined.push(new);
+            }
+            true
+        }
+        // the rest of the line may be a request too, after the
+        // condition; a quote with another in it compares strings
+        "if" | "ie" | "el" | "while" => args.match_indices(['.', '\'']).all(|(i, _)| {
+            let (before, rest) = args.split_at(i);
+            let token = rest.split(char::is_whitespace).next().unwrap_or_default();
+            !(before.ends_with(char::is_whitespace) || before.ends_with("\\{"))
+                || (rest.starts_with('\'') && token[1..].contains('\''))
+                || allowed(&rest[1..], defined)
+        }),
+        _ => true,
+    }
+}
+
+/// `src` rendered as a page.
+async fn show(src: Bytes, prefs: Prefs) -> Result<Bytes, Error> {
+    bg(guard::check).await?;
+    let (scratch, path) = bg(move || write(&src)).await?;
+    let key = cache::Key {
+        path,
+        mtime: SystemTime::now(),
+        format: Format::Html,
+        prefs,
+    };
+    let slot = guard::slot().await?;
+    let issues = lint::check(&key.path, slot).await.unwrap_or_default();
+    let slot = guard::slot().await?;
+    let limit = Duration::from_secs(config().preview.timeout);
+    let doc = render::once(key, slot, limit).await?;
+    drop(scratch);
+    if issues.is_empty() {
+        return Ok(doc);
+    }
+    // the problems go after the page header
+    let doc = String::from_utf8_lossy(&doc);
+    let Some(at) = doc.find("</nav>\n").map(|i| i + 7) else {
+        return Ok(doc.into_owned().into());
+    };
+    let report = format!(
+        "<details class=\"lint\" open>\n<summary>Lint</summary>\n{}</details>\n",
+        lint::table(&issues)
+    );
+    Ok(format!("{}{report}{}", &doc[..at], &doc[at..]).into())
+}
+
+fn page(doc: impl Into<Body>) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        doc.into(),
+    )
+        .into_response()
+}
+
+/// `src` gzipped into a fresh scratch directory, as the file of the
+/// page it titles itself.  Blocks.
+fn write(src: &[u8]) -> Result<(Scratch, PathBuf), Error> {
+    static SEQ: AtomicUsize = AtomicUsize::new(0);
+    let top = guard::runtime_dir().ok_or(std::io::Error::other("no runtime directory"))?;
+    let dir = top.join("preview").join(format!(
+        "{}-{}",
+        std::process::id(),
+        SEQ.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::create_dir_all(&dir).at("create", &dir)?;
+    let scratch = Scratch(dir);
+    let (name, section) = title(&String::from_utf8_lossy(src));
+    let path = scratch.0.join(format!("{name}.{section}.gz"));
+    let f = std::fs::File::create(&path).at("create", &path)?;
+    let mut gz = flate2::write::GzEncoder::new(f, flate2::Compression::fast());
+    gz.write_all(src)
+        .and_then(|()| gz.finish().map(drop))
+        .at("write", &path)?;
+    Ok((scratch, path))
+}
+
+/// Name and section from the `.TH` or `.Dt` line, lowercased for
+/// mdoc's capitals, else `preview(1)`.
+fn title(src: &str) -> (String, String) {
+    src.lines()
+        .find_map(|line| {
+            let line = line.strip_prefix('.')?.trim_start();
+            let mut args = line
+                .strip_prefix("TH")
+                .or_else(|| line.strip_prefix("Dt"))?
+                .split_whitespace()
+                .map(|a| a.trim_matches('"'));
+            let name = args.next()?.to_lowercase();
+            let section = args.next()?.to_lowercase();
+            (lookup::valid_component(&name) && lookup::valid_section(&section))
+                .then_some((name, section))
+        })
+        .unwrap_or_else(|| ("preview".to_owned(), "1".to_owned()))
+}
+
+/// Generation of the watched file, bumped whenever it is saved.
+static SAVED: OnceLock<watch::Sender<u64>> = OnceLock::new();
+
+/// `handoc preview [--port N] FILE`: serves `FILE` at `/` of
+/// localhost, and the rest of the site around it, reloading open
+/// pages whenever the file is saved.
+pub fn run(args: &[String]) -> ExitCode {
+    let mut port = 8080;
+    let mut file = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => match args.next().and_then(|v| v.parse().ok()) {
+                Some(v) => port = v,
+                None => return usage(),
+            },
+            _ if file.is_none() => file = Some(PathBuf::from(arg)),
+            _ => return usage(),
+        }
+    }
+    let Some(file) = file else {
+        return usage();
+    };
+    SAVED.get_or_init(|| watch::channel(0).0);
+    if let Err(e) = watcher(&file) {
+        eprintln!("preview: cannot watch {}: {e}", file.display());
+        return ExitCode::FAILURE;
+    }
+    let app = Router::new()
+        .route("/", get(move |prefs: Prefs| watched(file.clone(), prefs)))
+        .route("/events", get(events))
+        .fallback_service(crate::routes());
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    rt.block_on(async move {
+        let listener = match tokio::net::TcpListener::bind(("127.0.0.1", port)).await {
+            Ok(l) => l,
+            Err(e) => {
+                eprintln!("preview: cannot listen on port {port}: {e}");
+                return ExitCode::FAILURE;
+            }
+        };
+        eprintln!("previewing at http://127.0.0.1:{port}/");
+        tokio::task::spawn_blocking(index::prebuild);
+        tokio::task::spawn_blocking(render::caps);
+        loop {
+            let Ok((sock, peer)) = listener.accept().await else {
+                continue;
+            };
+            let app = access::wrap(app.clone(), peer);
+            let hs = hyper_util::service::TowerToHyperService::new(app.into_service());
+            tokio::spawn(
+                hyper::server::conn::http1::Builder::new()
+                    .timer(TokioTimer::new())
+                    .serve_connection(TokioIo::new(sock), hs),
+            );
+        }
+    })
+}
+
+fn usage() -> ExitCode {
+    eprintln!("usage: handoc preview [--port N] FILE");
+    ExitCode::from(2)
+}
+
+/// The watched file as a page, which reloads once it is saved again.
+async fn watched(file: PathBuf, prefs: Prefs) -> Result<Response, Error> {
+    let generation = *SAVED.get().unwrap().borrow();
+    let src = bg(move || std::fs::read(&file).at("read", &file)).await?;
+    let doc = show(src.into(), prefs).await?;
+    let doc = String::from_utf8_lossy(&doc);
+    let script = format!(
+        "<script>new EventSource(\"/events?since={generation}\").onmessage = () => location.reload();</script>\n"
+    );
+    let at = doc.rfind("</body>").unwrap_or(doc.len());
+    Ok(page(format!("{}{script}{}", &doc[..at], &doc[at..])))
+}
+
+/// Server-sent events, one each time the file is saved after
+/// generation `since`.
+async fn events(uri: Uri) -> Response {
+    let saved = SAVED.get().unwrap();
+    let mut rx = saved.subscribe();
+    let since = query_param(&uri, "since").and_then(|s| s.parse().ok());
+    if since.is_some_and(|s: u64| s != *rx.borrow()) {
+        rx.mark_changed();
+    }
+    let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.changed().await.ok()?;
+        Some((
+            Ok::<_, Infallible>(Bytes::from_static(b"data: saved\n\n")),
+            rx,
+        ))
+    });
+    (
+        [
+            (header::CONTENT_TYPE, "text/event-stream"),
+            (header::CACHE_CONTROL, "no-store"),
+        ],
+        Body::from_stream(stream),
+    )
+        .into_response()
+}
+
+/// Watch the directory of `file` with inotify, for editors saving
+/// by renaming a new file over it, bumping `SAVED` when it changes.
+#[cfg(target_os = "linux")]
+fn watcher(file: &std::path::Path) -> std::io::Result<()> {
+    use std::ffi::CString;
+    use std::io::Read;
+    use std::os::fd::FromRawFd;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = match file.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => std::path::Path::new("."),
+    };
+    let name = file
+        .file_name()
+        .ok_or(std::io::ErrorKind::InvalidInput)?
+        .as_bytes()
+        .to_owned();
+    let dir = CString::new(dir.as_os_str().as_bytes())?;
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let mut events = unsafe { std::fs::File::from_raw_fd(fd) };
+    let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO;
+    if unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), mask) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    std::thread::spawn(move || {
+        let header = std::mem::size_of::<libc::inotify_event>();
+        let mut buf = [0; 4096];
+        while let Ok(n) = events.read(&mut buf) {
+            let mut hit = false;
+            let mut i = 0;
+            while i + header <= n {
+                let ev: libc::inotify_event =
+                    unsafe { std::ptr::read_unaligned(buf[i..].as_ptr().cast()) };
+                let end = (i + header + ev.len as usize).min(n);
+                let evname = buf[i + header..end].split(|&b| b == 0).next();
+                hit |= evname == Some(&name[..]);
+                i = end;
+            }
+            if hit {
+                SAVED.get().unwrap().send_modify(|g| *g += 1);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Elsewhere, poll the modification time of `file`.
+#[cfg(not(target_os = "linux"))]
+fn watcher(file: &std::path::Path) -> std::io::Result<()> {
+    let file = file.to_owned();
+    let mtime = move || std::fs::metadata(&file).and_then(|m| m.modified()).ok();
+    let mut last = mtime();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_millis(500));
+        let now = mtime();
+        if now != last {
+            last = now;
+            SAVED.get().unwrap().send_modify(|g| *g += 1);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reading_other_files() {
+        for src in [
+            ".so /etc/passwd",
+            ".  so man1/ls.1",
+            "'mso an.tmac",
+            ".do so x",
+            ".cf /etc/shadow",
+            ".als inc so",
+            ".rn so inc",
+            ".cc #",
+            ".\\*x /etc/passwd",
+            ".ds x so\n.\\*x /etc/passwd",
+            ".ec @\n.ds x so\n.@*x /etc/passwd",
+            ".eo\n.ds x so\n.\\*x /etc/passwd",
+            ".TH X 1\n.SH NAME\n.nx /etc/passwd\n",
+            ".if n .so /etc/passwd",
+            ".ie t \\{\\\n.el .if n 'so /etc/passwd",
+            ".if n \\{.so /etc/passwd",
+            ".de so\n..\n.so /etc/passwd",
+            ".sp\n.soelim\n",
+        ] {
+            assert!(refused(src), "{src}");
+        }
+        for src in [
+            ".TH SO 1\n.SH NAME\nso \\- not a request\n",
+            "text .so inline\n",
+            ".\\\" .so in a comment\n.PP\n",
+            ".Dd January 1, 2024\n.Dt X 1\n.Sh NAME\n.Nm x\n.So quoted Sc\n",
+            // as from pod2man
+            ".de Vb\n.ft CW\n.nf\n.ne \\\\$1\n..\n.Vb 1\n",
+            ".ie \\n(.g .ds Aq \\(aq\n.el       .ds Aq '\n",
+            ".if (\\n(rF:(\\n(.g==0)) \\{\\\n.    if \\nF \\{\\\n.        de IX\n..\n.    \\}\n.\\}\n.IX Title \"X 1\"\n",
+            ".ie '\\*(.T'utf8' \\{\\\n.\\}\n",
+        ] {
+            assert!(!refused(src), "{src}");
+        }
+    }
+}
@@ -0,0 +1,87 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Giving up root: when started as root, say to listen on port 80,
+//! change root and working directory as configured and become the
+//! configured user, before anything is read on behalf of clients.
+//! Paths in the config are then inside the new root.
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+
+use crate::config::config;
+
+fn check(ret: libc::c_int, what: &str) -> io::Result<()> {
+    if ret < 0 {
+        let e = io::Error::last_os_error();
+        return Err(io::Error::new(e.kind(), format!("{what}: {e}")));
+    }
+    Ok(())
+}
+
+fn c_string(s: impl AsRef<[u8]>) -> io::Result<CString> {
+    Ok(CString::new(s.as_ref())?)
+}
+
+/// The uid and gid of `user`, or its uid and `group`.  Reads
+/// /etc/passwd and /etc/group, so must come before changing root.
+fn ids(user: &str, group: Option<&str>) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let not_found = |what: &str| io::Error::new(io::ErrorKind::NotFound, format!("no {what}"));
+    let name = c_string(user)?;
+    let pw = unsafe { libc::getpwnam(name.as_ptr()) };
+    if pw.is_null() {
+        return Err(not_found(&format!("user {user}")));
+    }
+    let (uid, mut gid) = unsafe { ((*pw).pw_uid, (*pw).pw_gid) };
+    if let Some(group) = group {
+        let name = c_string(group)?;
+        let gr = unsafe { libc::getgrnam(name.as_ptr()) };
+        if gr.is_null() {
+            return Err(not_found(&format!("group {group}")));
+        }
+        gid = unsafe { (*gr).gr_gid };
+    }
+    Ok((uid, gid))
+}
+
+/// Lower privileges as configured, if root; only then is there
+/// anything to give up.  This happens before the process has any
+/// other threads.
+pub fn lower() -> io::Result<()> {
+    let cfg = &config().privileges;
+    if unsafe { libc::geteuid() } != 0 {
+        return Ok(());
+    }
+    let ids = match &cfg.user {
+        Some(user) => Some(ids(user, cfg.group.as_deref())?),
+        None => None,
+    };
+    if let Some(dir) = &cfg.chroot {
+        let c = c_string(dir.as_os_str().as_bytes())?;
+        check(
+            unsafe { libc::chroot(c.as_ptr()) },
+            &format!("chroot {}", dir.display()),
+        )?;
+        std::env::set_current_dir("/")?;
+    }
+    if let Some(dir) = &cfg.directory {
+        std::env::set_current_dir(dir)
+            .map_err(|e| io::Error::new(e.kind(), format!("cd {}: {e}", dir.display())))?;
+    }
+    if let Some((uid, gid)) = ids {
+        // the supplementary groups of root go too
+        check(unsafe { libc::setgroups(1, &gid) }, "setgroups")?;
+        check(unsafe { libc::setgid(gid) }, "setgid")?;
+        check(unsafe { libc::setuid(uid) }, "setuid")?;
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(io::Error::other("could regain root after setuid"));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,144 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Per-client rate limits: a token bucket per address, or per /64 for
+//! IPv6, refilled at `rate` requests a second up to `burst`, with 429
+//! once empty.  Like render slots the buckets are shared by every
+//! handoc process, as a locked file each in the runtime directory,
+//! removed once left long enough to be full again.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::extract::Request;
+use axum::http::{header, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+
+use crate::access::Client;
+use crate::config::config;
+use crate::{bg, guard};
+
+pub async fn layer(req: Request, next: Next) -> Response {
+    let limit = &config().rate_limit;
+    let Some(&Client(client)) = req.extensions().get::<Client>() else {
+        return next.run(req).await;
+    };
+    if limit.rate <= 0.0 || limit.exempt.contains(&client) {
+        return next.run(req).await;
+    }
+    match bg(move || take(client)).await {
+        Ok(None) => next.run(req).await,
+        Ok(Some(wait)) => {
+            eprintln!("{client}: rate limited");
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, wait.to_string())],
+            )
+                .into_response()
+        }
+        Err(e) => {
+            // not worth failing requests over
+            eprintln!("rate limit: {e}");
+            next.run(req).await
+        }
+    }
+}
+
+/// Take a token from the bucket of `client`, or say how many seconds
+/// until there is one.  Blocks.
+fn take(client: IpAddr) -> std::io::Result<Option<u64>> {
+    let limit = &config().rate_limit;
+    let Some(dir) = guard::runtime_dir() else {
+        return Ok(None);
+    };
+    let dir = dir.join("rate");
+    std::fs::create_dir_all(&dir)?;
+    if let Err(e) = sweep(&dir) {
+        eprintln!("cannot clean up {}: {e}", dir.display());
+    }
+    let mut f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dir.join(bucket(client)))?;
+    File::lock(&f)?;
+    let mut text = String::new();
+    f.read_to_string(&mut text)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_secs_f64());
+    // "<tokens> <unix time>"
+    let mut fields = text.split_whitespace().map(|f| f.parse::<f64>().ok());
+    let (tokens, then) = match (fields.next().flatten(), fields.next().flatten()) {
+        (Some(tokens), Some(then)) => (tokens, then),
+        _ => (limit.burst, now),
+    };
+    let mut tokens = (tokens + (now - then).max(0.0) * limit.rate).min(limit.burst);
+    let wait = if tokens >= 1.0 {
+        tokens -= 1.0;
+        None
+    } else {
+        Some(((1.0 - tokens) / limit.rate).ceil() as u64)
+    };
+    f.rewind()?;
+    f.set_len(0)?;
+    write!(f, "{tokens} {now}")?;
+    Ok(wait)
+}
+
+/// The bucket `client` draws from: a host commonly has a whole IPv6
+/// /64 to itself.
+fn bucket(client: IpAddr) -> String {
+    match client.to_canonical() {
+        IpAddr::V6(a) => format!("{}-64", Ipv6Addr::from(a.to_bits() & !0 << 64)),
+        a => a.to_string(),
+    }
+}
+
+/// Remove the buckets left for as long as any takes to fill up, as
+/// good as none by then; at most once in that time.  Blocks.
+fn sweep(dir: &Path) -> std::io::Result<()> {
+    let limit = &config().rate_limit;
+    let full = Duration::from_secs_f64((limit.burst / limit.rate).max(60.0));
+    let left = |p: &Path| {
+        let modified = std::fs::metadata(p).and_then(|m| m.modified()).ok()?;
+        modified.elapsed().ok()
+    };
+    let mark = dir.join(".swept");
+    if left(&mark).is_some_and(|d| d < full) {
+        return Ok(());
+    }
+    File::create(&mark)?.set_modified(SystemTime::now())?;
+    for ent in std::fs::read_dir(dir)?.flatten() {
+        let p = ent.path();
+        if p != mark && left(&p).is_some_and(|d| d >= full) {
+            std::fs::remove_file(p).ok();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        let bucket = |a: &str| bucket(a.parse().unwrap());
+        assert_eq!(bucket("192.0.2.1"), "192.0.2.1");
+        assert_eq!(bucket("::ffff:192.0.2.1"), "192.0.2.1");
+        assert_eq!(bucket("2001:db8:1:2:3:4:5:6"), "2001:db8:1:2::-64");
+        assert_eq!(bucket("2001:db8:1:2::ffff"), bucket("2001:db8:1:2:3:4:5:6"));
+        assert_ne!(bucket("2001:db8:1:3::1"), bucket("2001:db8:1:2::1"));
+    }
+}
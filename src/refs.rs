@@ -0,0 +1,229 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Per-root reverse index of cross references: for each page, the
+//! pages naming it under SEE ALSO.  Building one reads every source
+//! in the root, so it is kept on disk, in `cache.dir` or else the
+//! runtime directory, and built again once the page index changes.
+//! One process at a time builds it, in the background while pages
+//! are served without it.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::thread::JoinHandle;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::config;
+use crate::source::source;
+use crate::{guard, index, lookup, meta};
+
+/// (name, section).
+type Page = (String, String);
+
+/// Pages to the pages referring to them.
+pub type Refs = HashMap<Page, Vec<Page>>;
+
+/// Reverse indexes by root, with the stamp of the index they were
+/// built from.
+type Built = HashMap<PathBuf, (u64, Arc<Refs>)>;
+
+static REFS: LazyLock<Mutex<Built>> = LazyLock::new(Default::default);
+
+/// Builds begun in the background, by root.
+static BUILDS: Mutex<Vec<(PathBuf, JoinHandle<()>)>> = Mutex::new(Vec::new());
+
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    stamp: u64,
+    refs: Vec<(Page, Vec<Page>)>,
+}
+
+/// The reverse index of `root`, loaded or built as needed.  Unless
+/// `wait`, none while it is built in the background instead.  May
+/// block, for long if `wait`.
+pub fn get(root: &Path, wait: bool) -> Option<Arc<Refs>> {
+    let stamp = index::get(root).stamp();
+    let mut all = REFS.lock().unwrap();
+    if let Some((built, refs)) = all.get(root) {
+        if *built == stamp {
+            return Some(refs.clone());
+        }
+    }
+    if let Some(refs) = load(root, stamp) {
+        let refs = Arc::new(refs);
+        all.insert(root.to_owned(), (stamp, refs.clone()));
+        return Some(refs);
+    }
+    drop(all);
+    if !wait {
+        build_later(root, stamp);
+        return None;
+    }
+    let refs = Arc::new(rebuild(root, stamp, true)?);
+    REFS.lock()
+        .unwrap()
+        .insert(root.to_owned(), (stamp, refs.clone()));
+    Some(refs)
+}
+
+/// Whether the reverse index of `root` is there for the page index
+/// of `stamp`, without loading it.  May block.
+pub fn built(root: &Path, stamp: u64) -> bool {
+    let held = REFS.lock().unwrap().get(root).map(|(built, _)| *built);
+    held == Some(stamp) || stored(root, stamp).is_some_and(|p| p.exists())
+}
+
+/// Build the reverse index of `root` and store it, one process at a
+/// time: the others `wait` for it and take what it stored, or leave
+/// it be.
+fn rebuild(root: &Path, stamp: u64, wait: bool) -> Option<Refs> {
+    let lock = disk_path(root, "lock").map(File::create);
+    match &lock {
+        Some(Ok(f)) if wait => f.lock().ok()?,
+        Some(Ok(f)) => f.try_lock().ok()?,
+        Some(Err(e)) => eprintln!("cannot lock the references of {}: {e}", root.display()),
+        None => {}
+    }
+    if let Some(refs) = load(root, stamp) {
+        return Some(refs);
+    }
+    let refs = build(root);
+    if let Err(e) = store(root, stamp, &refs) {
+        eprintln!("cannot store references of {}: {e}", root.display());
+    }
+    Some(refs)
+}
+
+/// Build the reverse index of `root` for later pages, unless already
+/// at it.
+fn build_later(root: &Path, stamp: u64) {
+    let mut builds = BUILDS.lock().unwrap();
+    builds.retain(|(_, t)| !t.is_finished());
+    if builds.iter().any(|(r, _)| r == root) {
+        return;
+    }
+    let t = std::thread::spawn({
+        let root = root.to_owned();
+        move || {
+            if let Some(refs) = rebuild(&root, stamp, false) {
+                REFS.lock().unwrap().insert(root, (stamp, Arc::new(refs)));
+            }
+        }
+    });
+    builds.push((root.to_owned(), t));
+}
+
+/// Wait for builds begun in the background, which would be lost
+/// along with the process.  Blocks.
+pub fn finish() {
+    let builds = std::mem::take(&mut *BUILDS.lock().unwrap());
+    for (_, t) in builds {
+        t.join().ok();
+    }
+}
+
+fn disk_path(root: &Path, ext: &str) -> Option<PathBuf> {
+    let dir = config().cache.dir.as_ref().or(guard::runtime_dir())?;
+    let mut h = std::hash::DefaultHasher::new();
+    root.hash(&mut h);
+    Some(dir.join(format!("refs-{:016x}.{ext}", h.finish())))
+}
+
+/// Named by the stamp of the page index too, so whether there is one
+/// takes no reading.
+fn stored(root: &Path, stamp: u64) -> Option<PathBuf> {
+    disk_path(root, &format!("{stamp:016x}.json"))
+}
+
+fn load(root: &Path, stamp: u64) -> Option<Refs> {
+    let stored: Stored = serde_json::from_slice(&std::fs::read(stored(root, stamp)?).ok()?).ok()?;
+    (stored.stamp == stamp).then(|| stored.refs.into_iter().collect())
+}
+
+/// Store `refs` in place of those of other stamps.
+fn store(root: &Path, stamp: u64, refs: &Refs) -> std::io::Result<()> {
+    let Some(path) = stored(root, stamp) else {
+        return Ok(());
+    };
+    let stored = Stored {
+        stamp,
+        refs: refs.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+    };
+    // write aside and rename, as other processes may be reading
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    std::fs::write(&tmp, serde_json::to_vec(&stored)?)?;
+    std::fs::rename(tmp, &path)?;
+    let name = path
+        .file_name()
+        .and_then(|f| f.to_str())
+        .unwrap_or_default();
+    let (prefix, _) = name.split_once('.').unwrap_or_default();
+    for ent in std::fs::read_dir(path.parent().unwrap())?.flatten() {
+        let old = ent.file_name();
+        let old = old.to_str().unwrap_or_default();
+        if old != name && old.starts_with(prefix) && old.ends_with(".json") {
+            std::fs::remove_file(ent.path()).ok();
+        }
+    }
+    Ok(())
+}
+
+fn build(root: &Path) -> Refs {
+    let mut refs = Refs::new();
+    for dir in lookup::sections(root) {
+        let dir = root.join(format!("man{dir}"));
+        let Ok(files) = source().list(&dir) else {
+            continue;
+        };
+        for file in files {
+            let Some((name, sec)) = file
+                .to_str()
+                .and_then(|f| f.strip_suffix(".gz"))
+                .and_then(|f| f.rsplit_once('.'))
+                .filter(|(_, sec)| lookup::valid_section(sec))
+            else {
+                continue;
+            };
+            let Ok(info) = meta::read(&dir.join(&file)) else {
+                continue;
+            };
+            let from = (name.to_owned(), sec.to_owned());
+            for to in info.see_also {
+                if to != from {
+                    refs.entry(to).or_default().push(from.clone());
+                }
+            }
+        }
+    }
+    for from in refs.values_mut() {
+        from.sort_unstable();
+        from.dedup();
+    }
+    refs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn built_once_waited_for() {
+        crate::testing::setup();
+        let root = Path::new("/man");
+        let stamp = index::get(root).stamp();
+        let refs = get(root, true).unwrap();
+        assert!(built(root, stamp));
+        let ls = ("ls".to_owned(), "1".to_owned());
+        assert_eq!(refs[&ls], [("dir".to_owned(), "1".to_owned())]);
+        assert!(get(root, false).is_some());
+    }
+}
@@ -0,0 +1,1012 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Running mandoc and streaming its output, post-processed line by
+//! line, into the response body.  A page is cached once its rendering
+//! has completed successfully.
+
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use axum::body::{Body, Bytes};
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc;
+
+use crate::config::{config, RendererKind};
+use crate::prefs::Prefs;
+use crate::source::source;
+use crate::{
+    bg, cache, encoding, escape_html, guard, html, lookup, meta, package, scope, template, tldr,
+    trace, Format, RENDER_VERSION,
+};
+
+#[derive(Debug)]
+pub enum RenderError {
+    Io(std::io::Error),
+    /// The renderer could not be started at all.
+    Spawn(String, std::io::Error),
+    /// It ran but failed, with this on stderr.
+    Failed(String, std::process::ExitStatus, String),
+    Timeout,
+    TooLarge,
+    /// Post-processing the HTML failed.
+    Rewrite(lol_html::errors::RewritingError),
+    /// Nobody is reading the output any more.
+    Gone,
+}
+
+impl From<std::io::Error> for RenderError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<lol_html::errors::RewritingError> for RenderError {
+    fn from(e: lol_html::errors::RewritingError) -> Self {
+        Self::Rewrite(e)
+    }
+}
+
+impl std::fmt::Display for RenderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RenderError::Io(e) => e.fmt(f),
+            RenderError::Spawn(prog, e) => write!(f, "cannot run {prog}: {e}"),
+            RenderError::Failed(prog, status, stderr) => {
+                write!(f, "{prog} failed ({status})")?;
+                if !stderr.is_empty() {
+                    write!(f, ":\n{stderr}")?;
+                }
+                Ok(())
+            }
+            RenderError::Timeout => f.write_str("rendering took too long"),
+            RenderError::TooLarge => f.write_str("rendered page too large"),
+            RenderError::Rewrite(e) => write!(f, "cannot rewrite page: {e}"),
+            RenderError::Gone => f.write_str("client went away"),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+/// Most of renderer stderr worth keeping.
+const MAX_STDERR: u64 = 64 << 10;
+
+/// Output is passed on in pieces of about this size.
+const CHUNK: usize = 16 << 10;
+
+/// Pieces of output waiting for the reader, beyond which the rest is
+/// spilled to disk.
+const QUEUED: usize = 8;
+
+/// Output for the reader: a piece of it, or the rest, from the file
+/// it spilled to.
+enum Piece {
+    Chunk(Bytes),
+    Spilled(std::fs::File),
+}
+
+type Pieces = mpsc::Receiver<Result<Piece, RenderError>>;
+
+/// Render the page of `key`, holding `slot` until rendered.
+/// Resolves to the body once the first output is there, so that
+/// early failures still get a proper error response; later ones cut
+/// the body short.
+pub async fn stream(key: cache::Key, slot: guard::Slot) -> Result<Body, RenderError> {
+    let mut rest = Box::pin(chunks(spawn(key, Some(slot), timeout(), true)));
+    let first = rest.next().await.unwrap_or(Err(RenderError::Gone))?;
+    Ok(Body::from_stream(
+        futures_util::stream::once(async { Ok(first) }).chain(rest),
+    ))
+}
+
+/// The output in `rx` as it comes, then from any file it spilled to.
+fn chunks(rx: Pieces) -> impl futures_util::Stream<Item = Result<Bytes, RenderError>> + Send {
+    let state: (Pieces, Option<std::fs::File>) = (rx, None);
+    futures_util::stream::unfold(state, |(mut rx, spill)| async move {
+        let Some(mut f) = spill else {
+            return match rx.recv().await? {
+                Ok(Piece::Chunk(chunk)) => Some((Ok(chunk), (rx, None))),
+                Ok(Piece::Spilled(f)) => Some((Ok(Bytes::new()), (rx, Some(f)))),
+                Err(e) => Some((Err(e), (rx, None))),
+            };
+        };
+        let (f, read) = bg(move || {
+            let mut chunk = Vec::with_capacity(CHUNK);
+            let read = (&mut f).take(CHUNK as u64).read_to_end(&mut chunk);
+            (f, read.map(|_| chunk))
+        })
+        .await;
+        match read {
+            Ok(chunk) if chunk.is_empty() => None,
+            Ok(chunk) => Some((Ok(chunk.into()), (rx, Some(f)))),
+            Err(e) => Some((Err(e.into()), (rx, None))),
+        }
+    })
+}
+
+/// Render the page of `key` in whole, caching it as well.
+pub async fn collect(key: cache::Key, slot: Option<guard::Slot>) -> Result<Bytes, RenderError> {
+    gather(spawn(key, slot, timeout(), true)).await
+}
+
+/// Render the page of `key` in whole within `limit`, not to be
+/// cached, as for sources that are not installed.
+pub async fn once(
+    key: cache::Key,
+    slot: guard::Slot,
+    limit: Duration,
+) -> Result<Bytes, RenderError> {
+    gather(spawn(key, Some(slot), limit, false)).await
+}
+
+async fn gather(rx: Pieces) -> Result<Bytes, RenderError> {
+    let mut ret = Vec::new();
+    let mut chunks = Box::pin(chunks(rx));
+    while let Some(chunk) = chunks.next().await {
+        ret.extend_from_slice(&chunk?);
+    }
+    Ok(ret.into())
+}
+
+fn timeout() -> Duration {
+    Duration::from_secs(config().render.timeout)
+}
+
+fn spawn(key: cache::Key, slot: Option<guard::Slot>, limit: Duration, keep: bool) -> Pieces {
+    // what a slow reader is behind on is spilled to disk rather than
+    // waited on, so the slot and the timeout end with the rendering
+    let (tx, rx) = mpsc::channel(QUEUED);
+    tokio::spawn(scope::carry(async move {
+        let (head, tail, rewriter) = wrapping(&key.path, key.format, &key.prefs).await;
+        let mut out = Output {
+            tx: &tx,
+            pending: Vec::new(),
+            written: 0,
+            page: keep.then(Vec::new),
+            sent: false,
+            spill: None,
+            rewriter,
+        };
+        // dropping the render on timeout kills any child
+        let mut span = trace::span("render");
+        span.attr("renderer", renderer().name());
+        span.attr("format", key.format.ext());
+        let res = tokio::time::timeout(limit, async {
+            out.push(head.as_bytes())?;
+            renderer()
+                .render(&key.path, key.format, &key.prefs, &mut out)
+                .await?;
+            out.push(tail.as_bytes())?;
+            if let Some(rewriter) = out.rewriter.take() {
+                out.pending.extend(rewriter.end()?);
+            }
+            out.flush(true).await
+        })
+        .await
+        .unwrap_or(Err(RenderError::Timeout));
+        if res.is_err() {
+            span.fail();
+        }
+        drop(span);
+        drop(slot);
+        match res {
+            Ok(()) => {
+                if let Some(mut f) = out.spill {
+                    // read from the start, once the reader is there
+                    f.rewind().ok();
+                    tx.send(Ok(Piece::Spilled(f))).await.ok();
+                }
+                if let Some(page) = out.page {
+                    bg(move || cache::put(key, page.into())).await;
+                }
+            }
+            Err(RenderError::Gone) => {}
+            Err(e) => {
+                // not yet sent, it becomes the error response
+                if out.sent {
+                    eprintln!("render failed: {e}");
+                }
+                tx.send(Err(e)).await.ok();
+            }
+        }
+        // the body only ends with `tx` gone, after the page is cached
+    }));
+    rx
+}
+
+/// Turns a roff source into `format`: for HTML the fragment of a page,
+/// or in the terminal view, HTML for inside a `<pre>`.
+#[async_trait]
+pub trait Renderer: Send + Sync {
+    /// Identifies the renderer in cache keys and validators.
+    fn name(&self) -> &'static str;
+
+    async fn render(
+        &self,
+        p: &Path,
+        format: Format,
+        prefs: &Prefs,
+        out: &mut Output<'_>,
+    ) -> Result<(), RenderError>;
+
+    /// The program run, whose change means probing again.
+    fn program(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// Find out what the installed renderer supports.  Blocks.
+    fn probe(&self) -> Caps;
+}
+
+/// What the renderer in use can do.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Caps {
+    /// Output formats, by their mandoc -T names.
+    pub formats: Vec<String>,
+    /// Those of `render.mandoc_options` accepted.
+    pub options: Vec<String>,
+    /// What the program says its version is, empty if it does not.
+    pub version: String,
+}
+
+impl Caps {
+    pub fn format(&self, name: &str) -> bool {
+        self.formats.iter().any(|f| f == name)
+    }
+}
+
+/// Capabilities of the renderer, probed once per renderer binary and
+/// configuration and remembered in the runtime directory.  Blocks.
+pub fn caps() -> &'static Caps {
+    static CAPS: OnceLock<Caps> = OnceLock::new();
+    CAPS.get_or_init(|| {
+        let r = renderer();
+        let file = r.program().zip(guard::runtime_dir()).map(|(prog, dir)| {
+            let mut h = std::hash::DefaultHasher::new();
+            let meta = std::fs::metadata(&prog).ok();
+            (
+                r.name(),
+                &prog,
+                RENDER_VERSION,
+                &config().render.mandoc_options,
+            )
+                .hash(&mut h);
+            (meta.as_ref().map(|m| (m.len(), m.modified().ok()))).hash(&mut h);
+            dir.join(format!("caps-{:016x}.json", h.finish()))
+        });
+        if let Some(caps) = file
+            .as_ref()
+            .and_then(|f| std::fs::read(f).ok())
+            .and_then(|text| serde_json::from_slice(&text).ok())
+        {
+            return caps;
+        }
+        let caps = r.probe();
+        let prefer = &config().render.mandoc_options;
+        for opt in prefer.iter().filter(|o| !caps.options.contains(o)) {
+            eprintln!("{}: -O {opt} not supported, ignored", r.name());
+        }
+        if let Some(file) = file {
+            let tmp = file.with_extension(format!("tmp{}", std::process::id()));
+            let text = serde_json::to_vec(&caps).unwrap_or_default();
+            if let Err(e) = std::fs::write(&tmp, text).and_then(|()| std::fs::rename(&tmp, &file)) {
+                eprintln!("cannot write {}: {e}", file.display());
+            }
+        }
+        caps
+    })
+}
+
+/// `/status`: the renderer in use and what it supports.
+pub async fn status() -> Response {
+    let caps = bg(caps).await;
+    let body = serde_json::json!({
+        "renderer": renderer().name(),
+        "version": RENDER_VERSION,
+        "renderer_version": (!caps.version.is_empty()).then_some(&caps.version),
+        "formats": caps.formats,
+        "options": caps.options,
+    });
+    (
+        [(header::CONTENT_TYPE, "application/json")],
+        body.to_string(),
+    )
+        .into_response()
+}
+
+/// The first line `prog` prints when asked its version with `arg`.
+fn version(prog: &Path, arg: &str) -> Option<String> {
+    let out = std::process::Command::new(prog)
+        .arg(arg)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()
+        .filter(|o| o.status.success())?;
+    let text = String::from_utf8_lossy(&out.stdout);
+    let line = text.lines().next()?.trim();
+    (!line.is_empty()).then(|| line.to_owned())
+}
+
+/// Whether `cmd` succeeds on a minimal page, without complaining
+/// about `arg`.
+fn accepts(mut cmd: std::process::Command, arg: &str) -> bool {
+    const PAGE: &[u8] = b".Dd January 1, 2024\n.Dt PROBE 1\n.Os\n.Sh NAME\n.Nm probe\n.Nd probe\n";
+    let Ok(mut child) = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+    else {
+        return false;
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        std::io::Write::write_all(&mut stdin, PAGE).ok();
+    }
+    child.wait_with_output().is_ok_and(|out| {
+        out.status.success() && !String::from_utf8_lossy(&out.stderr).contains(arg)
+    })
+}
+
+/// The configured renderer; `auto` picks mandoc if installed, else
+/// groff, else the built-in one if there is.
+pub fn renderer() -> &'static dyn Renderer {
+    static RENDERER: OnceLock<&'static dyn Renderer> = OnceLock::new();
+    *RENDERER.get_or_init(|| match config().render.renderer {
+        RendererKind::Mandoc => &Mandoc,
+        RendererKind::Groff => &Groff,
+        #[cfg(feature = "builtin-renderer")]
+        RendererKind::Builtin => &Builtin,
+        RendererKind::Auto if on_path(&config().render.mandoc) => &Mandoc,
+        RendererKind::Auto if on_path(Path::new("groff")) => {
+            eprintln!("mandoc not found, rendering with groff");
+            &Groff
+        }
+        #[cfg(feature = "builtin-renderer")]
+        RendererKind::Auto => {
+            eprintln!("neither mandoc nor groff found, using the built-in renderer");
+            &Builtin
+        }
+        #[cfg(not(feature = "builtin-renderer"))]
+        RendererKind::Auto => &Mandoc,
+    })
+}
+
+fn on_path(prog: &Path) -> bool {
+    find_program(prog).is_some()
+}
+
+pub fn find_program(prog: &Path) -> Option<PathBuf> {
+    if prog.components().count() > 1 {
+        return prog.is_file().then(|| prog.to_owned());
+    }
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(prog))
+        .find(|p| p.is_file())
+}
+
+/// Output formats probed for, by mandoc -T name.
+const FORMATS: [&str; 5] = ["html", "utf8", "markdown", "pdf", "ps"];
+
+/// Rendered output on its way to the client, and the copy kept for
+/// the cache.
+pub struct Output<'a> {
+    tx: &'a mpsc::Sender<Result<Piece, RenderError>>,
+    pending: Vec<u8>,
+    written: usize,
+    /// None once too large to ever be cached.
+    page: Option<Vec<u8>>,
+    sent: bool,
+    /// Where output goes once the reader is behind, unlinked.
+    spill: Option<std::fs::File>,
+    /// What HTML passes through on the way.
+    rewriter: Option<html::Rewriter>,
+}
+
+impl Output<'_> {
+    /// Add to the page, within the configured output size limit.
+    pub async fn write(&mut self, s: &str) -> Result<(), RenderError> {
+        self.write_bytes(s.as_bytes()).await
+    }
+
+    pub async fn write_bytes(&mut self, b: &[u8]) -> Result<(), RenderError> {
+        self.written += b.len();
+        if self.written > config().render.max_output {
+            return Err(RenderError::TooLarge);
+        }
+        self.push(b)?;
+        self.flush(false).await
+    }
+
+    fn push(&mut self, b: &[u8]) -> Result<(), RenderError> {
+        match &mut self.rewriter {
+            Some(rewriter) => self.pending.extend(rewriter.write(b)?),
+            None => self.pending.extend_from_slice(b),
+        }
+        Ok(())
+    }
+
+    /// Pass on pending output: the first lines right away, then in
+    /// chunks, and everything at the `end`.
+    async fn flush(&mut self, end: bool) -> Result<(), RenderError> {
+        if self.sent && !end && self.pending.len() < CHUNK {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::take(&mut self.pending));
+        if let Some(page) = &mut self.page {
+            page.extend_from_slice(&chunk);
+            let cache = &config().cache;
+            if cache.dir.is_none() && page.len() > cache.memory {
+                self.page = None;
+            }
+        }
+        self.sent = true;
+        if self.tx.is_closed() {
+            return Err(RenderError::Gone);
+        }
+        if self.spill.is_none() && self.tx.capacity() > 0 {
+            return self
+                .tx
+                .try_send(Ok(Piece::Chunk(chunk)))
+                .map_err(|_| RenderError::Gone);
+        }
+        if self.spill.is_none() {
+            self.spill = bg(spill_file).await;
+        }
+        let Some(mut f) = self.spill.take() else {
+            // nowhere to spill to, so the reader is waited for
+            let sent = self.tx.send(Ok(Piece::Chunk(chunk))).await;
+            return sent.map_err(|_| RenderError::Gone);
+        };
+        let (f, written) = bg(move || {
+            let written = f.write_all(&chunk);
+            (f, written)
+        })
+        .await;
+        self.spill = Some(f);
+        Ok(written?)
+    }
+}
+
+/// A file for output to spill to, unlinked right away, in the cache
+/// directory when there is one as the page is bound for it anyway.
+/// Blocks.
+fn spill_file() -> Option<std::fs::File> {
+    static SEQ: AtomicUsize = AtomicUsize::new(0);
+    let dir = config().cache.dir.as_ref().or(guard::runtime_dir())?;
+    let n = SEQ.fetch_add(1, Ordering::Relaxed);
+    let path = dir.join(format!("spill-{}-{n}", std::process::id()));
+    let f = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .map_err(|e| eprintln!("cannot spill output to {}: {e}", path.display()))
+        .ok()?;
+    std::fs::remove_file(&path).ok();
+    Some(f)
+}
+
+pub struct Mandoc;
+
+#[async_trait]
+impl Renderer for Mandoc {
+    fn name(&self) -> &'static str {
+        "mandoc"
+    }
+
+    fn program(&self) -> Option<PathBuf> {
+        find_program(&config().render.mandoc)
+    }
+
+    fn probe(&self) -> Caps {
+        let Some(prog) = self.program() else {
+            return Caps::default();
+        };
+        let run = |args: &[&str]| {
+            let mut cmd = std::process::Command::new(&prog);
+            cmd.args(args);
+            accepts(cmd, args.last().unwrap_or(&""))
+        };
+        Caps {
+            formats: FORMATS
+                .into_iter()
+                .filter(|f| run(&["-T", f]))
+                .map(str::to_owned)
+                .collect(),
+            options: config()
+                .render
+                .mandoc_options
+                .iter()
+                .filter(|o| run(&["-T", "html", "-O", o]))
+                .cloned()
+                .collect(),
+            version: version(&prog, "-V").unwrap_or_default(),
+        }
+    }
+
+    async fn render(
+        &self,
+        p: &Path,
+        format: Format,
+        prefs: &Prefs,
+        out: &mut Output<'_>,
+    ) -> Result<(), RenderError> {
+        let render = &config().render;
+        let mut cmd = tokio::process::Command::new(&render.mandoc);
+        // mandoc reads local files itself when they are UTF-8
+        // already, and is given them converted otherwise: on its own,
+        // mandoc only tells UTF-8 from Latin-1
+        let (original, converted) = decoded(p).await?;
+        let local = source().local_path(p).filter(|_| converted.is_none());
+        let input = local.is_none().then(|| converted.unwrap_or(original));
+        let read_from = |cmd: &mut tokio::process::Command| match &local {
+            Some(p) => {
+                cmd.arg(p);
+            }
+            None => {
+                cmd.args(["-K", "utf-8"]);
+            }
+        };
+        if format.raw() {
+            read_from(cmd.args(["-T", format.output()]));
+            return run_raw(cmd, input, out).await;
+        }
+        if let Some(filter) = terminal(format, prefs) {
+            cmd.args(["-T", "utf8"]);
+            if let Some(width) = prefs.width {
+                cmd.arg("-O").arg(format!("width={width}"));
+            }
+            read_from(&mut cmd);
+            return run_command(cmd, input, out, filter).await;
+        }
+        let mut options = vec!["fragment", "man=/%S/%N.%S.html"];
+        for opt in &bg(caps).await.options {
+            let name = |o: &str| o.split('=').next().unwrap_or_default().to_owned();
+            options.retain(|o| name(o) != name(opt));
+            options.push(opt);
+        }
+        cmd.args(["-T", "html", "-O", &options.join(",")]);
+        read_from(&mut cmd);
+        run_raw(cmd, input, out).await
+    }
+}
+
+/// groff, whose HTML output is a whole document; only its body is
+/// kept.
+pub struct Groff;
+
+#[async_trait]
+impl Renderer for Groff {
+    fn name(&self) -> &'static str {
+        "groff"
+    }
+
+    fn program(&self) -> Option<PathBuf> {
+        find_program(Path::new("groff"))
+    }
+
+    fn probe(&self) -> Caps {
+        let Some(prog) = self.program() else {
+            return Caps::default();
+        };
+        Caps {
+            formats: FORMATS
+                .into_iter()
+                .filter(|f| {
+                    let mut cmd = std::process::Command::new(&prog);
+                    cmd.args(["-mandoc", "-T", f]);
+                    accepts(cmd, f)
+                })
+                .map(str::to_owned)
+                .collect(),
+            options: Vec::new(),
+            version: version(&prog, "--version").unwrap_or_default(),
+        }
+    }
+
+    async fn render(
+        &self,
+        p: &Path,
+        format: Format,
+        prefs: &Prefs,
+        out: &mut Output<'_>,
+    ) -> Result<(), RenderError> {
+        let mut cmd = tokio::process::Command::new("groff");
+        cmd.args(["-mandoc", "-K", "utf-8"]);
+        // groff cannot read compressed sources
+        let source = read_source(p).await?;
+        if format.raw() {
+            cmd.args(["-T", format.output()]);
+            return run_raw(cmd, Some(source), out).await;
+        }
+        let terminal = terminal(format, prefs);
+        if terminal.is_some() {
+            // -c: overstrikes rather than SGR escapes
+            cmd.args(["-T", "utf8", "-P", "-c"]);
+            if let Some(width) = prefs.width {
+                cmd.arg(format!("-rLL={width}n"));
+            }
+        } else {
+            cmd.args(["-T", "html"]);
+        }
+        let mut in_body = false;
+        run_command(cmd, Some(source), out, |line| {
+            if let Some(filter) = terminal {
+                return filter(line);
+            }
+            if !in_body {
+                in_body = line.contains("<body>");
+                return String::new();
+            }
+            if line.contains("</body>") {
+                in_body = false;
+                return String::new();
+            }
+            line.to_owned()
+        })
+        .await
+    }
+}
+
+/// The contents of a page source, decompressed and in UTF-8.
+async fn read_source(p: &Path) -> std::io::Result<Vec<u8>> {
+    let (source, converted) = decoded(p).await?;
+    Ok(converted.unwrap_or(source))
+}
+
+/// The contents of a page source, decompressed, and converted to
+/// UTF-8 if not in that already.
+async fn decoded(p: &Path) -> std::io::Result<(Vec<u8>, Option<Vec<u8>>)> {
+    let p = p.to_owned();
+    bg(move || {
+        let _span = trace::span("decompress");
+        let mut ret = Vec::new();
+        flate2::read::GzDecoder::new(source().open(&p)?).read_to_end(&mut ret)?;
+        let converted = encoding::convert(&ret, &p);
+        Ok((ret, converted))
+    })
+    .await
+}
+
+#[cfg(feature = "builtin-renderer")]
+pub struct Builtin;
+
+#[cfg(feature = "builtin-renderer")]
+#[async_trait]
+impl Renderer for Builtin {
+    fn name(&self) -> &'static str {
+        "builtin"
+    }
+
+    fn probe(&self) -> Caps {
+        Caps {
+            formats: vec!["html".into(), "utf8".into()],
+            options: Vec::new(),
+            version: RENDER_VERSION.to_owned(),
+        }
+    }
+
+    async fn render(
+        &self,
+        p: &Path,
+        format: Format,
+        prefs: &Prefs,
+        out: &mut Output<'_>,
+    ) -> Result<(), RenderError> {
+        let source = read_source(p).await?;
+        let width = prefs.width.unwrap_or(78).into();
+        let (terminal, markup) = (
+            terminal(format, prefs).is_some(),
+            matches!(format, Format::Html | Format::Fragment),
+        );
+        let page = bg(move || {
+            let source = String::from_utf8_lossy(&source);
+            if terminal {
+                crate::roff::text(&source, width, markup)
+            } else {
+                crate::roff::html(&source)
+            }
+        })
+        .await;
+        out.write(&page).await
+    }
+}
+
+/// Run a renderer process, feeding it `input` if any, and passing its
+/// output on line by line through `filter`.  A non-zero exit is an
+/// error, carrying what it wrote to stderr.
+async fn run_command(
+    cmd: tokio::process::Command,
+    input: Option<Vec<u8>>,
+    out: &mut Output<'_>,
+    filter: impl FnMut(&str) -> String + Send,
+) -> Result<(), RenderError> {
+    run_process(cmd, input, out, Some(filter)).await
+}
+
+/// Run a renderer process whose output is passed on as is.
+async fn run_raw(
+    cmd: tokio::process::Command,
+    input: Option<Vec<u8>>,
+    out: &mut Output<'_>,
+) -> Result<(), RenderError> {
+    run_process(cmd, input, out, None::<fn(&str) -> String>).await
+}
+
+async fn run_process(
+    mut cmd: tokio::process::Command,
+    input: Option<Vec<u8>>,
+    out: &mut Output<'_>,
+    mut filter: Option<impl FnMut(&str) -> String + Send>,
+) -> Result<(), RenderError> {
+    let max = config().render.max_output;
+    let prog = cmd.as_std().get_program().to_string_lossy().into_owned();
+    let mut child = cmd
+        .kill_on_drop(true)
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| RenderError::Spawn(prog.clone(), e))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        tokio::spawn(async move { stdin.write_all(&input).await.ok() });
+    }
+    let mut stderr = child.stderr.take().unwrap();
+    let stderr = tokio::spawn(async move {
+        let mut err = Vec::new();
+        (&mut stderr)
+            .take(MAX_STDERR)
+            .read_to_end(&mut err)
+            .await
+            .ok();
+        // drained all the same, or the renderer blocks writing more
+        tokio::io::copy(&mut stderr, &mut tokio::io::sink())
+            .await
+            .ok();
+        err
+    });
+    let mut stdout = BufReader::new(child.stdout.take().unwrap().take(max as u64 + 1));
+    let (mut line, mut total) = (Vec::new(), 0);
+    loop {
+        line.clear();
+        let n = stdout.read_until(b'\n', &mut line).await?;
+        if n == 0 {
+            break;
+        }
+        total += n;
+        if total > max {
+            return Err(RenderError::TooLarge);
+        }
+        let Some(filter) = &mut filter else {
+            out.write_bytes(&line).await?;
+            continue;
+        };
+        // lines never split a UTF-8 sequence; sources are converted,
+        // so anything invalid is the renderer's own
+        out.write(&filter(&String::from_utf8_lossy(&line))).await?;
+    }
+    let status = child.wait().await?;
+    let stderr = stderr.await.unwrap_or_default();
+    let stderr = String::from_utf8_lossy(&stderr).trim_end().to_owned();
+    if !status.success() {
+        return Err(RenderError::Failed(prog, status, stderr));
+    }
+    if !stderr.is_empty() {
+        eprintln!("{prog}: {stderr}");
+    }
+    Ok(())
+}
+
+/// For terminal output, how to convert it.
+fn terminal(format: Format, prefs: &Prefs) -> Option<fn(&str) -> String> {
+    match format {
+        Format::Html if prefs.terminal => Some(overstrike_html),
+        Format::Txt => Some(strip_overstrikes),
+        Format::Html | Format::Pdf | Format::Ps | Format::Md | Format::Fragment => None,
+    }
+}
+
+/// What goes around the rendered page, and what its HTML goes
+/// through: the document titled `name(section)` with the description
+/// from its NAME section and navigation within the section, or for
+/// fragments the content stages only.
+async fn wrapping(
+    p: &Path,
+    format: Format,
+    prefs: &Prefs,
+) -> (String, String, Option<html::Rewriter>) {
+    if format == Format::Fragment {
+        let lang = prefs.lang.clone();
+        let installed = bg(move || lookup::installed(lang.as_deref())).await;
+        return (
+            String::new(),
+            String::new(),
+            Some(html::Rewriter::new(None, Some(installed))),
+        );
+    }
+    if format != Format::Html {
+        return Default::default();
+    }
+    let file = p.file_name().and_then(|f| f.to_str()).unwrap_or_default();
+    let file = file.strip_suffix(".gz").unwrap_or(file);
+    let (name, section) = file.rsplit_once('.').unwrap_or((file, ""));
+    let (page, installed) = bg({
+        let (p, name, section) = (p.to_owned(), name.to_owned(), section.to_owned());
+        let file = file.to_owned();
+        let lang = prefs.lang.clone();
+        move || {
+            let pages = lookup::section_pages(&section, lang.as_deref());
+            let (prev, next) = match pages.binary_search(&name) {
+                Ok(i) => (
+                    i.checked_sub(1).map(|i| pages[i].clone()),
+                    pages.get(i + 1).cloned(),
+                ),
+                Err(_) => (None, None),
+            };
+            let referenced_by = lookup::referenced_by(&name, &section, lang.as_deref(), false);
+            let installed = lookup::installed(lang.as_deref());
+            let meta = meta::read(&p).unwrap_or_default();
+            let package = package::owner(&p);
+            // tldr-pages only covers commands
+            let tldr = section
+                .starts_with(['1', '6', '8'])
+                .then(|| tldr::find(&name, lang.as_deref()))
+                .flatten();
+            let versions = lookup::versions(&section, &format!("{file}.gz"), lang.as_deref())
+                .into_iter()
+                .map(|(label, found)| (label, found == p))
+                .collect();
+            let see_also = meta
+                .see_also
+                .into_iter()
+                .filter(|(n, s)| installed.has(n, s))
+                .collect();
+            let page = html::Page {
+                title: format!("{name}({section})"),
+                description: meta.description,
+                place: html::Place::Man(Box::new(html::Man {
+                    name,
+                    section,
+                    prev,
+                    next,
+                    see_also,
+                    package,
+                    referenced_by,
+                    versions,
+                    tldr,
+                })),
+            };
+            (page, installed)
+        }
+    })
+    .await;
+    let rewriter = Some(html::Rewriter::new(Some(page), Some(installed)));
+    let mut prefs = prefs.clone();
+    prefs.lang = lookup::language(p);
+    let (pre, post) = template::wrap(&prefs);
+    if prefs.terminal {
+        (
+            pre + "<pre class=\"terminal\">",
+            "</pre>".to_owned() + &post,
+            rewriter,
+        )
+    } else {
+        (pre, post, rewriter)
+    }
+}
+
+fn strip_overstrikes(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '\x08' {
+            ret.pop();
+        } else {
+            ret.push(c);
+        }
+    }
+    ret
+}
+
+/// Convert terminal output to HTML, turning the backspace overstrikes
+/// `c\x08c` and `_\x08c` into bold and underline.
+fn overstrike_html(s: &str) -> String {
+    #[derive(PartialEq)]
+    enum Style {
+        Plain,
+        Bold,
+        Under,
+    }
+    let mut ret = String::with_capacity(s.len());
+    let mut cur = Style::Plain;
+    let chars: Vec<char> = s.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let (style, c) = if chars.get(i + 1) == Some(&'\x08') && i + 2 < chars.len() {
+            let over = chars[i + 2];
+            i += 3;
+            (
+                if chars[i - 3] == '_' {
+                    Style::Under
+                } else {
+                    Style::Bold
+                },
+                over,
+            )
+        } else {
+            i += 1;
+            (Style::Plain, chars[i - 1])
+        };
+        if style != cur {
+            ret.push_str(match cur {
+                Style::Plain => "",
+                Style::Bold => "</b>",
+                Style::Under => "</u>",
+            });
+            ret.push_str(match style {
+                Style::Plain => "",
+                Style::Bold => "<b>",
+                Style::Under => "<u>",
+            });
+            cur = style;
+        }
+        ret.push_str(&escape_html(c.encode_utf8(&mut [0; 4])));
+    }
+    if cur != Style::Plain {
+        ret.push_str(if cur == Style::Bold { "</b>" } else { "</u>" });
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn spilled_when_behind() {
+        crate::testing::setup();
+        let (tx, rx) = mpsc::channel(QUEUED);
+        let mut out = Output {
+            tx: &tx,
+            pending: Vec::new(),
+            written: 0,
+            page: None,
+            sent: false,
+            spill: None,
+            rewriter: None,
+        };
+        let line = "x".repeat(CHUNK - 1) + "\n";
+        for _ in 0..QUEUED * 2 {
+            out.write(&line).await.unwrap();
+        }
+        out.flush(true).await.unwrap();
+        // the reader only takes the rest once it got to the spill
+        let mut f = out.spill.take().unwrap();
+        f.rewind().unwrap();
+        let page = tokio::spawn(gather(rx));
+        tx.send(Ok(Piece::Spilled(f))).await.unwrap();
+        drop(tx);
+        assert_eq!(page.await.unwrap().unwrap(), line.repeat(QUEUED * 2));
+    }
+}
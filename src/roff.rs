@@ -0,0 +1,1209 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! A small renderer for the common subset of man(7) and mdoc(7), for
+//! systems with neither mandoc nor groff.  Requests it does not know
+//! are dropped; their text arguments are not shown.
+
+use crate::escape_html;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Font {
+    Roman,
+    Bold,
+    Italic,
+}
+
+#[derive(Clone)]
+struct Span {
+    text: String,
+    font: Font,
+    /// Cross references are absolute paths, other links URLs.
+    link: Option<String>,
+}
+
+enum Block {
+    /// Page title and section.
+    Head(String, String),
+    Sh(Vec<Span>),
+    Ss(Vec<Span>),
+    Para(Vec<Span>),
+    Tagged(Vec<Span>, Vec<Span>),
+    /// No-fill text, lines ending in newlines.
+    Literal(Vec<Span>),
+}
+
+/// What the text being collected becomes.
+enum Kind {
+    Para,
+    Tagged(Vec<Span>),
+    Literal,
+}
+
+/// A request whose argument is the next line.
+enum Pending {
+    Sh,
+    Ss,
+    Tag,
+}
+
+/// Open mdoc list, with its item count for -enum.
+struct List {
+    kind: String,
+    count: usize,
+}
+
+struct Parser {
+    blocks: Vec<Block>,
+    cur: Vec<Span>,
+    kind: Kind,
+    pending: Option<Pending>,
+    font: Font,
+    prev_font: Font,
+    link: Option<String>,
+    /// Font of the next text line, from `.B` and the like alone.
+    line_font: Option<Font>,
+    nofill: bool,
+    /// Off between mdoc `.Sm off` and `.Sm on`.
+    spacing: bool,
+    lists: Vec<List>,
+    /// First .Nm argument of an mdoc page.
+    name: Option<String>,
+    /// Skipping a `.de` or `.ig` body, or an `.if` block.
+    skip: Option<&'static str>,
+}
+
+/// Render `src` into an HTML fragment like mandoc's.
+pub fn html(src: &str) -> String {
+    let mut ret = String::with_capacity(src.len() * 2);
+    let mut in_list = false;
+    for block in parse(src) {
+        let tagged = matches!(block, Block::Tagged(..));
+        if in_list && !tagged {
+            ret.push_str("</dl>\n");
+        } else if !in_list && tagged {
+            ret.push_str("<dl class=\"Bl-tag\">\n");
+        }
+        in_list = tagged;
+        match block {
+            Block::Head(title, sec) => {
+                let head = escape_html(&format!("{title}({sec})"));
+                ret.push_str(&format!(
+                    "<table class=\"head\">\n<tr>\n<td class=\"head-ltitle\">{head}</td>\n<td class=\"head-rtitle\">{head}</td>\n</tr>\n</table>\n"
+                ));
+            }
+            Block::Sh(s) => ret.push_str(&format!("<h1 class=\"Sh\">{}</h1>\n", spans_html(&s))),
+            Block::Ss(s) => ret.push_str(&format!("<h2 class=\"Ss\">{}</h2>\n", spans_html(&s))),
+            Block::Para(s) => ret.push_str(&format!("<p class=\"Pp\">{}</p>\n", breaks(&s))),
+            Block::Tagged(tag, s) => ret.push_str(&format!(
+                "<dt>{}</dt>\n<dd>{}</dd>\n",
+                breaks(&tag),
+                breaks(&s)
+            )),
+            Block::Literal(s) => ret.push_str(&format!("<pre>{}</pre>\n", spans_html(&s))),
+        }
+    }
+    if in_list {
+        ret.push_str("</dl>\n");
+    }
+    ret
+}
+
+/// Render `src` as terminal text `width` columns wide, with `markup`
+/// in HTML with bold and underline.
+pub fn text(src: &str, width: usize, markup: bool) -> String {
+    const INDENT: usize = 7;
+    let mut ret = String::with_capacity(src.len() * 2);
+    for block in parse(src) {
+        match block {
+            Block::Head(title, sec) => {
+                let head = format!("{title}({sec})");
+                let gap = width.saturating_sub(2 * head.chars().count()).max(1);
+                let head = format!("{head}{}{head}\n\n", " ".repeat(gap));
+                ret.push_str(&if markup { escape_html(&head) } else { head });
+            }
+            Block::Sh(s) => fill(&mut ret, markup, &bold(s), 0, 0, width),
+            Block::Ss(s) => fill(&mut ret, markup, &bold(s), 3, 3, width),
+            Block::Para(s) => {
+                fill(&mut ret, markup, &s, INDENT, INDENT, width);
+                ret.push('\n');
+            }
+            Block::Tagged(tag, s) => {
+                let tag_len: usize = tag.iter().map(|s| s.text.chars().count()).sum();
+                if tag_len < INDENT {
+                    let mut line = tag;
+                    // joined to the tag, so the body starts at 2 * INDENT
+                    line.push(roman(&"\u{a0}".repeat(INDENT - 1 - tag_len)));
+                    line.extend(s);
+                    fill(&mut ret, markup, &line, INDENT, 2 * INDENT, width);
+                } else {
+                    fill(&mut ret, markup, &tag, INDENT, INDENT, width);
+                    fill(&mut ret, markup, &s, 2 * INDENT, 2 * INDENT, width);
+                }
+                ret.push('\n');
+            }
+            Block::Literal(s) => {
+                for line in split_lines(&s) {
+                    ret.push_str(&" ".repeat(INDENT));
+                    ret.push_str(&spans_text(&line, markup));
+                    ret.push('\n');
+                }
+                ret.push('\n');
+            }
+        }
+    }
+    ret.replace('\u{a0}', " ")
+}
+
+fn roman(s: &str) -> Span {
+    Span {
+        text: s.to_owned(),
+        font: Font::Roman,
+        link: None,
+    }
+}
+
+fn bold(spans: Vec<Span>) -> Vec<Span> {
+    spans
+        .into_iter()
+        .map(|s| Span {
+            font: Font::Bold,
+            ..s
+        })
+        .collect()
+}
+
+fn spans_html(spans: &[Span]) -> String {
+    let mut ret = String::new();
+    for s in spans {
+        let text = escape_html(&s.text);
+        let text = match s.font {
+            Font::Roman => text,
+            Font::Bold => format!("<b>{text}</b>"),
+            Font::Italic => format!("<i>{text}</i>"),
+        };
+        match &s.link {
+            Some(href) => {
+                let class = if href.starts_with('/') { "Xr" } else { "Lk" };
+                ret.push_str(&format!(
+                    "<a class=\"{class}\" href=\"{}\">{text}</a>",
+                    escape_html(href)
+                ));
+            }
+            None => ret.push_str(&text),
+        }
+    }
+    ret
+}
+
+/// Filled text, with `.br` as `<br/>`.
+fn breaks(spans: &[Span]) -> String {
+    spans_html(spans).trim_end().replace('\n', "<br/>\n")
+}
+
+fn spans_text(spans: &[Span], markup: bool) -> String {
+    let mut ret = String::new();
+    for s in spans {
+        if !markup {
+            ret.push_str(&s.text);
+            continue;
+        }
+        let text = escape_html(&s.text);
+        ret.push_str(&match s.font {
+            Font::Roman => text,
+            Font::Bold => format!("<b>{text}</b>"),
+            Font::Italic => format!("<u>{text}</u>"),
+        });
+    }
+    ret
+}
+
+fn split_lines(spans: &[Span]) -> Vec<Vec<Span>> {
+    let mut ret = vec![Vec::new()];
+    for s in spans {
+        let mut parts = s.text.split('\n');
+        if let Some(first) = parts.next() {
+            ret.last_mut().unwrap().push(Span {
+                text: first.to_owned(),
+                ..s.clone()
+            });
+        }
+        for part in parts {
+            ret.push(vec![Span {
+                text: part.to_owned(),
+                ..s.clone()
+            }]);
+        }
+    }
+    if ret
+        .last()
+        .is_some_and(|l| l.iter().all(|s| s.text.is_empty()))
+    {
+        ret.pop();
+    }
+    ret
+}
+
+/// Fill words into lines, the first indented by `first`, the rest
+/// by `rest`.
+fn fill(out: &mut String, markup: bool, spans: &[Span], first: usize, rest: usize, width: usize) {
+    // words as runs of differently styled pieces
+    let mut words: Vec<Vec<Span>> = Vec::new();
+    let mut joined = false;
+    for s in spans {
+        for token in s.text.split_inclusive([' ', '\n']) {
+            let (piece, sep) = match token.strip_suffix([' ', '\n']) {
+                Some(piece) => (piece, token.chars().last()),
+                None => (token, None),
+            };
+            if !piece.is_empty() {
+                let piece = Span {
+                    text: piece.to_owned(),
+                    ..s.clone()
+                };
+                match words.last_mut() {
+                    Some(word) if joined => word.push(piece),
+                    _ => words.push(vec![piece]),
+                }
+                joined = true;
+            }
+            if let Some(sep) = sep {
+                joined = false;
+                // an empty word for a line break
+                if sep == '\n' {
+                    words.push(Vec::new());
+                }
+            }
+        }
+    }
+    let len = |w: &[Span]| w.iter().map(|s| s.text.chars().count()).sum::<usize>();
+    let mut col = 0;
+    let mut indent = first;
+    for word in words {
+        let n = len(&word);
+        if word.is_empty() || (col > indent && col + 1 + n > width) {
+            if col > 0 {
+                out.push('\n');
+            }
+            col = 0;
+            indent = rest;
+            if word.is_empty() {
+                continue;
+            }
+        }
+        if col == 0 {
+            out.push_str(&" ".repeat(indent));
+            col = indent;
+        } else {
+            out.push(' ');
+            col += 1;
+        }
+        out.push_str(&spans_text(&word, markup));
+        col += n;
+    }
+    if col > 0 {
+        out.push('\n');
+    }
+}
+
+fn parse(src: &str) -> Vec<Block> {
+    let mut p = Parser {
+        blocks: Vec::new(),
+        cur: Vec::new(),
+        kind: Kind::Para,
+        pending: None,
+        font: Font::Roman,
+        prev_font: Font::Roman,
+        link: None,
+        line_font: None,
+        nofill: false,
+        spacing: true,
+        lists: Vec::new(),
+        name: None,
+        skip: None,
+    };
+    for line in src.lines() {
+        p.line(line);
+    }
+    p.flush();
+    p.blocks
+}
+
+impl Parser {
+    fn line(&mut self, line: &str) {
+        if let Some(end) = self.skip {
+            if line.trim_start().starts_with(end) || (end == "\\}" && line.contains(end)) {
+                self.skip = None;
+            }
+            return;
+        }
+        let Some(req) = line.strip_prefix(['.', '\'']) else {
+            self.text(line);
+            return;
+        };
+        let req = match req.find("\\\"") {
+            Some(i) => &req[..i],
+            None => req,
+        };
+        let args = split_args(req.trim_start());
+        let Some((name, args)) = args.split_first() else {
+            return;
+        };
+        self.request(name, args);
+    }
+
+    fn text(&mut self, line: &str) {
+        if line.trim().is_empty() {
+            if self.nofill {
+                self.push("\n", Font::Roman);
+            } else {
+                self.flush();
+            }
+            return;
+        }
+        if !self.nofill {
+            self.space();
+        }
+        match self.line_font.take() {
+            Some(font) => self.styled(line, font),
+            None => self.inline(line),
+        }
+        if self.nofill {
+            self.push("\n", Font::Roman);
+        }
+        self.settle();
+    }
+
+    /// Separate from the text before, unless at the start of a block.
+    fn space(&mut self) {
+        if self
+            .cur
+            .last()
+            .is_some_and(|s| !s.text.ends_with([' ', '\n']))
+        {
+            self.push(" ", Font::Roman);
+        }
+    }
+
+    fn push(&mut self, text: &str, font: Font) {
+        if text.is_empty() {
+            return;
+        }
+        match self.cur.last_mut() {
+            Some(last) if last.font == font && last.link == self.link => last.text.push_str(text),
+            _ => self.cur.push(Span {
+                text: text.to_owned(),
+                font,
+                link: self.link.clone(),
+            }),
+        }
+    }
+
+    /// Append text with escapes, in the current font.
+    fn inline(&mut self, s: &str) {
+        let mut chars = s.chars().peekable();
+        let mut text = String::new();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                text.push(c);
+                continue;
+            }
+            let Some(e) = chars.next() else {
+                break;
+            };
+            match e {
+                'f' => {
+                    let font = escape_name(&mut chars);
+                    self.push(&std::mem::take(&mut text), self.font);
+                    let next = match font.as_str() {
+                        "B" | "3" | "CB" | "BI" => Font::Bold,
+                        "I" | "2" | "CI" => Font::Italic,
+                        "P" => self.prev_font,
+                        _ => Font::Roman,
+                    };
+                    self.prev_font = self.font;
+                    self.font = next;
+                }
+                '(' | '[' => {
+                    let name = if e == '(' {
+                        chars.by_ref().take(2).collect()
+                    } else {
+                        chars.by_ref().take_while(|&c| c != ']').collect::<String>()
+                    };
+                    text.push_str(&glyph(&name));
+                }
+                '*' => text.push_str(string(&escape_name(&mut chars))),
+                'e' | '\\' => text.push('\\'),
+                '-' => text.push('-'),
+                ' ' | '~' | '0' => text.push(' '),
+                '"' => break,
+                '&' | '|' | '^' | 'c' | '%' | ':' | '/' | ',' | ')' => {}
+                'n' | 'g' | 'F' | 'm' | 'M' | 'Y' | 'k' | 'z' => {
+                    escape_name(&mut chars);
+                }
+                's' => {
+                    chars.next_if(|&c| c == '+' || c == '-');
+                    if chars.peek().is_some_and(|&c| c == '(' || c == '[') {
+                        escape_name(&mut chars);
+                    } else {
+                        while chars.next_if(char::is_ascii_digit).is_some() {}
+                    }
+                }
+                'h' | 'v' | 'w' | 'l' | 'L' | 'o' | 'X' | 'N' | 'b' | 'D' | 'R' | 'S' | 'x'
+                | 'Z' | 'A' | 'B' | 'C' => {
+                    if let Some(delim) = chars.next() {
+                        chars.by_ref().take_while(|&c| c != delim).for_each(drop);
+                    }
+                }
+                c => text.push(c),
+            }
+        }
+        self.push(&text, self.font);
+    }
+
+    /// Append in a given font, keeping the current one.
+    fn styled(&mut self, s: &str, font: Font) {
+        let (saved, prev) = (self.font, self.prev_font);
+        self.font = font;
+        self.inline(s);
+        self.font = saved;
+        self.prev_font = prev;
+    }
+
+    /// After a line of content, complete what was waiting for it.
+    fn settle(&mut self) {
+        match self.pending.take() {
+            Some(Pending::Sh) => self.heading(Block::Sh),
+            Some(Pending::Ss) => self.heading(Block::Ss),
+            Some(Pending::Tag) => {
+                let tag = std::mem::take(&mut self.cur);
+                self.kind = Kind::Tagged(tag);
+            }
+            None => {}
+        }
+    }
+
+    fn heading(&mut self, make: fn(Vec<Span>) -> Block) {
+        let spans = std::mem::take(&mut self.cur);
+        self.blocks.push(make(spans));
+    }
+
+    /// Complete the current block.
+    fn flush(&mut self) {
+        let spans = std::mem::take(&mut self.cur);
+        let kind = std::mem::replace(
+            &mut self.kind,
+            if self.nofill {
+                Kind::Literal
+            } else {
+                Kind::Para
+            },
+        );
+        if spans.iter().all(|s| s.text.trim().is_empty()) && !matches!(kind, Kind::Tagged(_)) {
+            return;
+        }
+        self.blocks.push(match kind {
+            Kind::Para => Block::Para(spans),
+            Kind::Tagged(tag) => Block::Tagged(tag, spans),
+            Kind::Literal => Block::Literal(spans),
+        });
+    }
+
+    fn request(&mut self, name: &str, args: &[String]) {
+        let joined = args.join(" ");
+        match name {
+            // roff
+            "de" | "de1" | "am" | "ig" => self.skip = Some(".."),
+            "if" | "ie" | "el" if opens_block(args) => self.skip = Some("\\}"),
+            "br" => {
+                if self.cur.is_empty() {
+                    return;
+                }
+                self.push("\n", Font::Roman);
+            }
+            "sp" => self.flush(),
+            "nf" | "EX" => {
+                self.flush();
+                self.nofill = true;
+                self.kind = Kind::Literal;
+            }
+            "fi" | "EE" => {
+                self.flush();
+                self.nofill = false;
+                self.kind = Kind::Para;
+            }
+            "ft" => {
+                let font = match args.first().map(String::as_str) {
+                    Some("B" | "3") => Font::Bold,
+                    Some("I" | "2") => Font::Italic,
+                    Some("P") | None => self.prev_font,
+                    _ => Font::Roman,
+                };
+                self.prev_font = self.font;
+                self.font = font;
+            }
+            // man(7)
+            "TH" => {
+                let title = args.first().cloned().unwrap_or_default();
+                let sec = args.get(1).cloned().unwrap_or_default();
+                self.blocks.push(Block::Head(title, sec));
+            }
+            "SH" | "SS" => {
+                self.flush();
+                self.pending = Some(if name == "SH" {
+                    Pending::Sh
+                } else {
+                    Pending::Ss
+                });
+                if !args.is_empty() {
+                    self.inline(&joined);
+                    self.settle();
+                }
+            }
+            "PP" | "LP" | "P" | "HP" => self.flush(),
+            "TP" | "TQ" => {
+                self.flush();
+                self.pending = Some(Pending::Tag);
+            }
+            "IP" => {
+                self.flush();
+                if let Some(tag) = args.first().filter(|t| !t.is_empty()) {
+                    self.inline(tag);
+                    let tag = std::mem::take(&mut self.cur);
+                    self.kind = Kind::Tagged(tag);
+                }
+            }
+            "B" | "I" | "SB" | "SM" => {
+                let font = match name {
+                    "B" | "SB" => Font::Bold,
+                    "I" => Font::Italic,
+                    _ => Font::Roman,
+                };
+                if args.is_empty() {
+                    self.line_font = Some(font);
+                    return;
+                }
+                self.space();
+                self.styled(&joined, font);
+                self.settle();
+            }
+            "BR" | "BI" | "IB" | "IR" | "RB" | "RI" => {
+                let fonts: Vec<Font> = name
+                    .chars()
+                    .map(|c| match c {
+                        'B' => Font::Bold,
+                        'I' => Font::Italic,
+                        _ => Font::Roman,
+                    })
+                    .collect();
+                self.space();
+                for (i, arg) in args.iter().enumerate() {
+                    self.styled(arg, fonts[i % 2]);
+                }
+                self.settle();
+            }
+            "UR" | "MT" => {
+                self.space();
+                let target = args.first().cloned().unwrap_or_default();
+                // no javascript: and the like; the text stays unlinked
+                self.link = if name == "MT" {
+                    Some(format!("mailto:{target}"))
+                } else {
+                    Some(target).filter(|t| url_ok(t))
+                };
+            }
+            "UE" | "ME" => {
+                if let Some(link) = self.link.take() {
+                    if self
+                        .cur
+                        .last()
+                        .is_none_or(|s| s.link.as_ref() != Some(&link))
+                    {
+                        let shown = link.strip_prefix("mailto:").unwrap_or(&link).to_owned();
+                        self.link = Some(link);
+                        self.push(&shown, Font::Roman);
+                        self.link = None;
+                    }
+                }
+                self.inline(&joined);
+            }
+            // mdoc(7)
+            "Dd" | "Os" | "Rs" | "Re" | "Bk" | "Ek" => {}
+            "Sm" => {
+                self.spacing = match args.first().map(String::as_str) {
+                    Some("on") => true,
+                    Some("off") => false,
+                    _ => !self.spacing,
+                }
+            }
+            "Dt" => {
+                let title = args.first().cloned().unwrap_or_default();
+                let sec = args.get(1).cloned().unwrap_or_default();
+                self.blocks.push(Block::Head(title, sec));
+            }
+            "Sh" | "Ss" => {
+                self.flush();
+                self.pending = Some(if name == "Sh" {
+                    Pending::Sh
+                } else {
+                    Pending::Ss
+                });
+                self.inline(&joined);
+                self.settle();
+            }
+            "Pp" | "Lp" => self.flush(),
+            "Bl" => {
+                self.flush();
+                let kind = args
+                    .iter()
+                    .find(|a| a.starts_with('-') && !matches!(a.as_str(), "-compact" | "-offset"))
+                    .cloned()
+                    .unwrap_or_default();
+                self.lists.push(List { kind, count: 0 });
+            }
+            "El" => {
+                self.flush();
+                self.lists.pop();
+            }
+            "It" => {
+                self.flush();
+                let (kind, count) = match self.lists.last_mut() {
+                    Some(list) => {
+                        list.count += 1;
+                        (list.kind.clone(), list.count)
+                    }
+                    None => (String::new(), 0),
+                };
+                let tag = match kind.as_str() {
+                    "-bullet" => vec![roman("\u{2022}")],
+                    "-dash" | "-hyphen" => vec![roman("-")],
+                    "-enum" => vec![roman(&format!("{count}."))],
+                    "-item" => Vec::new(),
+                    _ => {
+                        self.mdoc(args);
+                        std::mem::take(&mut self.cur)
+                    }
+                };
+                if !tag.is_empty() {
+                    self.kind = Kind::Tagged(tag);
+                }
+            }
+            "Bd" => {
+                self.flush();
+                if args.iter().any(|a| a == "-literal" || a == "-unfilled") {
+                    self.nofill = true;
+                    self.kind = Kind::Literal;
+                }
+            }
+            "Ed" => {
+                self.flush();
+                self.nofill = false;
+                self.kind = Kind::Para;
+            }
+            "D1" | "Dl" => {
+                self.flush();
+                if name == "Dl" {
+                    self.kind = Kind::Literal;
+                }
+                self.mdoc(args);
+                self.flush();
+            }
+            "Nd" => {
+                self.push(" \u{2014} ", Font::Roman);
+                self.inline(&joined);
+            }
+            "Ex" => {
+                self.space();
+                let name = self.name.clone().unwrap_or_default();
+                self.push("The ", Font::Roman);
+                self.push(&name, Font::Bold);
+                self.push(
+                    " utility exits 0 on success, and >0 if an error occurs.",
+                    Font::Roman,
+                );
+            }
+            _ if is_callable(name) => {
+                let mut words = vec![name.to_owned()];
+                words.extend_from_slice(args);
+                self.mdoc(&words);
+                self.settle();
+            }
+            _ => {}
+        }
+    }
+
+    /// Interpret mdoc macro `words`, as on a macro line.
+    fn mdoc(&mut self, words: &[String]) {
+        self.space();
+        let mut mac = String::new();
+        let mut used = false;
+        let mut nospace = false;
+        // closing delimiters of enclosures, innermost last
+        let mut closers: Vec<&str> = Vec::new();
+        let mut i = 0;
+        while i < words.len() {
+            let w = &words[i];
+            i += 1;
+            if is_callable(w) {
+                self.mdoc_default(&mac, used);
+                mac = w.clone();
+                used = false;
+                match w.as_str() {
+                    "Ns" => nospace = true,
+                    "Op" | "Oo" => {
+                        self.push("[", Font::Roman);
+                        closers.push("]");
+                        nospace = true;
+                    }
+                    "Dq" | "Do" => {
+                        self.push("\u{201c}", Font::Roman);
+                        closers.push("\u{201d}");
+                        nospace = true;
+                    }
+                    "Sq" | "So" => {
+                        self.push("\u{2018}", Font::Roman);
+                        closers.push("\u{2019}");
+                        nospace = true;
+                    }
+                    "Pq" | "Po" => {
+                        self.push("(", Font::Roman);
+                        closers.push(")");
+                        nospace = true;
+                    }
+                    "Qq" | "Qo" => {
+                        self.push("\"", Font::Roman);
+                        closers.push("\"");
+                        nospace = true;
+                    }
+                    "Oc" | "Dc" | "Sc" | "Pc" | "Qc" => {
+                        if let Some(close) = closers.pop() {
+                            self.push(close, Font::Roman);
+                        }
+                    }
+                    "Xr" => {
+                        let name = words.get(i).cloned().unwrap_or_default();
+                        let sec = words.get(i + 1).cloned().unwrap_or_default();
+                        i += 2;
+                        if !nospace && self.spacing {
+                            self.space();
+                        }
+                        // unencoded like mandoc's, for html::xref_links
+                        self.link = Some(format!("/{sec}/{name}.{sec}.html"));
+                        self.push(&format!("{name}({sec})"), Font::Bold);
+                        self.link = None;
+                        nospace = false;
+                        used = true;
+                    }
+                    "Nm" if self.name.is_none() => {
+                        self.name = words.get(i).filter(|w| !is_callable(w)).cloned();
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            // closing punctuation sticks to the word before
+            let punct = |w: &str| w.len() == 1 && ".,:;)]?!".contains(w);
+            if punct(w) && words[i..].iter().all(|w| punct(w)) {
+                // trailing punctuation goes after the enclosures
+                while let Some(close) = closers.pop() {
+                    self.push(close, Font::Roman);
+                }
+            }
+            let punct = punct(w);
+            if !nospace && !punct && self.spacing {
+                self.space();
+            }
+            nospace = w == "(" || w == "[";
+            if punct || nospace {
+                self.push(w, Font::Roman);
+                continue;
+            }
+            let (font, prefix) = match mac.as_str() {
+                "Fl" => (Font::Bold, "-"),
+                "Nm" | "Cm" | "Ic" | "Sy" | "Fn" | "Fd" | "Cd" | "In" => (Font::Bold, ""),
+                "Ar" | "Va" | "Pa" | "Em" | "Ft" | "Fa" | "Ev" => (Font::Italic, ""),
+                _ => (Font::Roman, ""),
+            };
+            self.styled(&format!("{prefix}{w}"), font);
+            used = true;
+        }
+        self.mdoc_default(&mac, used);
+        while let Some(close) = closers.pop() {
+            self.push(close, Font::Roman);
+        }
+    }
+
+    /// What argumentless `.Fl`, `.Ar` and `.Nm` stand for.
+    fn mdoc_default(&mut self, mac: &str, used: bool) {
+        if used {
+            return;
+        }
+        match mac {
+            "Fl" => self.push("-", Font::Bold),
+            "Ar" => self.push("file ...", Font::Italic),
+            "Nm" => {
+                if let Some(name) = self.name.clone() {
+                    self.push(&name, Font::Bold);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Whether `.UR` may link to `url`.
+fn url_ok(url: &str) -> bool {
+    ["https://", "http://", "mailto:"]
+        .iter()
+        .any(|p| url.starts_with(p))
+}
+
+fn opens_block(args: &[String]) -> bool {
+    args.iter().any(|a| a.contains("\\{")) && !args.iter().any(|a| a.contains("\\}"))
+}
+
+fn is_callable(w: &str) -> bool {
+    matches!(
+        w,
+        "Ad" | "Ar"
+            | "Cd"
+            | "Cm"
+            | "Dc"
+            | "Do"
+            | "Dq"
+            | "Dv"
+            | "Em"
+            | "Er"
+            | "Ev"
+            | "Fa"
+            | "Fd"
+            | "Fl"
+            | "Fn"
+            | "Ft"
+            | "Ic"
+            | "In"
+            | "Li"
+            | "Nm"
+            | "No"
+            | "Ns"
+            | "Oc"
+            | "Oo"
+            | "Op"
+            | "Pa"
+            | "Pc"
+            | "Po"
+            | "Pq"
+            | "Qc"
+            | "Ql"
+            | "Qo"
+            | "Qq"
+            | "Sc"
+            | "So"
+            | "Sq"
+            | "Sy"
+            | "Va"
+            | "Xr"
+    )
+}
+
+/// Request arguments, split on spaces outside double quotes.
+fn split_args(s: &str) -> Vec<String> {
+    let mut ret = Vec::new();
+    let mut chars = s.chars().peekable();
+    loop {
+        while chars.next_if_eq(&' ').is_some() || chars.next_if_eq(&'\t').is_some() {}
+        let Some(&c) = chars.peek() else {
+            break;
+        };
+        let mut arg = String::new();
+        if c == '"' {
+            chars.next();
+            while let Some(c) = chars.next() {
+                if c == '"' {
+                    if chars.next_if_eq(&'"').is_none() {
+                        break;
+                    }
+                } else if c == '\\' {
+                    arg.push(c);
+                    if let Some(c) = chars.next() {
+                        arg.push(c);
+                    }
+                    continue;
+                }
+                arg.push(c);
+            }
+        } else {
+            while let Some(c) = chars.next_if(|&c| c != ' ' && c != '\t') {
+                arg.push(c);
+                if c == '\\' {
+                    if let Some(c) = chars.next() {
+                        arg.push(c);
+                    }
+                }
+            }
+        }
+        ret.push(arg);
+    }
+    ret
+}
+
+/// The name after an escape: one character, `(xx` or `[name]`.
+fn escape_name(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    match chars.next() {
+        Some('(') => chars.by_ref().take(2).collect(),
+        Some('[') => chars.by_ref().take_while(|&c| c != ']').collect(),
+        Some(c) => c.to_string(),
+        None => String::new(),
+    }
+}
+
+fn glyph(name: &str) -> String {
+    if let Some(cp) = name.strip_prefix('u') {
+        if let Some(c) = u32::from_str_radix(cp, 16).ok().and_then(char::from_u32) {
+            return c.to_string();
+        }
+    }
+    match name {
+        "em" => "\u{2014}",
+        "en" => "\u{2013}",
+        "hy" | "mi" => "-",
+        "aq" => "'",
+        "dq" => "\"",
+        "lq" => "\u{201c}",
+        "rq" => "\u{201d}",
+        "oq" => "\u{2018}",
+        "cq" => "\u{2019}",
+        "bu" => "\u{2022}",
+        "co" => "\u{a9}",
+        "rg" => "\u{ae}",
+        "tm" => "\u{2122}",
+        "de" => "\u{b0}",
+        "mu" => "\u{d7}",
+        "di" => "\u{f7}",
+        "+-" => "\u{b1}",
+        "<=" => "\u{2264}",
+        ">=" => "\u{2265}",
+        "!=" => "\u{2260}",
+        "->" => "\u{2192}",
+        "<-" => "\u{2190}",
+        "ua" => "\u{2191}",
+        "da" => "\u{2193}",
+        "la" => "\u{27e8}",
+        "ra" => "\u{27e9}",
+        "ga" => "`",
+        "aa" => "\u{b4}",
+        "ti" | "a~" => "~",
+        "ha" | "a^" => "^",
+        "sl" => "/",
+        "rs" => "\\",
+        "ba" | "bv" | "or" => "|",
+        "lB" => "[",
+        "rB" => "]",
+        "lC" => "{",
+        "rC" => "}",
+        "ss" => "\u{df}",
+        _ => "",
+    }
+    .to_owned()
+}
+
+fn string(name: &str) -> &'static str {
+    match name {
+        "lq" | "Lq" => "\u{201c}",
+        "rq" | "Rq" => "\u{201d}",
+        "R" => "\u{ae}",
+        "Tm" => "\u{2122}",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAN: &str = r#".TH LS 1
+.SH NAME
+ls \- list directory contents
+.SH SYNOPSIS
+.B ls
+[\fIOPTION\fR]... [\fIFILE\fR]...
+.SH OPTIONS
+.TP
+.BR \-a ", " \-\-all
+do not ignore entries starting with .
+.IP \(bu
+a bullet
+.PP
+.nf
+literal  text
+.fi
+See
+.UR https://example.org/ls
+the manual
+.UE .
+.SH SEE ALSO
+.BR dir (1)
+"#;
+
+    const MDOC: &str = r#".Dd January 1, 2024
+.Dt TRUE 1
+.Os
+.Sh NAME
+.Nm true
+.Nd do nothing
+.Sh SYNOPSIS
+.Nm
+.Op Fl v
+.Ar file
+.Sh DESCRIPTION
+.Bl -tag -width Ds
+.It Fl v
+Be verbose.
+.El
+.Bl -enum
+.It
+first
+.El
+.Sh EXIT STATUS
+.Ex -std
+.Sh SEE ALSO
+.Xr false 1 ,
+.Dq quoted
+"#;
+
+    #[test]
+    fn man_html() {
+        assert_eq!(
+            html(MAN),
+            r#"<table class="head">
+<tr>
+<td class="head-ltitle">LS(1)</td>
+<td class="head-rtitle">LS(1)</td>
+</tr>
+</table>
+<h1 class="Sh">NAME</h1>
+<p class="Pp">ls - list directory contents</p>
+<h1 class="Sh">SYNOPSIS</h1>
+<p class="Pp"><b>ls</b> [<i>OPTION</i>]... [<i>FILE</i>]...</p>
+<h1 class="Sh">OPTIONS</h1>
+<dl class="Bl-tag">
+<dt><b>-a</b>, <b>--all</b></dt>
+<dd>do not ignore entries starting with .</dd>
+<dt>•</dt>
+<dd>a bullet</dd>
+</dl>
+<pre>literal  text
+</pre>
+<p class="Pp">See <a class="Lk" href="https://example.org/ls">the manual</a>.</p>
+<h1 class="Sh">SEE ALSO</h1>
+<p class="Pp"><b>dir</b>(1)</p>
+"#
+        );
+    }
+
+    #[test]
+    fn man_text() {
+        assert_eq!(
+            text(MAN, 60, false),
+            r#"LS(1)                                                  LS(1)
+
+NAME
+       ls - list directory contents
+
+SYNOPSIS
+       ls [OPTION]... [FILE]...
+
+OPTIONS
+       -a, --all
+              do not ignore entries starting with .
+
+       •     a bullet
+
+       literal  text
+
+       See the manual.
+
+SEE ALSO
+       dir(1)
+
+"#
+        );
+    }
+
+    #[test]
+    fn mdoc_html() {
+        assert_eq!(
+            html(MDOC),
+            r#"<table class="head">
+<tr>
+<td class="head-ltitle">TRUE(1)</td>
+<td class="head-rtitle">TRUE(1)</td>
+</tr>
+</table>
+<h1 class="Sh">NAME</h1>
+<p class="Pp"><b>true</b> — do nothing</p>
+<h1 class="Sh">SYNOPSIS</h1>
+<p class="Pp"><b>true</b> [<b>-v</b>] <i>file</i></p>
+<h1 class="Sh">DESCRIPTION</h1>
+<dl class="Bl-tag">
+<dt><b>-v</b></dt>
+<dd>Be verbose.</dd>
+<dt>1.</dt>
+<dd>first</dd>
+</dl>
+<h1 class="Sh">EXIT STATUS</h1>
+<p class="Pp">The <b>true</b> utility exits 0 on success, and &gt;0 if an error occurs.</p>
+<h1 class="Sh">SEE ALSO</h1>
+<p class="Pp"><a class="Xr" href="/1/false.1.html"><b>false(1)</b></a>, “quoted”</p>
+"#
+        );
+    }
+
+    #[test]
+    fn mdoc_text() {
+        assert_eq!(
+            text(MDOC, 60, true),
+            r#"TRUE(1)                                              TRUE(1)
+
+<b>NAME</b>
+       <b>true</b> — do nothing
+
+<b>SYNOPSIS</b>
+       <b>true</b> [<b>-v</b>] <u>file</u>
+
+<b>DESCRIPTION</b>
+       <b>-v</b>    Be verbose.
+
+       1.    first
+
+<b>EXIT</b> <b>STATUS</b>
+       The <b>true</b> utility exits 0 on success, and &gt;0 if an
+       error occurs.
+
+<b>SEE</b> <b>ALSO</b>
+       <b>false(1)</b>, “quoted”
+
+"#
+        );
+    }
+
+    #[test]
+    fn link_schemes() {
+        let page = |url| html(&format!(".UR {url}\nhere\n.UE\n"));
+        assert!(page("http://example.org/").contains("href=\"http://example.org/\""));
+        assert!(page("mailto:a@example.org").contains("href="));
+        for url in [
+            "javascript:alert(1)",
+            "data:text/html,x",
+            "JAVASCRIPT:x",
+            "/etc/passwd",
+        ] {
+            assert_eq!(page(url), "<p class=\"Pp\">here</p>\n", "{url}");
+        }
+    }
+}
@@ -0,0 +1,476 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Confinement once a connection process has started: it may read
+//! the man roots and what else the config names, run programs from
+//! the system directories, and write only its runtime and cache
+//! directories.
+//!
+//! On Linux that is Landlock, inherited by the renderers it runs,
+//! along with a seccomp filter refusing system calls no part of
+//! handoc needs, such as ptrace, mounting or new namespaces; where
+//! the kernel has no Landlock, the filter still applies.  On OpenBSD
+//! it is unveil and pledge, as its daemons do; programs run start
+//! afresh there, and mandoc pledges itself.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::config::{config, Sink};
+use crate::{guard, popular, render, vhost};
+
+/// How a path may be used.
+#[derive(Clone, Copy)]
+enum Access {
+    Read,
+    /// Read and run programs.
+    Run,
+    /// Anything, within a directory.
+    Write,
+    /// Read and write, but not create.
+    ReadWrite,
+}
+
+/// Programs and what they load; nothing secret lives here.
+const SYSTEM: [&str; 7] = [
+    "/usr", "/bin", "/sbin", "/lib", "/lib32", "/lib64", "/libx32",
+];
+
+/// What the dynamic loader, name resolution, groff, perl and the
+/// package databases read.
+const SYSTEM_READ: [&str; 15] = [
+    "/etc/ld.so.cache",
+    "/var/run/ld.so.hints",
+    "/etc/localtime",
+    "/etc/hosts",
+    "/etc/resolv.conf",
+    "/etc/nsswitch.conf",
+    "/etc/gai.conf",
+    "/etc/host.conf",
+    "/etc/groff",
+    "/etc/perl",
+    "/etc/rpm",
+    "/var/lib/dpkg",
+    "/var/lib/pacman",
+    "/var/lib/rpm",
+    "/proc/self",
+];
+
+/// Confine the process as configured; false if that failed.  Must
+/// come before any other threads start.
+pub fn confine() -> bool {
+    if !config().sandbox.enabled {
+        return true;
+    }
+    #[cfg(target_os = "linux")]
+    let confined = linux::confine();
+    #[cfg(target_os = "openbsd")]
+    let confined = openbsd::confine();
+    #[cfg(not(any(target_os = "linux", target_os = "openbsd")))]
+    let confined: io::Result<()> = Err(io::ErrorKind::Unsupported.into());
+    if let Err(e) = &confined {
+        eprintln!("sandbox: {e}");
+    }
+    confined.is_ok()
+}
+
+/// The paths allowed, and how.
+fn rules() -> Vec<(PathBuf, Access)> {
+    let cfg = config();
+    let mut read: Vec<PathBuf> = SYSTEM_READ.iter().map(PathBuf::from).collect();
+    read.extend(vhost::all_roots().into_iter().cloned());
+    read.extend(
+        [
+            cfg.templates.clone(),
+            cfg.auth.htpasswd.clone(),
+            cfg.tldr.dir.clone(),
+            cfg.doc.enabled.then(|| cfg.doc.dir.clone()),
+        ]
+        .into_iter()
+        .flatten(),
+    );
+    read.extend(cfg.info.dirs.iter().cloned());
+    read.extend(cfg.archives.pool.iter().cloned());
+    read.extend(cfg.sandbox.read.iter().cloned());
+
+    let mut run: Vec<PathBuf> = SYSTEM.iter().map(PathBuf::from).collect();
+    // programs configured outside the system directories
+    run.extend(
+        [
+            render::renderer().program(),
+            render::find_program(&cfg.render.mandoc),
+        ]
+        .into_iter()
+        .chain(cfg.pod.enabled.then(|| render::find_program(&cfg.pod.perl)))
+        .flatten()
+        .filter_map(|p| p.canonicalize().ok()),
+    );
+
+    let mut write: Vec<PathBuf> = [
+        guard::runtime_dir().cloned(),
+        cfg.cache.dir.clone(),
+        cfg.archives.dir.clone(),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    // grohtml works in temporary files
+    if render::renderer().name() == "groff" {
+        write.push(std::env::temp_dir());
+    }
+    for sink in &cfg.stats.sinks {
+        if let Sink::Json { path } = sink {
+            write.extend(path.parent().map(Path::to_owned));
+        }
+    }
+    write.extend(popular::path().and_then(|p| p.parent()).map(Path::to_owned));
+    write.extend(cfg.sandbox.write.iter().cloned());
+    for dir in &write {
+        std::fs::create_dir_all(dir).ok();
+    }
+
+    let with = |paths: Vec<PathBuf>, access| paths.into_iter().map(move |p| (p, access));
+    with(read, Access::Read)
+        .chain(with(run, Access::Run))
+        .chain(with(write, Access::Write))
+        .chain(with(vec!["/dev/null".into()], Access::ReadWrite))
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::fs::OpenOptions;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::fs::OpenOptionsExt;
+
+    use super::*;
+
+    const EXECUTE: u64 = 1 << 0;
+    const WRITE_FILE: u64 = 1 << 1;
+    const READ_FILE: u64 = 1 << 2;
+    const READ_DIR: u64 = 1 << 3;
+    /// Every right of Landlock ABI 1, removing and making things.
+    const ABI1: u64 = (1 << 13) - 1;
+    const REFER: u64 = 1 << 13;
+    const TRUNCATE: u64 = 1 << 14;
+    /// The rights that apply to files rather than directories.
+    const FILE_RIGHTS: u64 = EXECUTE | WRITE_FILE | READ_FILE | TRUNCATE;
+
+    const READ: u64 = READ_FILE | READ_DIR;
+    const RUN: u64 = READ | EXECUTE;
+    const WRITE: u64 = u64::MAX;
+
+    fn rights(access: Access) -> u64 {
+        match access {
+            Access::Read => READ,
+            Access::Run => RUN,
+            Access::Write => WRITE,
+            Access::ReadWrite => READ_FILE | WRITE_FILE,
+        }
+    }
+
+    pub fn confine() -> io::Result<()> {
+        let landlocked =
+            landlock().map_err(|e| io::Error::new(e.kind(), format!("Landlock: {e}")))?;
+        if !landlocked {
+            eprintln!("sandbox: no Landlock in this kernel, files are not confined");
+        }
+        seccomp().map_err(|e| io::Error::new(e.kind(), format!("seccomp: {e}")))
+    }
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    /// Restrict the process to `rules()`; false if the kernel cannot.
+    fn landlock() -> io::Result<bool> {
+        let check = |ret: libc::c_long| {
+            if ret < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(ret)
+            }
+        };
+        // LANDLOCK_CREATE_RULESET_VERSION
+        let abi = match check(unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                1,
+            )
+        }) {
+            Ok(abi) => abi,
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENOSYS | libc::EOPNOTSUPP)) => {
+                return Ok(false);
+            }
+            Err(e) => return Err(e),
+        };
+        let handled = match abi {
+            1 => ABI1,
+            2 => ABI1 | REFER,
+            _ => ABI1 | REFER | TRUNCATE,
+        };
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        let fd = check(unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        })?;
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+        for (path, access) in rules() {
+            // missing paths need no access
+            let Ok(f) = OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+                .open(&path)
+            else {
+                continue;
+            };
+            let is_dir = f.metadata().is_ok_and(|m| m.is_dir());
+            let rights = rights(access);
+            let rights = if is_dir { rights } else { rights & FILE_RIGHTS };
+            let rule = PathBeneathAttr {
+                allowed_access: rights & handled,
+                parent_fd: f.as_raw_fd(),
+            };
+            // LANDLOCK_RULE_PATH_BENEATH
+            check(unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_add_rule,
+                    ruleset.as_raw_fd(),
+                    1,
+                    &rule,
+                    0,
+                )
+            })
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+        }
+        no_new_privs()?;
+        check(unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) })?;
+        Ok(true)
+    }
+
+    fn no_new_privs() -> io::Result<()> {
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// System calls refused with EPERM.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    const DENIED: &[libc::c_long] = &[
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_kcmp,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_open_tree,
+        libc::SYS_fsopen,
+        libc::SYS_mount_setattr,
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        libc::SYS_open_by_handle_at,
+        libc::SYS_name_to_handle_at,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_kexec_load,
+        libc::SYS_kexec_file_load,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_userfaultfd,
+        libc::SYS_io_uring_setup,
+        libc::SYS_io_uring_enter,
+        libc::SYS_io_uring_register,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_reboot,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_acct,
+        libc::SYS_quotactl,
+        libc::SYS_syslog,
+        libc::SYS_lookup_dcookie,
+        libc::SYS_settimeofday,
+        libc::SYS_clock_settime,
+        libc::SYS_clock_adjtime,
+        libc::SYS_adjtimex,
+        libc::SYS_sethostname,
+        libc::SYS_setdomainname,
+        libc::SYS_vhangup,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_iopl,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_ioperm,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_modify_ldt,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_uselib,
+    ];
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn seccomp() -> io::Result<()> {
+        use libc::{
+            sock_filter, sock_fprog, BPF_ABS, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_JSET, BPF_K, BPF_LD,
+            BPF_RET, BPF_W, SECCOMP_RET_ALLOW, SECCOMP_RET_ERRNO, SECCOMP_RET_KILL_PROCESS,
+        };
+        let op = |code: u32, k: u32, jt: u8, jf: u8| sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        };
+        let load = |offset| op(BPF_LD | BPF_W | BPF_ABS, offset, 0, 0);
+        let ret = |k| op(BPF_RET | BPF_K, k, 0, 0);
+        let errno = |e: i32| ret(SECCOMP_RET_ERRNO | e as u32);
+        let namespaces = (libc::CLONE_NEWNS
+            | libc::CLONE_NEWUTS
+            | libc::CLONE_NEWIPC
+            | libc::CLONE_NEWUSER
+            | libc::CLONE_NEWPID
+            | libc::CLONE_NEWNET
+            | libc::CLONE_NEWCGROUP) as u32;
+        // struct seccomp_data: nr, arch, instruction_pointer, args
+        let mut prog = vec![
+            load(4),
+            op(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+            ret(SECCOMP_RET_KILL_PROCESS),
+            load(0),
+        ];
+        // x32 calls
+        if cfg!(target_arch = "x86_64") {
+            prog.extend([
+                op(BPF_JMP | BPF_JGE | BPF_K, 0x4000_0000, 0, 1),
+                errno(libc::EPERM),
+            ]);
+        }
+        for &nr in DENIED {
+            prog.extend([
+                op(BPF_JMP | BPF_JEQ | BPF_K, nr as u32, 0, 1),
+                errno(libc::EPERM),
+            ]);
+        }
+        // clone3 hides its flags behind a pointer; libc falls back on clone
+        prog.extend([
+            op(BPF_JMP | BPF_JEQ | BPF_K, libc::SYS_clone3 as u32, 0, 1),
+            errno(libc::ENOSYS),
+            op(BPF_JMP | BPF_JEQ | BPF_K, libc::SYS_clone as u32, 0, 3),
+            // the low half of the flags
+            load(16),
+            op(BPF_JMP | BPF_JSET | BPF_K, namespaces, 0, 1),
+            errno(libc::EPERM),
+            ret(SECCOMP_RET_ALLOW),
+        ]);
+        let fprog = sock_fprog {
+            len: prog.len() as u16,
+            filter: prog.as_mut_ptr(),
+        };
+        no_new_privs()?;
+        let ret = unsafe {
+            libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &fprog as *const sock_fprog,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    fn seccomp() -> io::Result<()> {
+        eprintln!("sandbox: no seccomp filter for this architecture");
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "openbsd")]
+mod openbsd {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    use super::*;
+
+    fn permissions(access: Access) -> &'static str {
+        match access {
+            Access::Read => "r",
+            Access::Run => "rx",
+            Access::Write => "rwc",
+            Access::ReadWrite => "rw",
+        }
+    }
+
+    fn check(ret: libc::c_int) -> io::Result<()> {
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn confine() -> io::Result<()> {
+        for (path, access) in rules() {
+            let c = CString::new(path.as_os_str().as_bytes())?;
+            let perms = CString::new(permissions(access))?;
+            match check(unsafe { libc::unveil(c.as_ptr(), perms.as_ptr()) }) {
+                // missing paths need no access
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                r => r.map_err(|e| {
+                    io::Error::new(e.kind(), format!("unveil {}: {e}", path.display()))
+                })?,
+            }
+        }
+        check(unsafe { libc::unveil(std::ptr::null(), std::ptr::null()) })?;
+        let promises = CString::new(promises())?;
+        check(unsafe { libc::pledge(promises.as_ptr(), std::ptr::null()) })
+            .map_err(|e| io::Error::new(e.kind(), format!("pledge: {e}")))
+    }
+
+    /// Serving and running renderers, plus the lock files and caches
+    /// of the runtime directory, and name lookups when something is
+    /// sent elsewhere.
+    fn promises() -> String {
+        let cfg = config();
+        let mut promises = String::from("stdio rpath wpath cpath flock inet proc exec");
+        let sends = cfg.otlp.endpoint.is_some()
+            || cfg.stats.enabled
+                && cfg
+                    .stats
+                    .sinks
+                    .iter()
+                    .any(|s| !matches!(s, Sink::Json { .. }));
+        if sends {
+            promises += " dns";
+        }
+        promises
+    }
+}
@@ -0,0 +1,67 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! What the request being served was given by the layers in front of
+//! it, kept for the task serving it: one router may serve many
+//! requests at once, embedded or under `handoc preview`.  Blocking
+//! work and tasks started for the request take it along, through
+//! [`crate::bg`] and [`carry`].
+
+use std::cell::Cell;
+use std::future::Future;
+use std::path::PathBuf;
+
+use crate::trace;
+
+#[derive(Clone, Copy, Default)]
+pub struct Scope {
+    /// Man roots of the virtual host being served.
+    pub roots: Option<&'static [PathBuf]>,
+    /// Where spans started now belong.
+    pub trace: Option<trace::Context>,
+}
+
+tokio::task_local! {
+    static TASK: Scope;
+}
+
+thread_local! {
+    static THREAD: Cell<Option<Scope>> = const { Cell::new(None) };
+}
+
+/// The scope of the request being served, if any.
+pub fn current() -> Scope {
+    TASK.try_with(|s| *s)
+        .ok()
+        .or_else(|| THREAD.get())
+        .unwrap_or_default()
+}
+
+/// Run `fut` in the current scope as changed by `change`.
+pub async fn enter<F: Future>(change: impl FnOnce(&mut Scope), fut: F) -> F::Output {
+    let mut scope = current();
+    change(&mut scope);
+    TASK.scope(scope, fut).await
+}
+
+/// `fut`, for a task of its own, in the current scope.
+pub fn carry<F: Future>(fut: F) -> impl Future<Output = F::Output> {
+    TASK.scope(current(), fut)
+}
+
+/// Run blocking `f` in `scope`.
+pub fn within<R>(scope: Scope, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<Scope>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            THREAD.set(self.0);
+        }
+    }
+    let _outer = Restore(THREAD.replace(Some(scope)));
+    f()
+}
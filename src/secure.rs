@@ -0,0 +1,56 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Security headers on every response.  Pages are rendered from
+//! whatever packages ship, so browsers are told to run no scripts
+//! and load nothing but our own stylesheet and icon, whatever markup
+//! gets through.
+//!
+//! Inline `style` attributes are allowed all the same: groff sets
+//! out its HTML with them, as mandoc does for `.in`, `.ti` and tbl,
+//! and without them indents and table layout are lost.  They can
+//! load nothing from elsewhere, as `img-src` still holds for them.
+
+use axum::extract::Request;
+use axum::http::{header, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+
+use crate::config::config;
+
+const CSP: &str = "default-src 'none'; style-src 'self'; \
+                   style-src-attr 'unsafe-inline'; img-src 'self'; \
+                   form-action 'self'; base-uri 'none'; frame-ancestors 'none'";
+
+pub async fn layer(req: Request, next: Next) -> Response {
+    let mut res = next.run(req).await;
+    let headers = res.headers_mut();
+    let mut set = |name, value| {
+        headers.entry(name).or_insert(value);
+    };
+    set(
+        header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_static(CSP),
+    );
+    set(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    set(
+        header::REFERRER_POLICY,
+        HeaderValue::from_static("strict-origin-when-cross-origin"),
+    );
+    let hsts = &config().security.hsts;
+    if let Some(value) = (!hsts.is_empty())
+        .then(|| HeaderValue::from_str(hsts).ok())
+        .flatten()
+    {
+        set(header::STRICT_TRANSPORT_SECURITY, value);
+    }
+    res
+}
@@ -0,0 +1,92 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Where man trees are read from: the local filesystem, unless a
+//! [`ManSource`] is given to the [`crate::Server`], e.g. in-memory
+//! fixtures for tests, or trees kept in tarballs or remote stores.
+//! Paths are those under the configured roots either way.
+//!
+//! Only the man trees go through it.  Pages extracted from package
+//! archives, lint reports and the info, POD, tldr and doc trees still
+//! come from the local filesystem.
+
+use std::ffi::OsString;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+pub struct Metadata {
+    pub modified: SystemTime,
+    pub len: u64,
+    pub dir: bool,
+}
+
+/// Man trees to serve.  Methods may block.
+pub trait ManSource: Send + Sync {
+    fn exists(&self, path: &Path) -> bool;
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// The file `path` as stored; pages are gzipped.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>>;
+
+    /// Names of the entries of directory `path`.
+    fn list(&self, path: &Path) -> io::Result<Vec<OsString>>;
+
+    /// Where `path` is on the local filesystem, if it is: renderers
+    /// then read it themselves, and it must not lead out of its root
+    /// through symlinked directories.
+    fn local_path(&self, _path: &Path) -> Option<PathBuf> {
+        None
+    }
+}
+
+/// The local filesystem.
+pub struct Local;
+
+impl ManSource for Local {
+    fn exists(&self, path: &Path) -> bool {
+        std::fs::exists(path).unwrap_or_default()
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let meta = std::fs::metadata(path)?;
+        Ok(Metadata {
+            modified: meta.modified()?,
+            len: meta.len(),
+            dir: meta.is_dir(),
+        })
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(std::fs::File::open(path)?))
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        std::fs::read_dir(path)?
+            .map(|ent| Ok(ent?.file_name()))
+            .collect()
+    }
+
+    fn local_path(&self, path: &Path) -> Option<PathBuf> {
+        Some(path.to_owned())
+    }
+}
+
+static SOURCE: OnceLock<Box<dyn ManSource>> = OnceLock::new();
+
+pub fn source() -> &'static dyn ManSource {
+    SOURCE.get_or_init(|| Box::new(Local)).as_ref()
+}
+
+/// Read from `source` rather than the local filesystem, unless some
+/// source is in use already.
+pub(crate) fn install(source: Box<dyn ManSource>) {
+    SOURCE.set(source).ok();
+}
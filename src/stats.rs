@@ -0,0 +1,146 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Page hit counting.  Counts accumulate in memory and are handed to
+//! the configured sinks as increments, every `interval` seconds and
+//! once more when the connection is done.
+
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use axum::body::Bytes;
+use axum::http::{header, Request};
+use http_body_util::Full;
+use hyper_util::rt::TokioIo;
+
+use crate::config::{config, Sink};
+use crate::{bg, popular};
+
+pub type Counts = BTreeMap<String, u64>;
+
+static HITS: Mutex<Counts> = Mutex::new(BTreeMap::new());
+
+pub fn hit(page: &str) {
+    popular::hit(page);
+    if config().stats.enabled {
+        *HITS.lock().unwrap().entry(page.to_owned()).or_default() += 1;
+    }
+}
+
+pub async fn export_loop() {
+    let stats = &config().stats;
+    if !stats.enabled || stats.sinks.is_empty() {
+        return;
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(stats.interval.max(1)));
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        flush().await;
+    }
+}
+
+pub async fn flush() {
+    let counts = std::mem::take(&mut *HITS.lock().unwrap());
+    if counts.is_empty() {
+        return;
+    }
+    for sink in &config().stats.sinks {
+        let res = match sink {
+            Sink::Json { path } => {
+                let counts = counts.clone();
+                bg(move || merge_json(path, counts)).await
+            }
+            Sink::Statsd { addr, prefix } => send_statsd(addr, prefix, &counts).await,
+            Sink::Webhook { url } => match serde_json::to_vec(&counts) {
+                Ok(body) => post_json(url, body).await,
+                Err(e) => Err(e.into()),
+            },
+        };
+        if let Err(e) = res {
+            eprintln!("stats export failed: {e}");
+        }
+    }
+}
+
+pub type ExportResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+/// Add `counts` to the totals kept in the file at `path`.
+pub fn merge_json(path: &Path, counts: Counts) -> ExportResult {
+    let mut f = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    // several handoc processes may export to the same file
+    f.lock()?;
+    let mut text = String::new();
+    f.read_to_string(&mut text)?;
+    let mut total: Counts = if text.trim().is_empty() {
+        Default::default()
+    } else {
+        serde_json::from_str(&text)?
+    };
+    for (page, n) in counts {
+        *total.entry(page).or_default() += n;
+    }
+    f.set_len(0)?;
+    f.rewind()?;
+    serde_json::to_writer_pretty(&mut f, &total)?;
+    f.write_all(b"\n")?;
+    Ok(())
+}
+
+async fn send_statsd(addr: &str, prefix: &str, counts: &Counts) -> ExportResult {
+    let sock = tokio::net::UdpSocket::bind(if addr.starts_with('[') {
+        "[::]:0"
+    } else {
+        "0.0.0.0:0"
+    })
+    .await?;
+    sock.connect(addr).await?;
+    for (page, n) in counts {
+        // statsd uses '.' as the hierarchy separator
+        let page = page.replace(['.', '/', ':', '|', '@'], "_");
+        sock.send(format!("{prefix}.hits.{page}:{n}|c").as_bytes())
+            .await?;
+    }
+    Ok(())
+}
+
+/// POST `body` as JSON to `url`, which must be http://.
+pub async fn post_json(url: &str, body: Vec<u8>) -> ExportResult {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or("only http:// URLs are supported")?;
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let addr = if authority
+        .rsplit_once(':')
+        .is_some_and(|(_, p)| !p.ends_with(']'))
+    {
+        authority.to_owned()
+    } else {
+        format!("{authority}:80")
+    };
+    let stream = tokio::net::TcpStream::connect(addr).await?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(conn);
+    let req = Request::post(if path.is_empty() { "/" } else { path })
+        .header(header::HOST, authority)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body)))?;
+    let status = sender.send_request(req).await?.status();
+    if !status.is_success() {
+        return Err(format!("{url} returned {status}").into());
+    }
+    Ok(())
+}
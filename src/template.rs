@@ -0,0 +1,105 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! The document around every page, from the Tera template
+//! `page.html`: built in, or read from the `templates` directory at
+//! startup.  It gets `lang`, `theme` if set, `base`, the base path,
+//! `stylesheet` and
+//! `favicon`, the URLs of the built-in ones, and `content`, where the
+//! page goes; output before and after that is sent around the page as
+//! it renders.
+
+use std::hash::{Hash, Hasher};
+use std::sync::OnceLock;
+
+use crate::assets;
+use crate::base;
+use crate::config::config;
+use crate::prefs::Prefs;
+
+static DEFAULT: &str = include_str!("../templates/page.html");
+
+/// Stands in for the content, to split the output at.
+const CONTENT: &str = "\0handoc-content\0";
+
+struct Template {
+    tera: tera::Tera,
+    /// Hash of the source and the assets it may link, for
+    /// validators and the disk cache.
+    stamp: u64,
+}
+
+fn load(src: &str) -> tera::TeraResult<Template> {
+    let mut tera = tera::Tera::default();
+    tera.add_raw_template("page.html", src)?;
+    let mut h = std::hash::DefaultHasher::new();
+    let assets = (assets::href("style.css"), assets::href("favicon.svg"));
+    (src, assets).hash(&mut h);
+    Ok(Template {
+        tera,
+        stamp: h.finish(),
+    })
+}
+
+fn template() -> &'static Template {
+    static TEMPLATE: OnceLock<Template> = OnceLock::new();
+    TEMPLATE.get_or_init(|| {
+        let custom = config().templates.as_ref().and_then(|dir| {
+            let path = dir.join("page.html");
+            let loaded = std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|src| load(&src).map_err(|e| e.to_string()))
+                .and_then(|t| match render(&t, &Prefs::default()) {
+                    Ok((_, Some(_))) => Ok(t),
+                    Ok((_, None)) => Err("no {{ content }}".to_owned()),
+                    Err(e) => Err(e.to_string()),
+                });
+            loaded
+                .inspect_err(|e| eprintln!("cannot use {}: {e}", path.display()))
+                .ok()
+        });
+        custom.unwrap_or_else(|| load(DEFAULT).unwrap())
+    })
+}
+
+fn render(t: &Template, prefs: &Prefs) -> tera::TeraResult<(String, Option<String>)> {
+    let lang = prefs
+        .lang
+        .as_deref()
+        .and_then(|l| l.split(['.', '@']).next())
+        .unwrap_or("en")
+        .replace('_', "-");
+    let mut ctx = tera::Context::new();
+    ctx.insert("lang", &lang);
+    ctx.insert("theme", &prefs.theme);
+    ctx.insert("base", base());
+    ctx.insert("stylesheet", &assets::href("style.css"));
+    ctx.insert("favicon", &assets::href("favicon.svg"));
+    ctx.insert("content", CONTENT);
+    let out = t.tera.render("page.html", &ctx)?;
+    Ok(match out.split_once(CONTENT) {
+        Some((pre, post)) => (pre.to_owned(), Some(post.to_owned())),
+        None => (out, None),
+    })
+}
+
+/// The document before and after a page, for `prefs`.
+pub fn wrap(prefs: &Prefs) -> (String, String) {
+    match render(template(), prefs) {
+        Ok((pre, post)) => (pre, post.unwrap_or_default()),
+        Err(e) => {
+            eprintln!("cannot render page template: {e}");
+            Default::default()
+        }
+    }
+}
+
+/// Changes with the template in use.
+pub fn stamp() -> u64 {
+    template().stamp
+}
@@ -0,0 +1,149 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! What unit tests share.  Configuration and the man source are per
+//! process, so every test runs with the same: the in-memory trees of
+//! [`Memory::fixtures`], `/man` and, for host `other.test`, `/other`.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::config::{Config, Render, Vhost};
+use crate::source::{ManSource, Metadata};
+use crate::Server;
+
+/// Man trees held in memory: gzipped files by path, with the
+/// directories leading to them.
+pub struct Memory {
+    files: BTreeMap<PathBuf, Vec<u8>>,
+    modified: SystemTime,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Self {
+            files: BTreeMap::new(),
+            modified: SystemTime::now(),
+        }
+    }
+
+    /// Add page `path`, gzipping `src`.
+    pub fn page(mut self, path: &str, src: &str) -> Self {
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        gz.write_all(src.as_bytes()).unwrap();
+        self.files.insert(path.into(), gz.finish().unwrap());
+        self
+    }
+
+    pub fn fixtures() -> Self {
+        Self::new()
+            .page(
+                "/man/man1/ls.1.gz",
+                ".TH LS 1\n.SH NAME\nls \\- list directory contents\n.SH SEE ALSO\ndir(1)\n",
+            )
+            .page(
+                "/man/man1/dir.1.gz",
+                ".TH DIR 1\n.SH NAME\ndir \\- list directories\n.SH SEE ALSO\nls(1)\n",
+            )
+            .page(
+                "/man/man8/mount.8.gz",
+                ".TH MOUNT 8\n.SH NAME\nmount \\- mount\n",
+            )
+            .page("/man/man1/list.1.gz", ".so man1/ls.1\n")
+            .page(
+                "/other/man1/only.1.gz",
+                ".TH ONLY 1\n.SH NAME\nonly \\- here\n",
+            )
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.files.keys().any(|f| f != path && f.starts_with(path))
+    }
+}
+
+/// Paths asked about, whether there or not.
+static PROBED: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Every path looked up or opened so far, by any test.
+pub fn probed() -> Vec<PathBuf> {
+    PROBED.lock().unwrap().clone()
+}
+
+impl ManSource for Memory {
+    fn exists(&self, path: &Path) -> bool {
+        PROBED.lock().unwrap().push(path.to_owned());
+        self.files.contains_key(path) || self.is_dir(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        PROBED.lock().unwrap().push(path.to_owned());
+        let len = match self.files.get(path) {
+            Some(f) => f.len() as u64,
+            None if self.is_dir(path) => 0,
+            None => return Err(io::ErrorKind::NotFound.into()),
+        };
+        Ok(Metadata {
+            modified: self.modified,
+            len,
+            dir: !self.files.contains_key(path),
+        })
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        PROBED.lock().unwrap().push(path.to_owned());
+        let f = self.files.get(path).ok_or(io::ErrorKind::NotFound)?;
+        Ok(Box::new(io::Cursor::new(f.clone())))
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        if !self.is_dir(path) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let mut names: Vec<_> = self
+            .files
+            .keys()
+            .filter_map(|f| Some(f.strip_prefix(path).ok()?.iter().next()?.to_owned()))
+            .collect();
+        names.dedup();
+        Ok(names)
+    }
+}
+
+fn config() -> Config {
+    Config {
+        roots: vec!["/man".into()],
+        vhosts: [(
+            "other.test".to_owned(),
+            Vhost {
+                roots: vec!["/other".into()],
+            },
+        )]
+        .into(),
+        render: Render {
+            // indexes and locks of other runs are no use
+            lock_dir: Some(
+                std::env::temp_dir().join(format!("handoc-test-{}", std::process::id())),
+            ),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+/// The server all tests share.
+pub fn server() -> Server {
+    Server::with_config(config()).source(Memory::fixtures())
+}
+
+pub fn setup() {
+    server().install().unwrap();
+}
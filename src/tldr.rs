@@ -0,0 +1,122 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Examples from a checkout or client cache of tldr-pages, shown atop
+//! the pages of commands and at `/tldr/<name>`.  Pages are looked up
+//! in `pages.<lang>` before `pages`, in the configured platforms.
+
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use axum::extract::Path;
+use axum::response::{IntoResponse, Response};
+
+use crate::config::config;
+use crate::error::Error;
+use crate::prefs::Prefs;
+use crate::{bg, browse, escape_html, html, lookup};
+
+/// The tldr page of command `name` as HTML, without its title.
+/// Blocks.
+pub fn find(name: &str, lang: Option<&str>) -> Option<String> {
+    std::fs::read_to_string(path(name, lang)?)
+        .ok()
+        .map(|src| markup(&src))
+}
+
+/// When the tldr page of command `name` last changed.  Blocks.
+pub fn stamp(name: &str, lang: Option<&str>) -> Option<SystemTime> {
+    std::fs::metadata(path(name, lang)?)
+        .and_then(|m| m.modified())
+        .ok()
+}
+
+/// The file of the tldr page of command `name`.  Blocks.
+fn path(name: &str, lang: Option<&str>) -> Option<PathBuf> {
+    let tldr = &config().tldr;
+    let dir = tldr.dir.as_ref()?;
+    if !lookup::valid_component(name) {
+        return None;
+    }
+    // e.g. pt_BR.UTF-8 -> pages.pt_BR, then pages.pt
+    let full = lang.and_then(|l| l.split(['.', '@']).next());
+    let short = full.and_then(|l| l.split('_').next());
+    let trees = [full, short]
+        .into_iter()
+        .flatten()
+        .filter(|l| *l != "en" && lookup::valid_component(l))
+        .map(|l| format!("pages.{l}"))
+        .chain(["pages".to_owned()]);
+    trees
+        .flat_map(|tree| tldr.platforms.iter().map(move |p| (tree.clone(), p)))
+        .map(|(tree, platform)| dir.join(tree).join(platform).join(format!("{name}.md")))
+        .find(|p| p.is_file())
+}
+
+/// The subset of Markdown tldr pages are written in: a `#` title,
+/// `>` description lines, `-` example descriptions each followed by
+/// a command in backticks, with `{{placeholders}}`.
+fn markup(src: &str) -> String {
+    let mut out = String::new();
+    for line in src.lines() {
+        if let Some(desc) = line.strip_prefix("> ") {
+            out += &format!("<p class=\"description\">{}</p>\n", inline(desc));
+        } else if let Some(example) = line.strip_prefix("- ") {
+            out += &format!("<p>{}</p>\n", inline(example));
+        } else if let Some(cmd) = line.strip_prefix('`').and_then(|l| l.strip_suffix('`')) {
+            let cmd = escape_html(cmd)
+                .replace("{{", "<var>")
+                .replace("}}", "</var>");
+            out += &format!("<pre><code>{cmd}</code></pre>\n");
+        }
+    }
+    out
+}
+
+/// `code` spans and `<URL>` links.
+fn inline(s: &str) -> String {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(i) = rest.find(['`', '<']) {
+        out += &escape_html(&rest[..i]);
+        let close = if rest[i..].starts_with('`') { '`' } else { '>' };
+        let Some(len) = rest[i + 1..].find(close) else {
+            out += &escape_html(&rest[i..]);
+            return out;
+        };
+        let inner = &rest[i + 1..i + 1 + len];
+        out += &if close == '`' {
+            format!("<code>{}</code>", escape_html(inner))
+        } else if inner.starts_with("https://") || inner.starts_with("http://") {
+            format!("<a href=\"{0}\">{0}</a>", escape_html(inner))
+        } else {
+            escape_html(&rest[i..i + 2 + len])
+        };
+        rest = &rest[i + 2 + len..];
+    }
+    out + &escape_html(rest)
+}
+
+/// The box on command pages.
+pub fn details(body: &str) -> String {
+    format!("<details class=\"tldr\" open>\n<summary>Examples (tldr)</summary>\n{body}</details>\n")
+}
+
+pub async fn tldr(Path(name): Path<String>, prefs: Prefs) -> Result<Response, Error> {
+    let body = bg({
+        let (name, lang) = (name.clone(), prefs.lang.clone());
+        move || find(&name, lang.as_deref())
+    })
+    .await
+    .ok_or(Error::NotFound)?;
+    let body = format!(
+        "<h1>{}</h1>\n<div class=\"tldr\">\n{body}</div>",
+        escape_html(&name)
+    );
+    Ok(browse::document(&prefs, &name, html::Place::Other, &body).into_response())
+}
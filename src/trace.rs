@@ -0,0 +1,220 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Traces of requests, sent to an OpenTelemetry collector as OTLP
+//! over HTTP in its JSON encoding: a server span per request, joining
+//! the trace of a W3C `traceparent` header, with spans inside for
+//! page lookup, decompression, rendering and cache access.  Spans
+//! accumulate in memory and are sent after each request, like hits.
+//! Where spans belong is kept in the [`scope`](crate::scope) of the
+//! request.
+
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use serde_json::{json, Value};
+
+use crate::config::config;
+use crate::{access, scope, stats};
+
+#[derive(Clone, Copy)]
+pub struct Context {
+    trace: u128,
+    span: u64,
+}
+
+struct Done {
+    ctx: Context,
+    parent: Option<u64>,
+    name: String,
+    server: bool,
+    start: SystemTime,
+    end: SystemTime,
+    attrs: Vec<(&'static str, String)>,
+    failed: bool,
+}
+
+static DONE: Mutex<Vec<Done>> = Mutex::new(Vec::new());
+
+fn enabled() -> bool {
+    config().otlp.endpoint.is_some()
+}
+
+/// Random enough for IDs, which need not be secret.
+fn random() -> u64 {
+    static SEQ: AtomicU64 = AtomicU64::new(0);
+    let mut h = std::hash::RandomState::new().build_hasher();
+    h.write_u64(SEQ.fetch_add(1, Ordering::Relaxed));
+    h.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos()),
+    );
+    h.finish() | 1
+}
+
+/// A span within the current request, ended when dropped.
+pub struct Span(Option<Done>);
+
+/// Start a span named `name`; outside of requests, or without a
+/// collector, it goes nowhere.
+pub fn span(name: &str) -> Span {
+    let parent = if enabled() {
+        scope::current().trace
+    } else {
+        None
+    };
+    Span(parent.map(|parent| Done {
+        ctx: Context {
+            trace: parent.trace,
+            span: random(),
+        },
+        parent: Some(parent.span),
+        name: name.to_owned(),
+        server: false,
+        start: SystemTime::now(),
+        end: UNIX_EPOCH,
+        attrs: Vec::new(),
+        failed: false,
+    }))
+}
+
+impl Span {
+    pub fn attr(&mut self, key: &'static str, value: impl ToString) {
+        if let Some(span) = &mut self.0 {
+            span.attrs.push((key, value.to_string()));
+        }
+    }
+
+    /// Mark the span as failed.
+    pub fn fail(&mut self) {
+        if let Some(span) = &mut self.0 {
+            span.failed = true;
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(mut span) = self.0.take() {
+            span.end = SystemTime::now();
+            DONE.lock().unwrap().push(span);
+        }
+    }
+}
+
+/// The trace and parent span of a `traceparent` header.
+fn parent(req: &Request) -> Option<(u128, u64)> {
+    let value = req.headers().get("traceparent")?.to_str().ok()?;
+    let mut fields = value.split('-');
+    let (version, trace, span) = (fields.next()?, fields.next()?, fields.next()?);
+    if version != "00" || trace.len() != 32 || span.len() != 16 {
+        return None;
+    }
+    let trace = u128::from_str_radix(trace, 16).ok()?;
+    let span = u64::from_str_radix(span, 16).ok()?;
+    (trace != 0 && span != 0).then_some((trace, span))
+}
+
+pub async fn layer(req: Request, next: Next) -> Response {
+    if !enabled() {
+        return next.run(req).await;
+    }
+    let from = parent(&req);
+    let ctx = Context {
+        trace: from.map_or_else(
+            || u128::from(random()) << 64 | u128::from(random()),
+            |f| f.0,
+        ),
+        span: random(),
+    };
+    let mut root = Done {
+        ctx,
+        parent: from.map(|f| f.1),
+        name: format!("{} {}", req.method(), req.uri().path()),
+        server: true,
+        start: SystemTime::now(),
+        end: UNIX_EPOCH,
+        attrs: vec![
+            ("http.request.method", req.method().to_string()),
+            ("url.path", req.uri().path().to_owned()),
+        ],
+        failed: false,
+    };
+    let res = scope::enter(|s| s.trace = Some(ctx), next.run(req)).await;
+    let status = res.status();
+    root.attrs
+        .push(("http.response.status_code", status.as_u16().to_string()));
+    root.failed = status.is_server_error();
+    access::on_end(res, move |bytes| {
+        root.attrs
+            .push(("http.response.body.size", bytes.to_string()));
+        root.end = SystemTime::now();
+        DONE.lock().unwrap().push(root);
+        if let Ok(rt) = tokio::runtime::Handle::try_current() {
+            rt.spawn(flush());
+        }
+    })
+}
+
+/// Send the spans so far to the collector, after any being sent.
+pub async fn flush() {
+    static SENDING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+    let Some(endpoint) = &config().otlp.endpoint else {
+        return;
+    };
+    // the last flush of a connection waits for the one an ending
+    // request started, so the process exits after it
+    let _sending = SENDING.lock().await;
+    let spans = std::mem::take(&mut *DONE.lock().unwrap());
+    if spans.is_empty() {
+        return;
+    }
+    let body = json!({
+        "resourceSpans": [{
+            "resource": { "attributes": [attr("service.name", &config().otlp.service_name)] },
+            "scopeSpans": [{
+                "scope": { "name": "handoc", "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans.iter().map(encode).collect::<Vec<_>>(),
+            }],
+        }],
+    });
+    if let Err(e) = stats::post_json(endpoint, body.to_string().into_bytes()).await {
+        eprintln!("trace export failed: {e}");
+    }
+}
+
+fn attr(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn encode(span: &Done) -> Value {
+    let nanos = |t: SystemTime| {
+        t.duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos())
+            .to_string()
+    };
+    json!({
+        "traceId": format!("{:032x}", span.ctx.trace),
+        "spanId": format!("{:016x}", span.ctx.span),
+        "parentSpanId": span.parent.map_or(String::new(), |p| format!("{p:016x}")),
+        "name": span.name,
+        // SPAN_KIND_SERVER, SPAN_KIND_INTERNAL
+        "kind": if span.server { 2 } else { 1 },
+        "startTimeUnixNano": nanos(span.start),
+        "endTimeUnixNano": nanos(span.end),
+        "attributes": span.attrs.iter().map(|(k, v)| attr(k, v)).collect::<Vec<_>>(),
+        // STATUS_CODE_ERROR, STATUS_CODE_UNSET
+        "status": { "code": if span.failed { 2 } else { 0 } },
+    })
+}
@@ -0,0 +1,72 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Virtual hosts: `vhosts` in the config gives Host names man roots
+//! of their own.  A layer in front of all routes picks them for the
+//! [`scope`](crate::scope) of each request; commands pick one for the
+//! whole process.
+
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use axum::extract::Request;
+use axum::http::header;
+use axum::middleware::Next;
+use axum::response::Response;
+
+use crate::config::{config, Vhost};
+use crate::scope;
+
+/// The host of the process, outside of requests.
+static SELECTED: Mutex<Option<&'static Vhost>> = Mutex::new(None);
+
+pub async fn layer(req: Request, next: Next) -> Response {
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or_default();
+    let roots = find(host).map_or(&config().roots, |vhost| &vhost.roots);
+    scope::enter(|s| s.roots = Some(roots), next.run(req)).await
+}
+
+/// The vhost configured for `host`, with or without a port.
+fn find(host: &str) -> Option<&'static Vhost> {
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    };
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+    config().vhosts.get(&name)
+}
+
+/// Serve `host` outside of requests, as for `handoc warm --host`.
+pub fn select(host: &str) {
+    *SELECTED.lock().unwrap() = find(host);
+}
+
+/// Roots of the host being served, or the top-level ones.
+pub fn roots() -> &'static [PathBuf] {
+    scope::current().roots.unwrap_or_else(|| {
+        SELECTED
+            .lock()
+            .unwrap()
+            .map_or(&config().roots, |vhost| &vhost.roots)
+    })
+}
+
+/// Roots of all hosts, for work ahead of requests.
+pub fn all_roots() -> Vec<&'static PathBuf> {
+    let mut ret: Vec<_> = config().roots.iter().collect();
+    for root in config().vhosts.values().flat_map(|v| &v.roots) {
+        if !ret.contains(&root) {
+            ret.push(root);
+        }
+    }
+    ret
+}
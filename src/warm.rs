@@ -0,0 +1,170 @@
+/*
+ * Copyright Carl Lei, 2024.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! `handoc warm [--host NAME] [--sections 1,8] [--top N]`: render
+//! pages into the disk cache ahead of time, those of the roots of
+//! virtual host NAME if given.  Without `--top`, every page of the given
+//! sections (default all) is rendered; with it, the N most viewed ones
+//! according to the JSON stats sink.  The reverse indexes of cross
+//! references are built first.
+
+use std::collections::BTreeMap;
+use std::process::ExitCode;
+
+use crate::config::{config, Sink};
+use crate::prefs::Prefs;
+use crate::render::{self, RenderError};
+use crate::source::source;
+use crate::{cache, check_so, lookup, popular, refs, vhost, Format};
+
+pub fn run(args: &[String]) -> ExitCode {
+    let mut sections = None;
+    let mut top = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--sections", Some(v)) => {
+                sections = Some(v.split(',').map(str::to_owned).collect::<Vec<_>>())
+            }
+            ("--host", Some(v)) => vhost::select(v),
+            ("--top", Some(v)) => match v.parse::<usize>() {
+                Ok(n) => top = Some(n),
+                Err(_) => return usage(),
+            },
+            _ => return usage(),
+        }
+    }
+    if config().cache.dir.is_none() {
+        eprintln!("warm: no cache.dir configured");
+        return ExitCode::FAILURE;
+    }
+    let wanted = |sec: &str| {
+        sections.as_ref().is_none_or(|list| {
+            list.iter().any(|s| {
+                sec.strip_prefix(s.as_str())
+                    .is_some_and(|rest| !rest.starts_with(|c: char| c.is_ascii_digit()))
+            })
+        })
+    };
+    let pages = match top {
+        Some(n) => match most_viewed() {
+            Some(pages) => pages
+                .into_iter()
+                .filter(|(sec, _)| wanted(sec))
+                .take(n)
+                .collect(),
+            None => {
+                eprintln!("warm: --top needs [popular] or a json stats sink");
+                return ExitCode::FAILURE;
+            }
+        },
+        None => all_pages(&wanted),
+    };
+    for root in vhost::roots() {
+        refs::get(root, true);
+    }
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let (mut done, mut failed) = (0, 0);
+    for (section, file) in pages {
+        match rt.block_on(warm(&section, &file)) {
+            Ok(true) => done += 1,
+            Ok(false) => {}
+            Err(e) => {
+                eprintln!("warm: {section}/{file}: {e}");
+                failed += 1;
+            }
+        }
+    }
+    eprintln!("warm: rendered {done} pages, {failed} failed");
+    if failed > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+fn usage() -> ExitCode {
+    eprintln!("usage: handoc warm [--host NAME] [--sections 1,8] [--top N]");
+    ExitCode::from(2)
+}
+
+/// (section, page file without .gz) ordered by hit count, from
+/// `[popular]` or else a json stats sink.
+fn most_viewed() -> Option<Vec<(String, String)>> {
+    if let Some(pages) = popular::ranked() {
+        return Some(
+            pages
+                .into_iter()
+                .map(|(sec, file, _)| (sec, file))
+                .collect(),
+        );
+    }
+    let path = config().stats.sinks.iter().find_map(|s| match s {
+        Sink::Json { path } => Some(path),
+        _ => None,
+    })?;
+    let counts: BTreeMap<String, u64> = match std::fs::read_to_string(path) {
+        Ok(text) => serde_json::from_str(&text).ok()?,
+        Err(_) => Default::default(),
+    };
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by_key(|&(_, n)| std::cmp::Reverse(n));
+    Some(
+        counts
+            .into_iter()
+            .filter_map(|(page, _)| {
+                let (sec, file) = page.split_once('/')?;
+                Some((sec.to_owned(), file.to_owned()))
+            })
+            .collect(),
+    )
+}
+
+fn all_pages(wanted: &dyn Fn(&str) -> bool) -> Vec<(String, String)> {
+    let mut ret = Vec::new();
+    for root in vhost::roots() {
+        for dir in lookup::sections(root) {
+            let Ok(names) = source().list(&root.join(format!("man{dir}"))) else {
+                continue;
+            };
+            for name in names {
+                let Some(file) = name.to_str().and_then(|n| n.strip_suffix(".gz")) else {
+                    continue;
+                };
+                if let Some((_, sec)) = file.rsplit_once('.').filter(|(_, sec)| wanted(sec)) {
+                    ret.push((sec.to_owned(), file.to_owned()));
+                }
+            }
+        }
+    }
+    ret.sort_unstable();
+    ret.dedup();
+    ret
+}
+
+/// Render one page into the cache, unless it is there already or is
+/// only a .so redirect.
+async fn warm(section: &str, file: &str) -> Result<bool, RenderError> {
+    let Some(path) = lookup::resolve(section, &format!("{file}.gz"), None) else {
+        return Err(std::io::Error::from(std::io::ErrorKind::NotFound).into());
+    };
+    let key = cache::Key {
+        mtime: source().metadata(&path)?.modified,
+        path,
+        format: Format::Html,
+        prefs: Prefs::default(),
+    };
+    if check_so(&key.path)?.is_some() || cache::get(&key).is_some() {
+        return Ok(false);
+    }
+    render::collect(key, None).await?;
+    Ok(true)
+}